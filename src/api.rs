@@ -1,51 +1,757 @@
+//! C API.
+//!
+//! # Threads
+//!
+//! No call here is synchronized. An engine, every engine cloned from it
+//! with `oneshell_engine_clone` (they share builtins and the output
+//! callback) and every block evaluated or compiled on any of them form one
+//! group. Calls touching a group must not overlap, but the group may move
+//! to another thread between calls, e.g. under a mutex held by the host.
+//! Separate groups can be used from different threads concurrently.
+//!
+//! `oneshell_block_load`, `oneshell_block_load_unchecked`,
+//! `oneshell_script_load`, the `oneshell_load_error_*` functions and
+//! `oneshell_string_destroy` touch no engine and may be called from any
+//! thread. A block that has not been evaluated yet can be handed to any
+//! group; once evaluated it belongs to that group until destroyed.
+//!
+//! Builtin and output callbacks run synchronously on the thread that made
+//! the call that triggered them.
+
 use std;
-use std::os::raw::c_char;
-use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ffi::{CStr, CString};
+use serde_json;
 use engine;
+use var;
+use builtin;
+use output;
+use signals;
+use jit;
+use glob;
+
+// Panics must not unwind across the C ABI.
+fn ffi_guard<T, F: FnOnce() -> T>(default: T, f: F) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(_) => default
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn oneshell_engine_create() -> *mut engine::EngineHandle {
-    Box::into_raw(Box::new(engine::EngineHandle::from(
-        engine::Engine::new()
-    )))
+    ffi_guard(std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(engine::EngineHandle::from(
+            engine::Engine::new()
+        )))
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn oneshell_engine_destroy(eng: *mut engine::EngineHandle) {
-    unsafe {
-        Box::from_raw(eng);
+    if eng.is_null() {
+        return;
     }
+    ffi_guard((), || unsafe {
+        drop(Box::from_raw(eng));
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn oneshell_engine_clone(eng: &engine::EngineHandle) -> *mut engine::EngineHandle {
-    Box::into_raw(Box::new(engine::EngineHandle::from(
-        eng.borrow().clone()
-    )))
+pub extern "C" fn oneshell_engine_clone(eng: *const engine::EngineHandle) -> *mut engine::EngineHandle {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    ffi_guard(std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(engine::EngineHandle::from(
+            eng.borrow().clone()
+        )))
+    })
 }
 
+/// Loads a block from its JSON AST and validates it. On failure returns
+/// null and, if `err` is not null, stores an error object in it that must
+/// be released with `oneshell_load_error_destroy`.
 #[no_mangle]
-pub extern "C" fn oneshell_block_load(ast: *const c_char) -> *mut engine::Block {
-    let ast = unsafe {
-        CStr::from_ptr(ast).to_str().unwrap()
-    };
-    match engine::Engine::load_block(ast) {
+pub extern "C" fn oneshell_block_load(ast: *const c_char, err: *mut *mut engine::LoadError) -> *mut engine::Block {
+    block_load(ast, err, engine::Engine::load_block)
+}
+
+/// Like `oneshell_block_load`, but skips validation.
+#[no_mangle]
+pub extern "C" fn oneshell_block_load_unchecked(ast: *const c_char, err: *mut *mut engine::LoadError) -> *mut engine::Block {
+    block_load(ast, err, engine::Engine::load_block_unchecked)
+}
+
+fn block_load(
+    ast: *const c_char,
+    err: *mut *mut engine::LoadError,
+    load: fn(&str) -> Result<Box<engine::Block>, engine::LoadError>
+) -> *mut engine::Block {
+    let ret = ffi_guard(Err(engine::LoadError::invalid_input("Panic while loading block")), || {
+        if ast.is_null() {
+            return Err(engine::LoadError::invalid_input("AST is null"));
+        }
+        let ast = match unsafe { CStr::from_ptr(ast) }.to_str() {
+            Ok(v) => v,
+            Err(_) => return Err(engine::LoadError::invalid_input("AST is not valid UTF-8"))
+        };
+        load(ast)
+    });
+
+    match ret {
         Ok(v) => Box::into_raw(v),
         Err(e) => {
-            eprintln!("{:?}", e);
+            if !err.is_null() {
+                unsafe {
+                    *err = Box::into_raw(Box::new(e));
+                }
+            }
             std::ptr::null_mut()
         }
     }
 }
 
+/// Like `oneshell_block_load`, but compiles a shell script. Errors carry
+/// the script's line and column and an empty path.
+#[no_mangle]
+pub extern "C" fn oneshell_script_load(src: *const c_char, err: *mut *mut engine::LoadError) -> *mut engine::Block {
+    let ret = ffi_guard(Err(engine::LoadError::invalid_input("Panic while parsing script")), || {
+        if src.is_null() {
+            return Err(engine::LoadError::invalid_input("Script is null"));
+        }
+        let src = match unsafe { CStr::from_ptr(src) }.to_str() {
+            Ok(v) => v,
+            Err(_) => return Err(engine::LoadError::invalid_input("Script is not valid UTF-8"))
+        };
+        engine::Engine::parse_script(src)
+    });
+
+    match ret {
+        Ok(v) => Box::into_raw(v),
+        Err(e) => {
+            if !err.is_null() {
+                unsafe {
+                    *err = Box::into_raw(Box::new(e));
+                }
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_destroy(err: *mut engine::LoadError) {
+    if err.is_null() {
+        return;
+    }
+    ffi_guard((), || unsafe {
+        drop(Box::from_raw(err));
+    })
+}
+
+/// One of the `engine::LoadErrorKind` values.
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_kind(err: *const engine::LoadError) -> i32 {
+    match unsafe { err.as_ref() } {
+        Some(v) => v.kind as i32,
+        None => -1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_line(err: *const engine::LoadError) -> usize {
+    match unsafe { err.as_ref() } {
+        Some(v) => v.line,
+        None => 0
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_column(err: *const engine::LoadError) -> usize {
+    match unsafe { err.as_ref() } {
+        Some(v) => v.column,
+        None => 0
+    }
+}
+
+/// Returns an owned string; free it with `oneshell_string_destroy`.
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_message(err: *const engine::LoadError) -> *mut c_char {
+    match unsafe { err.as_ref() } {
+        Some(v) => into_c_string(v.message.clone()),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// Returns an owned string; free it with `oneshell_string_destroy`.
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_path(err: *const engine::LoadError) -> *mut c_char {
+    match unsafe { err.as_ref() } {
+        Some(v) => into_c_string(v.path.clone()),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// Returns every problem found by validation as a JSON array of objects
+/// with `message`, `path` and optionally `span`. The array is empty for
+/// other kinds of errors.
+#[no_mangle]
+pub extern "C" fn oneshell_load_error_diagnostics(err: *const engine::LoadError) -> *mut c_char {
+    match unsafe { err.as_ref() } {
+        Some(v) => ffi_guard(std::ptr::null_mut(), || match serde_json::to_string(&v.diagnostics) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// Checks a block as `oneshell_block_load` does. Returns the problems
+/// found, in the form of `oneshell_load_error_diagnostics`.
+#[no_mangle]
+pub extern "C" fn oneshell_block_validate(blk: *const engine::Block) -> *mut c_char {
+    match unsafe { blk.as_ref() } {
+        Some(blk) => ffi_guard(std::ptr::null_mut(), || match serde_json::to_string(&blk.validate()) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }),
+        None => std::ptr::null_mut()
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn oneshell_block_destroy(blk: *mut engine::Block) {
-    unsafe {
-        Box::from_raw(blk);
+    if blk.is_null() {
+        return;
+    }
+    ffi_guard((), || unsafe {
+        drop(Box::from_raw(blk));
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_eval_block(eng: *const engine::EngineHandle, blk: *mut engine::Block) -> i32 {
+    let (eng, blk) = match unsafe { (eng.as_ref(), blk.as_mut()) } {
+        (Some(eng), Some(blk)) => (eng, blk),
+        _ => return signals::EXCEPTION
+    };
+    ffi_guard(signals::EXCEPTION, || eng.eval_block(blk))
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_snapshot(eng: *const engine::EngineHandle) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    ffi_guard(std::ptr::null_mut(), || {
+        let eng = eng.borrow();
+        match eng.snapshot() {
+            Ok(v) => into_c_string(v),
+            Err(e) => {
+                eng.report_error(&*e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_restore(eng: *const engine::EngineHandle, data: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let data = match unsafe { c_str_arg(data) } {
+        Some(v) => v,
+        None => return -1
+    };
+    ffi_guard(-1, || {
+        let ret = eng.borrow_mut().restore(data);
+        match ret {
+            Ok(_) => 0,
+            Err(e) => {
+                eng.borrow().report_error(&*e);
+                -1
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_string_destroy(s: *mut c_char) {
+    if s.is_null() {
+        return;
+    }
+    ffi_guard((), || unsafe {
+        drop(CString::from_raw(s));
+    })
+}
+
+unsafe fn c_str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(v) => v.into_raw(),
+        Err(_) => std::ptr::null_mut()
+    }
+}
+
+/// Returns 1 if the variable is readonly or requires another type.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_global(eng: *const engine::EngineHandle, name: *const c_char, value: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let (name, value) = match unsafe { (c_str_arg(name), c_str_arg(value)) } {
+        (Some(name), Some(value)) => (name, value),
+        _ => return -1
+    };
+    let value: var::Value = match serde_json::from_str(value) {
+        Ok(v) => v,
+        Err(_) => return -1
+    };
+
+    ffi_guard(-1, || {
+        match eng.borrow_mut().assign(engine::Scope::Global, name, var::Variable::from_value(value)) {
+            Ok(_) => 0,
+            Err(_) => 1
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_get_global(eng: *const engine::EngineHandle, name: *const c_char) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    let name = match unsafe { c_str_arg(name) } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+
+    ffi_guard(std::ptr::null_mut(), || {
+        let eng = eng.borrow();
+        let v = match eng.vars.get(name) {
+            Some(v) => v,
+            None => return std::ptr::null_mut()
+        };
+        let ret = serde_json::to_string(&v.impl_ref().value);
+        match ret {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }
+    })
+}
+
+/// Returns 1 if the variable wasn't set, or 2 if it is readonly.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_unset_global(eng: *const engine::EngineHandle, name: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let name = match unsafe { c_str_arg(name) } {
+        Some(v) => v,
+        None => return -1
+    };
+
+    ffi_guard(-1, || {
+        let mut eng = eng.borrow_mut();
+        let existed = eng.vars.contains_key(name);
+        match eng.unset(engine::Scope::Global, name) {
+            Ok(_) if existed => 0,
+            Ok(_) => 1,
+            Err(_) => 2
+        }
+    })
+}
+
+/// Returns a JSON array of global variable names.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_list_globals(eng: *const engine::EngineHandle) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+
+    ffi_guard(std::ptr::null_mut(), || {
+        let mut names: Vec<String> = eng.borrow().vars.keys().map(|k| k.clone()).collect();
+        names.sort();
+
+        match serde_json::to_string(&names) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_get_last_exit_status(eng: *const engine::EngineHandle) -> i32 {
+    match unsafe { eng.as_ref() } {
+        Some(v) => ffi_guard(-1, || v.borrow().get_last_exit_status()),
+        None => -1
     }
 }
 
+pub type BuiltinCallback = extern "C" fn(
+    user_data: *mut c_void,
+    argc: usize,
+    argv: *const *const c_char,
+    envc: usize,
+    envp: *const *const c_char, // "KEY=VALUE" entries
+    io: *mut c_void // pass to oneshell_builtin_io_read / oneshell_builtin_io_write
+) -> i32;
+
+struct CBuiltin {
+    callback: BuiltinCallback,
+    user_data: *mut c_void
+}
+
+impl builtin::Builtin for CBuiltin {
+    fn run(&self, args: &[String], env: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        let args: Vec<CString> = args.iter()
+            .map(|v| CString::new(v.as_str()).unwrap_or_default())
+            .collect();
+        let env: Vec<CString> = env.iter()
+            .map(|&(ref k, ref v)| CString::new(format!("{}={}", k, v)).unwrap_or_default())
+            .collect();
+        let argv: Vec<*const c_char> = args.iter().map(|v| v.as_ptr()).collect();
+        let envp: Vec<*const c_char> = env.iter().map(|v| v.as_ptr()).collect();
+
+        (self.callback)(
+            self.user_data,
+            argv.len(),
+            argv.as_ptr(),
+            envp.len(),
+            envp.as_ptr(),
+            io as *mut builtin::BuiltinIo as *mut c_void
+        )
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn oneshell_engine_register_builtin(
+    eng: *const engine::EngineHandle,
+    name: *const c_char,
+    callback: BuiltinCallback,
+    user_data: *mut c_void
+) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let name = match unsafe { c_str_arg(name) } {
+        Some(v) => v,
+        None => return -1
+    };
+
+    ffi_guard(-1, || {
+        eng.borrow_mut().register_builtin(name, CBuiltin {
+            callback: callback,
+            user_data: user_data
+        });
+        0
+    })
+}
+
 #[no_mangle]
-pub extern "C" fn oneshell_engine_eval_block(eng: &mut engine::EngineHandle, blk: &mut engine::Block) -> i32 {
-    eng.eval_block(blk)
+pub extern "C" fn oneshell_engine_unregister_builtin(eng: *const engine::EngineHandle, name: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let name = match unsafe { c_str_arg(name) } {
+        Some(v) => v,
+        None => return -1
+    };
+
+    ffi_guard(-1, || {
+        if eng.borrow_mut().unregister_builtin(name) {
+            0
+        } else {
+            1
+        }
+    })
+}
+
+/// Reads up to `len` bytes from a builtin's stdin. Returns the number of
+/// bytes read (0 on EOF) or -1 on error.
+#[no_mangle]
+pub extern "C" fn oneshell_builtin_io_read(io: *mut c_void, buf: *mut u8, len: usize) -> isize {
+    if io.is_null() || buf.is_null() {
+        return -1;
+    }
+    let io = unsafe { &mut *(io as *mut builtin::BuiltinIo) };
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+    ffi_guard(-1, || {
+        match io.stdin.read(buf) {
+            Ok(n) => n as isize,
+            Err(_) => -1
+        }
+    })
+}
+
+/// Writes `len` bytes to a builtin's stdout. Returns 0 on success.
+#[no_mangle]
+pub extern "C" fn oneshell_builtin_io_write(io: *mut c_void, buf: *const u8, len: usize) -> i32 {
+    if io.is_null() || buf.is_null() {
+        return -1;
+    }
+    let io = unsafe { &mut *(io as *mut builtin::BuiltinIo) };
+    let buf = unsafe { std::slice::from_raw_parts(buf, len) };
+    ffi_guard(-1, || {
+        match io.stdout.write_all(buf) {
+            Ok(_) => 0,
+            Err(_) => -1
+        }
+    })
+}
+
+pub const OUTPUT_STREAM_STDOUT: i32 = 1;
+pub const OUTPUT_STREAM_STDERR: i32 = 2;
+
+pub type OutputCallback = extern "C" fn(
+    user_data: *mut c_void,
+    stream: i32, // OUTPUT_STREAM_STDOUT or OUTPUT_STREAM_STDERR
+    data: *const u8,
+    len: usize
+);
+
+struct COutputSink {
+    callback: OutputCallback,
+    user_data: *mut c_void
+}
+
+impl output::OutputSink for COutputSink {
+    fn write_output(&self, data: &str) {
+        (self.callback)(self.user_data, OUTPUT_STREAM_STDOUT, data.as_ptr(), data.len());
+    }
+
+    fn write_error(&self, data: &str) {
+        (self.callback)(self.user_data, OUTPUT_STREAM_STDERR, data.as_ptr(), data.len());
+    }
+}
+
+/// Redirects engine output to `callback`. Passing a null callback restores stdout.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_output_callback(
+    eng: *const engine::EngineHandle,
+    callback: Option<OutputCallback>,
+    user_data: *mut c_void
+) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+
+    ffi_guard(-1, || {
+        match callback {
+            Some(callback) => eng.borrow_mut().set_output_sink(COutputSink {
+                callback: callback,
+                user_data: user_data
+            }),
+            None => eng.borrow_mut().set_output_sink(output::StdoutSink)
+        }
+        0
+    })
+}
+
+pub const JIT_POLICY_OFF: i32 = 0;
+pub const JIT_POLICY_EAGER: i32 = 1;
+pub const JIT_POLICY_AFTER_CALLS: i32 = 2;
+
+/// `threshold` is only used with `JIT_POLICY_AFTER_CALLS`.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_jit_policy(eng: *const engine::EngineHandle, policy: i32, threshold: usize) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let policy = match policy {
+        JIT_POLICY_OFF => jit::JitPolicy::Off,
+        JIT_POLICY_EAGER => jit::JitPolicy::Eager,
+        JIT_POLICY_AFTER_CALLS => jit::JitPolicy::AfterCalls(threshold),
+        _ => return -1
+    };
+    ffi_guard(-1, || {
+        eng.borrow_mut().set_jit_policy(policy);
+        0
+    })
+}
+
+pub const GLOB_NO_MATCH_KEEP: i32 = 0;
+pub const GLOB_NO_MATCH_REMOVE: i32 = 1;
+pub const GLOB_NO_MATCH_FAIL: i32 = 2;
+
+/// Sets what a command argument pattern that matches no path expands to,
+/// and whether wildcards match names starting with `.`.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_glob_options(eng: *const engine::EngineHandle, no_match: i32, dotglob: i32) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let no_match = match no_match {
+        GLOB_NO_MATCH_KEEP => glob::NoMatch::Keep,
+        GLOB_NO_MATCH_REMOVE => glob::NoMatch::Remove,
+        GLOB_NO_MATCH_FAIL => glob::NoMatch::Fail,
+        _ => return -1
+    };
+    ffi_guard(-1, || {
+        eng.borrow_mut().set_glob_options(glob::Options {
+            no_match: no_match,
+            dotglob: dotglob != 0
+        });
+        0
+    })
+}
+
+/// Returns and clears pending diagnostics as a JSON array of strings.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_take_diagnostics(eng: *const engine::EngineHandle) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    ffi_guard(std::ptr::null_mut(), || {
+        let diagnostics = eng.borrow_mut().take_diagnostics();
+        match serde_json::to_string(&diagnostics) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }
+    })
+}
+
+/// Dumps the IR of every block compiled from now on to `path`, or to the
+/// output sink if `path` is null. Disabled again when `enabled` is 0.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_jit_ir_dump(eng: *const engine::EngineHandle, enabled: i32, path: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let dump = if enabled == 0 {
+        None
+    } else if path.is_null() {
+        Some(jit::IrDump::Output)
+    } else {
+        match unsafe { c_str_arg(path) } {
+            Some(v) => Some(jit::IrDump::File(v.to_string())),
+            None => return -1
+        }
+    };
+    ffi_guard(-1, || {
+        eng.borrow_mut().set_jit_ir_dump(dump);
+        0
+    })
+}
+
+/// Returns the engine's JIT counters as a JSON object.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_get_jit_stats(eng: *const engine::EngineHandle) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    ffi_guard(std::ptr::null_mut(), || {
+        match serde_json::to_string(&eng.borrow().jit_stats) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }
+    })
+}
+
+/// Returns the IR of a compiled block, if it was built with IR dumping enabled.
+#[no_mangle]
+pub extern "C" fn oneshell_block_get_jit_ir(blk: *const engine::Block) -> *mut c_char {
+    match unsafe { blk.as_ref() } {
+        Some(blk) => ffi_guard(std::ptr::null_mut(), || match blk.jit_info.as_ref().and_then(|v| v.ir.as_ref()) {
+            Some(ir) => into_c_string(ir.clone()),
+            None => std::ptr::null_mut()
+        }),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// Caches optimized modules in the directory `dir`, or disables the cache if
+/// `dir` is null.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_aot_cache_dir(eng: *const engine::EngineHandle, dir: *const c_char) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    let cache = if dir.is_null() {
+        None
+    } else {
+        match unsafe { c_str_arg(dir) } {
+            Some(v) => Some(jit::AotCache::new(v)),
+            None => return -1
+        }
+    };
+    ffi_guard(-1, || {
+        eng.borrow_mut().set_aot_cache(cache);
+        0
+    })
+}
+
+/// Compiles a block and all blocks nested in it ahead of time.
+/// Returns 0 on success and 1 if any block fell back to the interpreter.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_compile_block_aot(eng: *const engine::EngineHandle, blk: *mut engine::Block) -> i32 {
+    let (eng, blk) = match unsafe { (eng.as_ref(), blk.as_mut()) } {
+        (Some(eng), Some(blk)) => (eng, blk),
+        _ => return -1
+    };
+    ffi_guard(-1, || if eng.compile_aot(blk) { 0 } else { 1 })
+}
+
+/// Returns the per-branch results of the last `Parallel` operation as a JSON
+/// array of `{ "signal": ..., "exit_status": ... }` objects. `signal` is null
+/// for branches skipped after a failure.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_get_branch_statuses(eng: *const engine::EngineHandle) -> *mut c_char {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return std::ptr::null_mut()
+    };
+    ffi_guard(std::ptr::null_mut(), || {
+        match serde_json::to_string(&eng.borrow().branch_statuses) {
+            Ok(v) => into_c_string(v),
+            Err(_) => std::ptr::null_mut()
+        }
+    })
+}
+
+/// Renders a block as shell-like text, for debugging.
+#[no_mangle]
+pub extern "C" fn oneshell_block_to_string(blk: *const engine::Block) -> *mut c_char {
+    match unsafe { blk.as_ref() } {
+        Some(blk) => ffi_guard(std::ptr::null_mut(), || into_c_string(blk.to_string())),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// Nonzero `enabled` makes backtraces include the native stack in
+/// addition to the script call stack.
+#[no_mangle]
+pub extern "C" fn oneshell_engine_set_native_backtrace(eng: *const engine::EngineHandle, enabled: i32) -> i32 {
+    let eng = match unsafe { eng.as_ref() } {
+        Some(v) => v,
+        None => return -1
+    };
+    ffi_guard(-1, || {
+        eng.borrow_mut().set_native_backtrace(enabled != 0);
+        0
+    })
 }
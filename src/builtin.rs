@@ -0,0 +1,56 @@
+use std;
+use std::io::{Read, Write};
+
+pub struct BuiltinIo<'a> {
+    pub stdin: &'a mut Read,
+    pub stdout: &'a mut Write,
+    pub cwd: &'a mut Option<String> // the engine's; kept unless in a pipeline
+}
+
+/// A command implemented by the host and run in-process instead of
+/// being spawned as a child.
+pub trait Builtin {
+    /// `args[0]` is the command name. Returns the exit status.
+    fn run(&self, args: &[String], env: &[(String, String)], io: &mut BuiltinIo) -> i32;
+}
+
+/// `cd [DIR]`: changes the engine's working directory to `DIR`, or to
+/// `$HOME` without an argument. Relative paths start at the current one.
+pub struct ChangeDir;
+
+impl Builtin for ChangeDir {
+    fn run(&self, args: &[String], env: &[(String, String)], io: &mut BuiltinIo) -> i32 {
+        let target = match args.get(1) {
+            Some(v) => v.clone(),
+            None => match env.iter().rev().find(|&&(ref k, _)| k == "HOME") {
+                Some(&(_, ref v)) => v.clone(),
+                None => match std::env::var("HOME") {
+                    Ok(v) => v,
+                    Err(_) => {
+                        let _ = writeln!(std::io::stderr(), "cd: HOME not set");
+                        return 1;
+                    }
+                }
+            }
+        };
+
+        let path = match *io.cwd {
+            Some(ref cwd) => std::path::Path::new(cwd).join(&target),
+            None => std::path::PathBuf::from(&target)
+        };
+        match path.canonicalize() {
+            Ok(ref v) if v.is_dir() => {
+                *io.cwd = Some(v.to_string_lossy().into_owned());
+                0
+            },
+            Ok(_) => {
+                let _ = writeln!(std::io::stderr(), "cd: {}: Not a directory", target);
+                1
+            },
+            Err(e) => {
+                let _ = writeln!(std::io::stderr(), "cd: {}: {}", target, e);
+                1
+            }
+        }
+    }
+}
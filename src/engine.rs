@@ -2,15 +2,24 @@ use std;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::io::{Read, Write};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::process::{Command, Stdio};
 use std::error::Error;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde_json;
 use backtrace;
 use jit;
 use signals;
 use var;
+use builtin;
+use output;
+use parser;
+use glob;
+use span::{self, Span};
+use validate;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
 #[derive(Clone)]
 pub struct EngineHandle {
@@ -46,12 +55,73 @@ impl From<Engine> for EngineHandle {
     }
 }
 
-#[derive(Default)]
 pub struct Engine {
     pub last_exit_status: i32,
+    pub branch_statuses: Vec<BranchStatus>, // of the last `Parallel` op
     pub last_return_value: Option<var::Variable>,
-    pub call_stack: Vec<Box<FunctionState>>,
-    pub vars: HashMap<String, var::Variable>
+    pub call_stack: Vec<Box<FunctionState>>, // one per call, above a root frame for top-level code
+    pub script_stack: Vec<ScriptFrame>, // one per call, under a frame for the outermost block
+    pub vars: HashMap<String, var::Variable>,
+    pub attributes: HashMap<String, Attributes>, // of global variables
+    pub env: HashMap<String, String>, // exported to every spawned child
+    pub cwd: Option<String>,
+    pub glob_options: glob::Options,
+    pub builtins: HashMap<String, Rc<builtin::Builtin>>,
+    pub parent_builtins: HashSet<String>, // of the engine that started this parallel branch
+    pub output: Rc<output::OutputSink>,
+    pub jit_policy: jit::JitPolicy,
+    pub jit_ir_dump: Option<jit::IrDump>,
+    pub jit_stats: jit::JitStats,
+    pub aot_cache: Option<jit::AotCache>,
+    pub trace: bool, // print ops to the error stream before running them
+    pub native_backtrace: bool, // add the native stack to backtraces
+    pub interrupted: Arc<AtomicBool>, // ops and loop iterations fail while set, e.g. from a signal handler
+    pub diagnostics: Vec<String> // non-fatal problems, drained by the host
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine {
+            last_exit_status: 0,
+            branch_statuses: Vec::new(),
+            last_return_value: None,
+            call_stack: vec![Box::new(FunctionState::new())], // the root frame
+            script_stack: Vec::new(),
+            vars: HashMap::new(),
+            attributes: HashMap::new(),
+            env: HashMap::new(),
+            cwd: None,
+            glob_options: glob::Options::default(),
+            builtins: HashMap::new(),
+            parent_builtins: HashSet::new(),
+            output: Rc::new(output::StdoutSink),
+            jit_policy: jit::JitPolicy::default(),
+            jit_ir_dump: None,
+            jit_stats: jit::JitStats::default(),
+            aot_cache: None,
+            trace: false,
+            native_backtrace: false,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            diagnostics: Vec::new()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub vars: HashMap<String, var::Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub attributes: HashMap<String, Attributes>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<String>,
+    pub last_exit_status: i32,
+    // The root frame, which holds the locals of top-level code.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub locals: HashMap<String, var::Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub local_attributes: HashMap<String, Attributes>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub local_globals: HashSet<String>
 }
 
 impl Clone for Engine {
@@ -71,20 +141,74 @@ impl Clone for Engine {
 
         Engine {
             last_exit_status: self.last_exit_status,
+            branch_statuses: self.branch_statuses.clone(),
             last_return_value: last_return_value,
             call_stack: self.call_stack.iter().map(|v| Box::new(v.deep_clone())).collect(),
-            vars: new_vars
+            script_stack: Vec::new(), // refers to blocks being run by `self`
+            vars: new_vars,
+            attributes: self.attributes.clone(),
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            glob_options: self.glob_options,
+            builtins: self.builtins.clone(),
+            parent_builtins: self.parent_builtins.clone(),
+            output: self.output.clone(),
+            jit_policy: self.jit_policy,
+            jit_ir_dump: self.jit_ir_dump.clone(),
+            jit_stats: jit::JitStats::default(),
+            aot_cache: self.aot_cache.clone(),
+            trace: self.trace,
+            native_backtrace: self.native_backtrace,
+            interrupted: Arc::new(AtomicBool::new(false)), // interrupts target a single engine
+            diagnostics: Vec::new()
         }
     }
 }
 
-#[derive(Deserialize)]
 pub struct Block {
     pub ops: Vec<Operation>,
-    #[serde(skip)]
+    pub spans: Vec<Option<Span>>, // by op index; may be shorter than `ops`
+    pub scoped: bool, // runs in a block scope of its own, see `Engine::local`
     pub jit_info: Option<jit::BlockJitInfo>,
-    #[serde(skip)]
-    call_count_before_jit: usize
+    pub jit: Option<jit::JitPolicy>,
+    call_count_before_jit: usize,
+    jit_failed: bool
+}
+
+impl Serialize for Block {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let n_fields = 1 + self.scoped as usize + self.jit.is_some() as usize;
+        let mut st = s.serialize_struct("Block", n_fields)?;
+        st.serialize_field("ops", &span::SpannedOpsRef(&self.ops, &self.spans))?;
+        if self.scoped {
+            st.serialize_field("scoped", &self.scoped)?;
+        }
+        if self.jit.is_some() {
+            st.serialize_field("jit", &self.jit)?;
+        }
+        st.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockRepr {
+    ops: span::SpannedOps,
+    #[serde(default)]
+    scoped: bool,
+    #[serde(default)]
+    jit: Option<jit::JitPolicy>
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Block, D::Error> {
+        let repr = BlockRepr::deserialize(d)?;
+        let mut blk = Block::with_spans(repr.ops.0, repr.ops.1);
+        blk.scoped = repr.scoped;
+        blk.jit = repr.jit;
+        Ok(blk)
+    }
 }
 
 impl PartialEq for Block {
@@ -97,38 +221,280 @@ impl Clone for Block {
     fn clone(&self) -> Block {
         Block {
             ops: self.ops.clone(),
+            spans: self.spans.clone(),
+            scoped: self.scoped,
+            jit_info: None,
+            jit: self.jit,
+            call_count_before_jit: 0,
+            jit_failed: false
+        }
+    }
+}
+
+impl Block {
+    pub fn new(ops: Vec<Operation>) -> Block {
+        Block::with_spans(ops, Vec::new())
+    }
+
+    pub fn with_spans(ops: Vec<Operation>, spans: Vec<Option<Span>>) -> Block {
+        Block {
+            ops: ops,
+            spans: spans,
+            scoped: false,
             jit_info: None,
-            call_count_before_jit: 0
+            jit: None,
+            call_count_before_jit: 0,
+            jit_failed: false
+        }
+    }
+
+    /// Where the op at `index` came from, if known.
+    pub fn span(&self, index: usize) -> Option<&Span> {
+        self.spans.get(index).and_then(|v| v.as_ref())
+    }
+
+    /// Sets the file of every span in this block and the blocks nested in
+    /// it, including those of commands.
+    pub fn set_span_file(&mut self, file: &str) {
+        for span in self.spans.iter_mut() {
+            if let Some(ref mut span) = *span {
+                span.file = Some(file.to_string());
+            }
+        }
+        for op in self.ops.iter_mut() {
+            let infos: Vec<&mut ExecInfo> = match *op {
+                Operation::Exec(ref mut info) | Operation::BackgroundExec(ref mut info) => vec![info],
+                Operation::ParallelExec(ref mut infos) => infos.iter_mut().collect(),
+                _ => continue
+            };
+            for info in infos {
+                if let Some(ref mut span) = info.span {
+                    span.file = Some(file.to_string());
+                }
+            }
+        }
+        for child in self.children_mut() {
+            child.set_span_file(file);
+        }
+    }
+
+    /// Blocks nested directly in this block's ops, including literal
+    /// function bodies.
+    pub fn children_mut(&mut self) -> Vec<&mut Block> {
+        let mut ret: Vec<&mut Block> = Vec::new();
+        for op in self.ops.iter_mut() {
+            match *op {
+                Operation::IfElse(ref mut if_blk, ref mut else_blk) => {
+                    ret.push(if_blk);
+                    ret.push(else_blk);
+                },
+                Operation::Loop(ref mut blk) => ret.push(blk),
+                Operation::ForEach(ref mut info) => ret.push(&mut info.body),
+                Operation::Parallel(ref mut info) => ret.extend(info.blocks.iter_mut()),
+                Operation::AssignGlobal(_, ValueSource::Plain(var::Value::Function(ref mut blk)))
+                    | Operation::AssignLocal(_, ValueSource::Plain(var::Value::Function(ref mut blk)))
+                    | Operation::Call(ValueSource::Plain(var::Value::Function(ref mut blk))) => ret.push(blk),
+                _ => {}
+            }
+        }
+        ret
+    }
+}
+
+/// Which variables an op refers to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scope {
+    Local,
+    Global
+}
+
+/// Restrictions on assignments to a variable, kept per name and scope
+/// next to the variable itself. Unsetting a variable drops them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Attributes {
+    #[serde(default)]
+    pub readonly: bool, // can't be assigned, unset or redeclared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<var::Type> // the only type that can be assigned
+}
+
+impl Attributes {
+    // Whether a value of type `ty`, `None` for null, may be assigned.
+    fn check_assign(&self, name: &str, ty: Option<var::Type>) -> Result<(), Box<Error>> {
+        if self.readonly {
+            return Err(readonly_error(name));
+        }
+        match self.value_type {
+            Some(expected) if ty != Some(expected) => Err(
+                format!("Variable `{}` can only hold {} values", name, expected).into()
+            ),
+            _ => Ok(())
         }
     }
 }
 
+fn readonly_error(name: &str) -> Box<Error> {
+    format!("Variable `{}` is readonly", name).into()
+}
+
+/// A call frame. The engine's root frame holds the locals of top-level
+/// code.
 pub struct FunctionState {
-    pub vars: HashMap<String, var::Variable>
+    pub vars: HashMap<String, var::Variable>,
+    pub attributes: HashMap<String, Attributes>,
+    pub globals: HashSet<String>, // names declared global in the frame
+    pub blocks: Vec<BlockScope> // of scoped blocks being run, innermost last
+}
+
+/// Stack depths to restore when a call returns, see `Engine::enter_call`.
+#[derive(Copy, Clone, Debug)]
+pub struct CallDepths {
+    call_stack: usize,
+    script_stack: usize
+}
+
+/// Variables of a scoped block being run.
+#[derive(Default)]
+pub struct BlockScope {
+    pub vars: HashMap<String, var::Variable>,
+    pub attributes: HashMap<String, Attributes>,
+    pub globals: HashSet<String>
+}
+
+enum Binding {
+    Block(usize),
+    Frame,
+    Global
+}
+
+fn deep_clone_vars(vars: &HashMap<String, var::Variable>) -> HashMap<String, var::Variable> {
+    vars.iter().map(|(k, v)| (k.clone(), v.deep_clone())).collect()
 }
 
 impl FunctionState {
     pub fn new() -> FunctionState {
         FunctionState {
-            vars: HashMap::new()
+            vars: HashMap::new(),
+            attributes: HashMap::new(),
+            globals: HashSet::new(),
+            blocks: Vec::new()
         }
     }
 
     pub fn deep_clone(&self) -> FunctionState {
-        let mut new_vars: HashMap<String, var::Variable> = HashMap::new();
-        for (k, v) in self.vars.iter() {
-            new_vars.insert(
-                k.clone(),
-                v.deep_clone()
-            );
-        }
         FunctionState {
-            vars: new_vars
+            vars: deep_clone_vars(&self.vars),
+            attributes: self.attributes.clone(),
+            globals: self.globals.clone(),
+            blocks: self.blocks.iter().map(|v| BlockScope {
+                vars: deep_clone_vars(&v.vars),
+                attributes: v.attributes.clone(),
+                globals: v.globals.clone()
+            }).collect()
+        }
+    }
+
+    // The innermost scope that declares `name`, if any.
+    fn binding(&self, name: &str) -> Option<Binding> {
+        for (i, scope) in self.blocks.iter().enumerate().rev() {
+            if scope.globals.contains(name) {
+                return Some(Binding::Global);
+            }
+            if scope.vars.contains_key(name) {
+                return Some(Binding::Block(i));
+            }
+        }
+        if self.globals.contains(name) {
+            Some(Binding::Global)
+        } else if self.vars.contains_key(name) {
+            Some(Binding::Frame)
+        } else {
+            None
+        }
+    }
+}
+
+/// A frame of the script-level call stack.
+pub struct ScriptFrame {
+    pub function: String, // the called variable, or `<main>` for the outermost block
+    block: *const Block,
+    at: *const u8 // points into the op being run; null before the first one
+}
+
+impl ScriptFrame {
+    pub fn new(function: &str, block: &Block) -> ScriptFrame {
+        ScriptFrame {
+            function: function.to_string(),
+            block: block,
+            at: std::ptr::null()
+        }
+    }
+
+    /// Indices of the op being run: first in the frame's block, then in
+    /// the `IfElse` or `Loop` block nested in that op, and so on.
+    ///
+    /// Compiled code only records its position at ops that can fail or
+    /// call, so this may be stale or `None` there.
+    pub fn op_path(&self) -> Option<Vec<usize>> {
+        self.current_op().map(|(path, _)| path)
+    }
+
+    /// Where the op being run came from, if known.
+    pub fn op_span(&self) -> Option<Span> {
+        self.current_op().and_then(|(_, span)| span)
+    }
+
+    fn current_op(&self) -> Option<(Vec<usize>, Option<Span>)> {
+        if self.at.is_null() {
+            return None;
+        }
+        // The frame's block outlives the frame.
+        find_op(unsafe { &*self.block }, self.at as usize)
+    }
+}
+
+fn find_op(blk: &Block, at: usize) -> Option<(Vec<usize>, Option<Span>)> {
+    for (i, op) in blk.ops.iter().enumerate() {
+        let start = op as *const Operation as usize;
+        if at >= start && at < start + std::mem::size_of::<Operation>() {
+            return Some((vec![i], blk.span(i).cloned()));
         }
+
+        let children: Vec<&Block> = match *op {
+            Operation::IfElse(ref if_blk, ref else_blk) => vec![if_blk, else_blk],
+            Operation::Loop(ref blk) => vec![blk],
+            Operation::ForEach(ref info) => vec![&info.body],
+            _ => continue
+        };
+        for child in children {
+            if let Some((path, span)) = find_op(child, at) {
+                let mut ret = vec![i];
+                ret.extend(path);
+                return Some((ret, span));
+            }
+        }
+    }
+    None
+}
+
+/// Returned by a call whose callee raised an exception. The exception
+/// has been reported already, so this one is not.
+#[derive(Debug)]
+pub struct PropagatedException;
+
+impl Error for PropagatedException {
+    fn description(&self) -> &str {
+        "Exception in called function"
+    }
+}
+
+impl std::fmt::Display for PropagatedException {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description())
     }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Operation {
     Exec(ExecInfo),
     ParallelExec(Vec<ExecInfo>),
@@ -138,42 +504,157 @@ pub enum Operation {
     Break,
     AssignGlobal(String, ValueSource),
     AssignLocal(String, ValueSource),
+    Declare(Scope, String),
+    Unset(Scope, String),
+    SetAttributes(Scope, String, Attributes),
     EngineBacktrace,
     Print(StringSource),
     CheckEq(ValueSource, ValueSource),
-    Call(ValueSource)
+    Call(ValueSource),
+    Parallel(ParallelInfo),
+    ForEach(ForEachInfo)
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ExecInfo {
-    command: Vec<StringSource>,
-    env: Vec<EnvInfo>,
-    stdin: StdioConfig,
-    stdout: StdioConfig
+    pub command: Vec<StringSource>,
+    pub env: Vec<EnvInfo>,
+    pub stdin: StdioConfig,
+    pub stdout: StdioConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>
+}
+
+/// Blocks run concurrently, each on a fresh engine seeded with a snapshot
+/// of the current globals, environment and working directory, and with
+/// copies of the current locals. Builtins can't be shared between threads,
+/// so running one of the engine's builtins in a branch is an error. Each
+/// branch's output is written once the branch finishes, in the order the
+/// branches finish.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ParallelInfo {
+    pub blocks: Vec<Block>,
+    #[serde(default)]
+    pub max_concurrency: usize, // 0 runs all branches at once
+    #[serde(default)]
+    pub merge_globals: Vec<String>, // copied back in branch order
+    #[serde(default)]
+    pub fail_fast: bool // don't start pending branches after an exception
+}
+
+/// Runs `body` once for each word of `words`, assigned to `name`. The words
+/// are expanded when the loop starts, with `Glob` sources standing for as
+/// many words as in command arguments.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ForEachInfo {
+    pub name: String,
+    pub scope: Scope,
+    pub words: Vec<StringSource>,
+    pub body: Block
+}
+
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct BranchStatus {
+    pub signal: Option<i32>, // None if skipped by `fail_fast`
+    pub exit_status: i32
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct EnvInfo {
-    key: StringSource,
-    value: StringSource
+    pub key: StringSource,
+    pub value: StringSource
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum StringSource {
     Plain(String),
     GlobalVariable(String),
     LocalVariable(String),
     Value(ValueSource),
-    Join(Vec<StringSource>)
+    Join(Vec<StringSource>),
+    Expand {
+        var: Box<StringSource>, // usually a variable, undefined if unset
+        op: ExpandOp
+    },
+    // A pattern for `glob::expand`. As a command argument, it becomes one
+    // argument per word of the expansion; elsewhere, the pattern without
+    // escapes.
+    Glob(Box<StringSource>),
+    // The value with `glob::escape` applied, for quoted expansions in
+    // patterns.
+    Escape(Box<StringSource>)
+}
+
+/// Parameter expansion operators. Patterns are matched with
+/// `glob::matches`, so text meant literally needs `glob::escape`. An
+/// undefined variable acts like an empty one, except for `Default` and
+/// `Alternative`.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ExpandOp {
+    // `${var-word}`, or `${var:-word}` if `if_empty`: `word` if the
+    // variable is unset (or empty).
+    Default {
+        word: Box<StringSource>,
+        #[serde(default)]
+        if_empty: bool
+    },
+    // `${var+word}` and `${var:+word}`: `word` if the variable is set (and
+    // not empty), otherwise the empty string.
+    Alternative {
+        word: Box<StringSource>,
+        #[serde(default)]
+        if_empty: bool
+    },
+    // `${var#pattern}`, or `${var##pattern}` if `longest`.
+    RemovePrefix {
+        pattern: Box<StringSource>,
+        #[serde(default)]
+        longest: bool
+    },
+    // `${var%pattern}`, or `${var%%pattern}` if `longest`.
+    RemoveSuffix {
+        pattern: Box<StringSource>,
+        #[serde(default)]
+        longest: bool
+    },
+    // `${var/pattern/replacement}`, or `${var//pattern/replacement}` if
+    // `all`. Replaces the longest match at the first position that has one.
+    Replace {
+        pattern: Box<StringSource>,
+        replacement: Box<StringSource>,
+        #[serde(default)]
+        all: bool
+    },
+    // `${#var}`, in characters.
+    Length,
+    // `${var:offset}` and `${var:offset:length}`, in characters. A negative
+    // offset counts from the end, as does a negative length for where the
+    // substring ends.
+    Substring {
+        offset: Box<ValueSource>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        length: Option<Box<ValueSource>>
+    }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum ValueSource {
     Plain(var::Value),
     GlobalVariable(String),
     LocalVariable(String),
     String(Box<StringSource>),
-    LastExitStatus
+    LastExitStatus,
+    Arith(ArithOp, Box<ValueSource>, Box<ValueSource>),
+    IsDefined(String) // 1 if `LocalVariable` would find the name, else 0
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem
 }
 
 impl StringSource {
@@ -184,7 +665,7 @@ impl StringSource {
                 Some(v) => Some(v.to_string()),
                 None => None
             },
-            StringSource::LocalVariable(ref name) => match eng.call_stack.last().unwrap().vars.get(name) {
+            StringSource::LocalVariable(ref name) => match eng.local(name) {
                 Some(v) => Some(v.to_string()),
                 None => None
             },
@@ -200,12 +681,149 @@ impl StringSource {
                     }
                 }
                 Some(ret)
-            }
+            },
+            StringSource::Expand { ref var, ref op } => op.apply(eng, var.fetch(eng)),
+            StringSource::Glob(ref pattern) => pattern.fetch(eng).map(|v| glob::unescape(&v)),
+            StringSource::Escape(ref s) => s.fetch(eng).map(|v| glob::escape(&v))
+        }
+    }
+
+    /// The words this stands for as a command argument: those a `Glob`
+    /// pattern expands to, or just the value. `None` if undefined.
+    pub fn fetch_words(&self, eng: &Engine) -> Result<Option<Vec<String>>, Box<Error>> {
+        let pattern = match *self {
+            StringSource::Glob(ref pattern) => pattern,
+            _ => return Ok(self.fetch(eng).map(|v| vec![v]))
+        };
+        let pattern = match pattern.fetch(eng) {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let cwd = std::path::Path::new(eng.cwd.as_ref().map(|v| v.as_str()).unwrap_or("."));
+        Ok(Some(glob::expand(&pattern, cwd, eng.glob_options)?))
+    }
+}
+
+impl ExpandOp {
+    fn apply(&self, eng: &Engine, value: Option<String>) -> Option<String> {
+        match *self {
+            ExpandOp::Default { ref word, if_empty } => match value {
+                Some(ref v) if if_empty && v.is_empty() => word.fetch(eng),
+                Some(v) => Some(v),
+                None => word.fetch(eng)
+            },
+            ExpandOp::Alternative { ref word, if_empty } => match value {
+                Some(ref v) if if_empty && v.is_empty() => Some(String::new()),
+                Some(_) => word.fetch(eng),
+                None => Some(String::new())
+            },
+            _ => Some(self.apply_to(eng, value.unwrap_or_default().as_str()))
+        }
+    }
+
+    // Undefined patterns and replacements are empty, and undefined or
+    // non-integer offsets and lengths are 0, as in a shell.
+    fn apply_to(&self, eng: &Engine, value: &str) -> String {
+        // Char boundaries, including the end.
+        let bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).chain(Some(value.len())).collect();
+
+        match *self {
+            ExpandOp::RemovePrefix { ref pattern, longest } => {
+                let pattern = pattern.fetch(eng).unwrap_or_default();
+                let mut ends: Vec<usize> = bounds.clone();
+                if longest {
+                    ends.reverse();
+                }
+                match ends.into_iter().find(|&end| glob::matches(pattern.as_str(), &value[..end])) {
+                    Some(end) => value[end..].to_string(),
+                    None => value.to_string()
+                }
+            },
+            ExpandOp::RemoveSuffix { ref pattern, longest } => {
+                let pattern = pattern.fetch(eng).unwrap_or_default();
+                let mut starts: Vec<usize> = bounds.clone();
+                if !longest {
+                    starts.reverse();
+                }
+                match starts.into_iter().find(|&start| glob::matches(pattern.as_str(), &value[start..])) {
+                    Some(start) => value[..start].to_string(),
+                    None => value.to_string()
+                }
+            },
+            ExpandOp::Replace { ref pattern, ref replacement, all } => {
+                let pattern = pattern.fetch(eng).unwrap_or_default();
+                let replacement = replacement.fetch(eng).unwrap_or_default();
+                if pattern.is_empty() {
+                    return value.to_string();
+                }
+
+                let mut ret = String::new();
+                let mut i = 0;
+                while i + 1 < bounds.len() {
+                    let start = bounds[i];
+                    // Empty matches don't count.
+                    let end = (i + 1..bounds.len()).rev()
+                        .find(|&j| glob::matches(pattern.as_str(), &value[start..bounds[j]]));
+                    match end {
+                        Some(j) => {
+                            ret += replacement.as_str();
+                            i = j;
+                            if !all {
+                                break;
+                            }
+                        },
+                        None => {
+                            ret += &value[start..bounds[i + 1]];
+                            i += 1;
+                        }
+                    }
+                }
+                ret += &value[bounds[i]..];
+                ret
+            },
+            ExpandOp::Length => (bounds.len() - 1).to_string(),
+            ExpandOp::Substring { ref offset, ref length } => {
+                let len = (bounds.len() - 1) as i64;
+                let number = |v: &ValueSource| v.fetch(eng).and_then(|v| v.impl_ref().value.to_integer()).unwrap_or(0);
+                let mut start = number(offset);
+                if start < 0 {
+                    start += len;
+                    if start < 0 {
+                        return String::new();
+                    }
+                }
+                let start = std::cmp::min(start, len);
+                let end = match *length {
+                    Some(ref length) => {
+                        let length = number(length);
+                        if length < 0 {
+                            len + length
+                        } else {
+                            start.saturating_add(length)
+                        }
+                    },
+                    None => len
+                };
+                if end < start {
+                    return String::new();
+                }
+                let end = std::cmp::min(end, len);
+                value[bounds[start as usize]..bounds[end as usize]].to_string()
+            },
+            ExpandOp::Default { .. } | ExpandOp::Alternative { .. } => unreachable!()
         }
     }
 }
 
 impl ValueSource {
+    /// How a function called through this source is named in backtraces.
+    pub fn function_name(&self) -> String {
+        match *self {
+            ValueSource::GlobalVariable(ref name) | ValueSource::LocalVariable(ref name) => name.clone(),
+            _ => "<anonymous>".to_string()
+        }
+    }
+
     pub fn fetch(&self, eng: &Engine) -> Option<var::Variable> {
         match *self {
             ValueSource::Plain(ref v) => Some(var::Variable::from_value(v.clone())),
@@ -213,7 +831,7 @@ impl ValueSource {
                 Some(v) => Some(v.clone()),
                 None => None
             },
-            ValueSource::LocalVariable(ref name) => match eng.call_stack.last().unwrap().vars.get(name) {
+            ValueSource::LocalVariable(ref name) => match eng.local(name) {
                 Some(v) => Some(v.clone()),
                 None => None
             },
@@ -225,23 +843,51 @@ impl ValueSource {
                     var::Value::String(v))
                 ),
                 None => None
-            }
+            },
+            ValueSource::Arith(op, ref left, ref right) => {
+                let left = match left.fetch(eng) {
+                    Some(v) => v,
+                    None => return None
+                };
+                let right = match right.fetch(eng) {
+                    Some(v) => v,
+                    None => return None
+                };
+                let ret = left.impl_ref().value.arith(op, &right.impl_ref().value);
+                ret.map(|v| var::Variable::from_value(v))
+            },
+            ValueSource::IsDefined(ref name) => Some(var::Variable::from_value(
+                var::Value::Integer(eng.local(name).is_some() as i64)
+            ))
         }
     }
 }
 
 impl ExecInfo {
-    pub fn build(&self, eng: &Engine) -> Result<Command, Box<Error>> {
-        let mut cmd = Command::new(match self.command[0].fetch(eng) {
-            Some(v) => v,
-            None => return Err("Invalid first argument".into())
-        });
-        for i in 1..self.command.len() {
-            cmd.arg(match self.command[i].fetch(eng) {
-                Some(v) => v,
+    pub fn build_args(&self, eng: &Engine) -> Result<Vec<String>, Box<Error>> {
+        if self.command.len() == 0 {
+            return Err("Empty command".into());
+        }
+
+        let mut args = Vec::with_capacity(self.command.len());
+        for (i, arg) in self.command.iter().enumerate() {
+            match arg.fetch_words(eng)? {
+                Some(v) => args.extend(v),
+                None if i == 0 => return Err("Invalid first argument".into()),
                 None => return Err("Invalid argument found in parameter list".into())
-            });
+            }
         }
+        if args.len() == 0 {
+            return Err("Empty command".into());
+        }
+        Ok(args)
+    }
+
+    // Exported engine env first, so per-command entries override it.
+    pub fn build_env(&self, eng: &Engine) -> Vec<(String, String)> {
+        let mut ret: Vec<(String, String)> = eng.env.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
 
         for env in self.env.iter() {
             let k = match env.key.fetch(eng) {
@@ -249,27 +895,114 @@ impl ExecInfo {
                 None => continue
             };
             let v = env.value.fetch(eng).unwrap_or("".to_string());
+            ret.push((k, v));
+        }
+        ret
+    }
+
+    pub fn build(&self, eng: &Engine) -> Result<Command, Box<Error>> {
+        let args = self.build_args(eng)?;
+
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+
+        for (k, v) in self.build_env(eng) {
             cmd.env(k, v);
         }
 
+        if let Some(ref cwd) = eng.cwd {
+            cmd.current_dir(cwd);
+        }
+
         cmd.stdin(match self.stdin {
             StdioConfig::Inherit => Stdio::inherit(),
-            StdioConfig::Pipe(_) => Stdio::piped()
+            StdioConfig::Pipe(_) => Stdio::piped(),
+            _ => Stdio::from(self.stdin.open_file(eng, false)?.unwrap())
         });
 
         cmd.stdout(match self.stdout {
             StdioConfig::Inherit => Stdio::inherit(),
-            StdioConfig::Pipe(_) => Stdio::piped()
+            StdioConfig::Pipe(_) => Stdio::piped(),
+            _ => Stdio::from(self.stdout.open_file(eng, true)?.unwrap())
         });
 
         Ok(cmd)
     }
 }
 
-#[derive(Deserialize, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum StdioConfig {
     Inherit,
-    Pipe(String) // pipe name
+    Pipe(String), // pipe name
+    File(StringSource), // truncated when used as stdout
+    AppendFile(StringSource) // same as `File` when used as stdin
+}
+
+impl StdioConfig {
+    /// Opens the file for `File`/`AppendFile`, relative to the engine's
+    /// working directory. Returns `None` for other variants.
+    pub fn open_file(&self, eng: &Engine, write: bool) -> Result<Option<std::fs::File>, Box<Error>> {
+        let (path, append) = match *self {
+            StdioConfig::File(ref path) => (path, false),
+            StdioConfig::AppendFile(ref path) => (path, true),
+            _ => return Ok(None)
+        };
+        let path = match path.fetch(eng) {
+            Some(v) => v,
+            None => return Err("Invalid redirection target".into())
+        };
+        let path = match eng.cwd {
+            Some(ref cwd) => std::path::Path::new(cwd).join(path),
+            None => std::path::PathBuf::from(path)
+        };
+
+        let f = if !write {
+            std::fs::File::open(&path)
+        } else {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(&path)
+        };
+        match f {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => Err(format!("{}: {}", path.display(), e).into())
+        }
+    }
+}
+
+enum ExecTask {
+    Child(std::process::Child),
+    Builtin(Rc<builtin::Builtin>, Vec<String>, Option<std::io::PipeWriter>), // not run yet
+    Done(i32)
+}
+
+enum PipeSource {
+    Child(std::process::ChildStdout),
+    Buffer(std::io::Cursor<Vec<u8>>),
+    Os(std::io::PipeReader), // written by a builtin
+    Drain(Option<std::thread::JoinHandle<Vec<u8>>>) // a child's output, read by a thread
+}
+
+impl Read for PipeSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let drained = match *self {
+            PipeSource::Drain(ref mut v) => v.take().map(|v| v.join().unwrap_or_else(|_| Vec::new())),
+            _ => None
+        };
+        if let Some(v) = drained {
+            *self = PipeSource::Buffer(std::io::Cursor::new(v));
+        }
+
+        match *self {
+            PipeSource::Child(ref mut v) => v.read(buf),
+            PipeSource::Buffer(ref mut v) => v.read(buf),
+            PipeSource::Os(ref mut v) => v.read(buf),
+            PipeSource::Drain(_) => unreachable!()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -277,6 +1010,17 @@ pub enum ExecError {
     Message(String)
 }
 
+impl ExecError {
+    /// An error running `info`, prefixed with where the command came from
+    /// if that is known.
+    pub fn in_command<E: std::fmt::Display>(info: &ExecInfo, e: E) -> ExecError {
+        match info.span {
+            Some(ref span) => ExecError::Message(format!("{}: {}", span, e)),
+            None => ExecError::Message(e.to_string())
+        }
+    }
+}
+
 impl Error for ExecError {
     fn description(&self) -> &str {
         match self {
@@ -303,63 +1047,399 @@ impl From<Box<Error>> for ExecError {
     }
 }
 
-impl EngineHandle {
-    pub fn impl_ref(&self) -> &EngineHandleImpl {
-        &*self.inner
-    }
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(i32)]
+pub enum LoadErrorKind {
+    Syntax = 1,
+    UnknownOperation = 2,
+    TypeMismatch = 3,
+    InvalidInput = 4,
+    Invalid = 5 // well-formed, but rejected by `Block::validate`
 }
 
-impl EngineHandleImpl {
-    pub fn engine_rc(&self) -> Rc<RefCell<Engine>> {
-        self.inner.clone()
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub kind: LoadErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub path: String, // e.g. `$.ops[2].Exec.command`; empty for scripts
+    pub diagnostics: Vec<validate::Diagnostic> // all problems, for `Invalid`
+}
+
+impl LoadError {
+    pub fn invalid_input(message: &str) -> LoadError {
+        LoadError {
+            kind: LoadErrorKind::InvalidInput,
+            message: message.to_string(),
+            line: 0,
+            column: 0,
+            path: "$".to_string(),
+            diagnostics: Vec::new()
+        }
     }
 
-    pub fn eval_block(&self, blk: &mut Block) -> i32 {
-        if blk.jit_info.is_some() {
-            //println!("JIT HIT");
-            let entry = blk.jit_info.as_ref().unwrap().entry;
-            entry()
+    /// Describes the first of `diagnostics`, which must not be empty, and
+    /// keeps all of them.
+    pub fn invalid(diagnostics: Vec<validate::Diagnostic>) -> LoadError {
+        let (message, path, line, column) = {
+            let first = &diagnostics[0];
+            let (line, column) = first.span.as_ref().map(|v| (v.line, v.column)).unwrap_or((0, 0));
+            (first.message.clone(), first.path.clone(), line, column)
+        };
+        LoadError {
+            kind: LoadErrorKind::Invalid,
+            message: message,
+            line: line,
+            column: column,
+            path: path,
+            diagnostics: diagnostics
+        }
+    }
+
+    /// A syntax error in a script. `line` and `column` are 1-based.
+    pub fn syntax(message: &str, line: usize, column: usize) -> LoadError {
+        LoadError {
+            kind: LoadErrorKind::Syntax,
+            message: message.to_string(),
+            line: line,
+            column: column,
+            path: String::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    pub fn from_json_error(ast: &str, e: &serde_json::Error) -> LoadError {
+        let message = e.to_string();
+        let kind = if e.is_syntax() || e.is_eof() {
+            LoadErrorKind::Syntax
+        } else if message.starts_with("unknown variant") {
+            LoadErrorKind::UnknownOperation
         } else {
-            //println!("JIT MISS");
-            let mut ret: i32 = signals::OK;
+            LoadErrorKind::TypeMismatch
+        };
 
-            for op in blk.ops.iter_mut() {
-                ret = self.eval_op(op);
-                if ret != signals::OK {
-                    break;
-                }
-            }
-            blk.call_count_before_jit += 1;
-            if blk.call_count_before_jit == 3 {
-                blk.build_jit(self).unwrap();
-            }
-            ret
+        LoadError {
+            kind: kind,
+            message: message,
+            line: e.line(),
+            column: e.column(),
+            path: json_path_at(ast, e.line(), e.column()),
+            diagnostics: Vec::new()
         }
     }
+}
 
-    pub fn eval_op(&self, op: &mut Operation) -> i32 {
-        match op {
-            &mut Operation::Exec(ref info) => {
-                self.borrow_mut().handle_exec(info).unwrap();
-                signals::OK
-            },
-            &mut Operation::ParallelExec(ref info) => {
-                self.borrow_mut().handle_parallel_exec(info.as_slice()).unwrap();
-                signals::OK
-            },
-            &mut Operation::BackgroundExec(ref info) => {
-                self.borrow_mut().handle_background_exec(info).unwrap();
-                signals::OK
-            },
-            &mut Operation::IfElse(ref mut if_blk, ref mut else_blk) => {
-                if self.borrow().last_exit_status == 0 {
-                    self.eval_block(else_blk)
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}:{}: {}", self.line, self.column, self.message)
+        } else {
+            write!(f, "{} (at {})", self.message, self.path)
+        }
+    }
+}
+
+enum JsonPathFrame {
+    Object(Option<String>, bool), // (current key, expecting key)
+    Array(usize)
+}
+
+// Scans `src` up to the given 1-based position, tracking which node the
+// position falls in.
+fn json_path_at(src: &str, line: usize, column: usize) -> String {
+    let mut stack: Vec<JsonPathFrame> = Vec::new();
+    let mut cur_line: usize = 1;
+    let mut cur_column: usize = 0;
+    let mut chars = src.chars();
+
+    loop {
+        if cur_line > line || (cur_line == line && cur_column >= column) {
+            break;
+        }
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break
+        };
+        if c == '\n' {
+            cur_line += 1;
+            cur_column = 0;
+        } else {
+            cur_column += 1;
+        }
+
+        match c {
+            '{' => stack.push(JsonPathFrame::Object(None, true)),
+            '[' => stack.push(JsonPathFrame::Array(0)),
+            '}' | ']' => {
+                stack.pop();
+            },
+            ',' => match stack.last_mut() {
+                Some(&mut JsonPathFrame::Array(ref mut i)) => *i += 1,
+                Some(&mut JsonPathFrame::Object(ref mut key, ref mut expecting_key)) => {
+                    *key = None;
+                    *expecting_key = true;
+                },
+                None => {}
+            },
+            ':' => if let Some(&mut JsonPathFrame::Object(_, ref mut expecting_key)) = stack.last_mut() {
+                *expecting_key = false;
+            },
+            '"' => {
+                let mut text = String::new();
+                let mut escaped = false;
+                while let Some(c) = chars.next() {
+                    cur_column += 1;
+                    if escaped {
+                        text.push(c);
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == '"' {
+                        break;
+                    } else {
+                        text.push(c);
+                    }
+                }
+                if let Some(&mut JsonPathFrame::Object(ref mut key, true)) = stack.last_mut() {
+                    *key = Some(text);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let mut path = "$".to_string();
+    for frame in stack.iter() {
+        match *frame {
+            JsonPathFrame::Object(Some(ref key), _) => {
+                path.push('.');
+                path += key.as_str();
+            },
+            JsonPathFrame::Object(None, _) => {},
+            JsonPathFrame::Array(i) => path += format!("[{}]", i).as_str()
+        }
+    }
+    path
+}
+
+impl EngineHandle {
+    pub fn impl_ref(&self) -> &EngineHandleImpl {
+        &*self.inner
+    }
+}
+
+impl EngineHandleImpl {
+    pub fn engine_rc(&self) -> Rc<RefCell<Engine>> {
+        self.inner.clone()
+    }
+
+    pub fn eval_block(&self, blk: &mut Block) -> i32 {
+        // Blocks evaluated by the host get the outermost frame.
+        if self.borrow().script_stack.is_empty() {
+            self.borrow_mut().script_stack.push(ScriptFrame::new("<main>", &*blk));
+            let ret = self.eval_block_in_scope(blk);
+            self.borrow_mut().script_stack.clear();
+            return ret;
+        }
+        self.eval_block_in_scope(blk)
+    }
+
+    fn eval_block_in_scope(&self, blk: &mut Block) -> i32 {
+        if !blk.scoped {
+            return self.eval_block_in_frame(blk);
+        }
+
+        let depth = self.borrow_mut().enter_block_scope();
+        let ret = self.eval_block_in_frame(blk);
+        if let Some(frame) = self.borrow_mut().call_stack.last_mut() {
+            frame.blocks.truncate(depth);
+        }
+        ret
+    }
+
+    fn eval_block_in_frame(&self, blk: &mut Block) -> i32 {
+        // Compiled code can't be traced.
+        let tracing = self.borrow().trace;
+
+        if blk.jit_info.is_none() && !blk.jit_failed && !tracing {
+            let (policy, has_aot_cache) = {
+                let eng = self.borrow();
+                (blk.jit.unwrap_or(eng.jit_policy), eng.aot_cache.is_some())
+            };
+
+            // With an AOT cache, compiling is usually just a cache lookup.
+            let compile = if has_aot_cache {
+                policy != jit::JitPolicy::Off
+            } else {
+                policy.should_compile(blk.call_count_before_jit)
+            };
+            if compile {
+                self.try_build_jit(blk);
+            }
+        }
+
+        if blk.jit_info.is_some() && !tracing {
+            self.borrow_mut().jit_stats.jit_executions += 1;
+            blk.jit_info.as_ref().unwrap().call(self)
+        } else {
+            self.borrow_mut().jit_stats.interpreted_executions += 1;
+            let mut ret: i32 = signals::OK;
+
+            for op in blk.ops.iter_mut() {
+                ret = self.eval_op(op);
+                if ret != signals::OK {
+                    break;
+                }
+            }
+            blk.call_count_before_jit += 1;
+            ret
+        }
+    }
+
+    /// Compiles `blk` and all blocks nested in it ahead of time, filling the
+    /// engine's AOT cache if one is set. Function bodies are copied into
+    /// variables on assignment, so for those only the cache entry is reused.
+    /// Returns false if any compilation failed.
+    pub fn compile_aot(&self, blk: &mut Block) -> bool {
+        let mut ok = true;
+        for child in blk.children_mut() {
+            ok &= self.compile_aot(child);
+        }
+
+        if blk.jit_info.is_none() && !blk.jit_failed {
+            self.try_build_jit(blk);
+        }
+        ok && blk.jit_info.is_some()
+    }
+
+    // Falls back to the interpreter if compilation fails.
+    fn try_build_jit(&self, blk: &mut Block) {
+        let (dump, aot_cache) = {
+            let eng = self.borrow();
+            (eng.jit_ir_dump.clone(), eng.aot_cache.clone())
+        };
+
+        let start = std::time::Instant::now();
+        let ret = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| blk.build_jit(dump.is_some(), aot_cache.as_ref()))) {
+            Ok(v) => v,
+            Err(_) => Err("Panic during JIT compilation".into())
+        };
+        let elapsed = start.elapsed();
+
+        if let Err(e) = ret {
+            blk.jit_failed = true;
+            let mut eng = self.borrow_mut();
+            eng.jit_stats.fallbacks += 1;
+            eng.diagnostics.push(format!("JIT compilation failed: {}", e));
+            return;
+        }
+
+        {
+            let mut eng = self.borrow_mut();
+            eng.jit_stats.blocks_compiled += 1;
+            eng.jit_stats.compile_time_ns += elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+            if blk.jit_info.as_ref().map(|v| v.from_cache).unwrap_or(false) {
+                eng.jit_stats.aot_cache_hits += 1;
+            }
+        }
+
+        if let (Some(dump), Some(ir)) = (dump, blk.jit_info.as_ref().and_then(|v| v.ir.as_ref())) {
+            let eng = self.borrow();
+            match dump {
+                jit::IrDump::Output => eng.output.write_output(ir.as_str()),
+                jit::IrDump::File(ref path) => {
+                    let ret = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut f| f.write_all(ir.as_bytes()));
+                    if let Err(e) = ret {
+                        eng.report_error(&e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn eval_for_each(&self, info: &mut ForEachInfo) -> i32 {
+        let mut words = Vec::new();
+        for word in info.words.iter() {
+            let ret = match word.fetch_words(&*self.borrow()) {
+                Ok(Some(v)) => {
+                    words.extend(v);
+                    continue;
+                },
+                Ok(None) => Err(format!("Undefined value assigned to `{}`", info.name).into()),
+                Err(e) => Err(e)
+            };
+            return self.borrow().check_result(ret);
+        }
+
+        for word in words {
+            let ret = self.borrow_mut().assign(info.scope, &info.name, var::Variable::from_value(var::Value::String(word)));
+            if let Err(e) = ret {
+                return self.borrow().check_result(Err(e));
+            }
+
+            let ret = self.eval_block(&mut info.body);
+            if ret == signals::BREAK {
+                break;
+            } else if ret != signals::OK && ret != signals::CONTINUE {
+                return ret;
+            }
+        }
+        signals::OK
+    }
+
+    pub fn eval_op(&self, op: &mut Operation) -> i32 {
+        {
+            let mut eng = self.borrow_mut();
+            eng.set_current_op(op as *const Operation as *const u8);
+            if eng.trace {
+                eng.trace_op(op);
+            }
+        }
+
+        let ret = self.borrow().check_interrupt();
+        if ret.is_err() {
+            return self.borrow().check_result(ret);
+        }
+
+        match op {
+            &mut Operation::Exec(ref info) => {
+                let ret = self.borrow_mut().handle_exec(info);
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::ParallelExec(ref info) => {
+                let ret = self.borrow_mut().handle_parallel_exec(info.as_slice());
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::BackgroundExec(ref info) => {
+                let ret = self.borrow_mut().handle_background_exec(info);
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::IfElse(ref mut if_blk, ref mut else_blk) => {
+                if self.borrow().last_exit_status == 0 {
+                    self.eval_block(else_blk)
                 } else {
                     self.eval_block(if_blk)
                 }
             },
             &mut Operation::Loop(ref mut blk) => {
                 loop {
+                    // The body may not reach `eval_op` at all when compiled.
+                    let ret = self.borrow().check_interrupt();
+                    if ret.is_err() {
+                        return self.borrow().check_result(ret);
+                    }
+
                     let ret = self.eval_block(blk);
                     if ret == signals::OK {
                         continue;
@@ -373,24 +1453,29 @@ impl EngineHandleImpl {
                 }
                 signals::OK
             },
+            &mut Operation::ForEach(ref mut info) => self.eval_for_each(info),
             &mut Operation::Break => {
                 signals::BREAK
             },
             &mut Operation::AssignGlobal(ref name, ref val) => {
-                let v = val.fetch(&*self.borrow()).unwrap();
-                self.borrow_mut().vars.insert(
-                    name.clone(),
-                    v
-                );
-                signals::OK
+                let ret = self.borrow_mut().assign_source(Scope::Global, name, val);
+                self.borrow().check_result(ret)
             },
             &mut Operation::AssignLocal(ref name, ref val) => {
-                let v = val.fetch(&*self.borrow()).unwrap();
-                self.borrow_mut().call_stack.last_mut().unwrap().vars.insert(
-                    name.clone(),
-                    v
-                );
-                signals::OK
+                let ret = self.borrow_mut().assign_source(Scope::Local, name, val);
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::Declare(scope, ref name) => {
+                let ret = self.borrow_mut().declare(scope, name);
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::Unset(scope, ref name) => {
+                let ret = self.borrow_mut().unset(scope, name);
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::SetAttributes(scope, ref name, ref attributes) => {
+                let ret = self.borrow_mut().set_attributes(scope, name, attributes.clone());
+                self.borrow().check_result(ret)
             },
             &mut Operation::EngineBacktrace => {
                 self.borrow().handle_engine_backtrace();
@@ -406,27 +1491,148 @@ impl EngineHandleImpl {
             },
             &mut Operation::Call(ref target) => {
                 let f = target.fetch(&*self.borrow());
-                if let Some(f) = f {
-                    match f.call(self) {
-                        Ok(_) => signals::OK,
-                        Err(_) => signals::EXCEPTION
-                    }
-                } else {
-                    signals::EXCEPTION
-                }
+                let ret = match f {
+                    Some(f) => f.call(self, target.function_name().as_str()),
+                    None => Err("Call target is undefined".into())
+                };
+                self.borrow().check_result(ret)
+            },
+            &mut Operation::Parallel(ref info) => {
+                let ret = self.borrow_mut().handle_parallel(info);
+                self.borrow().check_result(ret)
             }
         }
     }
 }
 
+// Values aren't `Send`, so like the rest of the engine state, locals and
+// merged globals cross threads in serialized form.
+#[derive(Serialize, Deserialize, Default)]
+struct BranchLocals {
+    vars: HashMap<String, var::Value>,
+    attributes: HashMap<String, Attributes>
+}
+
+struct BranchResult {
+    signal: i32,
+    exit_status: i32,
+    output: String,
+    error: String,
+    globals: String // of `merge_globals`, as `Vec<(String, var::Value)>`
+}
+
+fn run_branch(mut eng: Engine, snapshot: &str, locals: &str, ast: &str, merge_globals: &[String]) -> BranchResult {
+    let sink = output::BufferSink::default();
+    eng.set_output_sink(sink.clone());
+
+    // The branch was checked, if at all, as part of its parent.
+    let ret = eng.restore(snapshot)
+        .and_then(|_| serde_json::from_str::<BranchLocals>(locals).map_err(|e| Box::new(e) as Box<Error>))
+        .and_then(|locals| {
+            // These replace the snapshot's top-level locals, which the
+            // parent may not have been running.
+            let frame = eng.call_stack.last_mut().unwrap();
+            frame.vars = locals.vars.into_iter().map(|(k, v)| (k, var::Variable::from_value(v))).collect();
+            frame.attributes = locals.attributes;
+            frame.globals.clear();
+            Engine::load_block_unchecked(ast).map_err(|e| Box::new(e) as Box<Error>)
+        });
+    let eng: EngineHandle = eng.into();
+
+    let mut signal = match ret {
+        Ok(mut blk) => eng.eval_block(&mut blk),
+        Err(e) => {
+            eng.borrow().report_error(&*e);
+            signals::EXCEPTION
+        }
+    };
+
+    let eng = eng.borrow();
+    let globals: Vec<(String, var::Value)> = merge_globals.iter()
+        .filter_map(|k| eng.vars.get(k).map(|v| (k.clone(), v.impl_ref().value.clone())))
+        .collect();
+    let globals = match serde_json::to_string(&globals) {
+        Ok(v) => v,
+        Err(e) => {
+            eng.report_error(&e);
+            signal = signals::EXCEPTION;
+            "[]".to_string()
+        }
+    };
+    BranchResult {
+        signal: signal,
+        exit_status: eng.last_exit_status,
+        output: sink.output(),
+        error: sink.error(),
+        globals: globals
+    }
+}
+
 impl Engine {
     pub fn new() -> Engine {
-        Engine::default()
+        let mut eng = Engine::default();
+        if let Some(policy) = jit::JitPolicy::from_env() {
+            eng.jit_policy = policy;
+        }
+        eng
     }
 
     // Block must be boxed to prevent move
-    pub fn load_block(ast: &str) -> Result<Box<Block>, Box<Error>> {
-        Ok(Box::new(serde_json::from_str(ast)?))
+    /// Loads a block from its JSON AST and checks it with
+    /// `Block::validate`.
+    pub fn load_block(ast: &str) -> Result<Box<Block>, LoadError> {
+        let blk = Engine::load_block_unchecked(ast)?;
+        let diagnostics = blk.validate();
+        if !diagnostics.is_empty() {
+            return Err(LoadError::invalid(diagnostics));
+        }
+        Ok(blk)
+    }
+
+    /// Like `load_block`, without validation.
+    pub fn load_block_unchecked(ast: &str) -> Result<Box<Block>, LoadError> {
+        match serde_json::from_str(ast) {
+            Ok(v) => Ok(Box::new(v)),
+            Err(e) => Err(LoadError::from_json_error(ast, &e))
+        }
+    }
+
+    /// Compiles a script in the shell language described in `parser`.
+    pub fn parse_script(src: &str) -> Result<Box<Block>, LoadError> {
+        parser::parse(src).map(Box::new)
+    }
+
+    /// Serializes the globals, the locals of top-level code, the
+    /// environment, the working directory and the last exit status.
+    pub fn snapshot(&self) -> Result<String, Box<Error>> {
+        let snapshot = EngineSnapshot {
+            vars: self.vars.iter().map(|(k, v)| (k.clone(), v.impl_ref().value.clone())).collect(),
+            attributes: self.attributes.clone(),
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            last_exit_status: self.last_exit_status,
+            locals: self.call_stack[0].vars.iter().map(|(k, v)| (k.clone(), v.impl_ref().value.clone())).collect(),
+            local_attributes: self.call_stack[0].attributes.clone(),
+            local_globals: self.call_stack[0].globals.clone()
+        };
+        Ok(serde_json::to_string(&snapshot)?)
+    }
+
+    pub fn restore(&mut self, data: &str) -> Result<(), Box<Error>> {
+        let snapshot: EngineSnapshot = serde_json::from_str(data)?;
+
+        self.vars = snapshot.vars.into_iter().map(|(k, v)| (k, var::Variable::from_value(v))).collect();
+        self.attributes = snapshot.attributes;
+        self.env = snapshot.env;
+        self.cwd = snapshot.cwd;
+        self.last_exit_status = snapshot.last_exit_status;
+
+        let root = &mut self.call_stack[0];
+        root.vars = snapshot.locals.into_iter().map(|(k, v)| (k, var::Variable::from_value(v))).collect();
+        root.attributes = snapshot.local_attributes;
+        root.globals = snapshot.local_globals;
+
+        Ok(())
     }
 
     pub fn get_last_exit_status(&self) -> i32 {
@@ -438,8 +1644,8 @@ impl Engine {
     }
 
     pub fn handle_background_exec(&self, info: &ExecInfo) -> Result<(), Box<Error>> {
-        let mut cmd = info.build(self)?;
-        let mut child = cmd.spawn()?;
+        let mut cmd = info.build(self).map_err(|e| ExecError::in_command(info, e))?;
+        let mut child = cmd.spawn().map_err(|e| ExecError::in_command(info, e))?;
 
         std::thread::spawn(move || {
             match child.wait() {
@@ -451,31 +1657,183 @@ impl Engine {
         Ok(())
     }
 
+    pub fn handle_parallel(&mut self, info: &ParallelInfo) -> Result<(), Box<Error>> {
+        use std::sync::Mutex;
+
+        // Engine state isn't `Send`, so branches get it in serialized form.
+        let snapshot = Arc::new(self.snapshot()?);
+        let locals = Arc::new(serde_json::to_string(&self.visible_locals())?);
+        let parent_builtins: Arc<Vec<String>> = Arc::new(self.builtins.keys().chain(self.parent_builtins.iter()).cloned().collect());
+        let merge_globals = Arc::new(info.merge_globals.clone());
+        let mut asts: Vec<String> = Vec::new();
+        for blk in info.blocks.iter() {
+            asts.push(serde_json::to_string(blk)?);
+        }
+        let asts = Arc::new(asts);
+
+        let n_workers = if info.max_concurrency == 0 {
+            asts.len()
+        } else {
+            std::cmp::min(info.max_concurrency, asts.len())
+        };
+
+        let next = Arc::new(Mutex::new(0));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = std::sync::mpsc::channel::<(usize, BranchResult)>();
+
+        let mut workers = Vec::new();
+        for _ in 0..n_workers {
+            let snapshot = snapshot.clone();
+            let locals = locals.clone();
+            let parent_builtins = parent_builtins.clone();
+            let merge_globals = merge_globals.clone();
+            let asts = asts.clone();
+            let next = next.clone();
+            let failed = failed.clone();
+            let tx = tx.clone();
+            let fail_fast = info.fail_fast;
+            let jit_policy = self.jit_policy;
+            let glob_options = self.glob_options;
+            let aot_cache = self.aot_cache.clone();
+            let interrupted = self.interrupted.clone();
+
+            workers.push(std::thread::spawn(move || {
+                loop {
+                    let i = {
+                        let mut next = next.lock().unwrap();
+                        if *next == asts.len() || (fail_fast && failed.load(Ordering::SeqCst)) {
+                            break;
+                        }
+                        *next += 1;
+                        *next - 1
+                    };
+
+                    let mut eng = Engine::new();
+                    eng.jit_policy = jit_policy;
+                    eng.glob_options = glob_options;
+                    eng.aot_cache = aot_cache.clone();
+                    eng.interrupted = interrupted.clone();
+                    eng.parent_builtins = parent_builtins.iter().cloned().collect();
+                    let ret = run_branch(eng, snapshot.as_str(), locals.as_str(), asts[i].as_str(), merge_globals.as_slice());
+                    if ret.signal == signals::EXCEPTION {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    if tx.send((i, ret)).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(tx);
+
+        let mut results: Vec<Option<BranchResult>> = asts.iter().map(|_| None).collect();
+        for (i, ret) in rx {
+            self.output.write_output(ret.output.as_str());
+            self.output.write_error(ret.error.as_str());
+            results[i] = Some(ret);
+        }
+        for w in workers {
+            if w.join().is_err() {
+                return Err("Parallel branch panicked".into());
+            }
+        }
+
+        let mut first_exception: Option<usize> = None;
+        self.branch_statuses.clear();
+        self.last_exit_status = 0;
+
+        for (i, ret) in results.into_iter().enumerate() {
+            let ret = match ret {
+                Some(v) => v,
+                None => {
+                    self.branch_statuses.push(BranchStatus {
+                        signal: None,
+                        exit_status: 0
+                    });
+                    continue;
+                }
+            };
+
+            let globals: Vec<(String, var::Value)> = serde_json::from_str(ret.globals.as_str())?;
+            for (k, v) in globals {
+                self.vars.insert(k, var::Variable::from_value(v));
+            }
+
+            if ret.signal == signals::EXCEPTION && first_exception.is_none() {
+                first_exception = Some(i);
+            }
+            if ret.exit_status != 0 && self.last_exit_status == 0 {
+                self.last_exit_status = ret.exit_status;
+            }
+            self.branch_statuses.push(BranchStatus {
+                signal: Some(ret.signal),
+                exit_status: ret.exit_status
+            });
+        }
+
+        match first_exception {
+            Some(i) => Err(format!("Exception in parallel branch {}", i).into()),
+            None => Ok(())
+        }
+    }
+
     pub fn handle_parallel_exec<T>(&mut self, info: &[T]) -> Result<(), Box<Error>>
         where T: std::borrow::Borrow<ExecInfo>
     {
-        let mut stdout_pipes: HashMap<String, Option<std::process::ChildStdout>> = HashMap::new();
+        let mut stdout_pipes: HashMap<String, Option<PipeSource>> = HashMap::new();
 
-        let mut children = Vec::new();
+        // Expanded up front, since how a stage's output is passed on
+        // depends on whether a builtin reads it.
+        let mut stages = Vec::new();
         for item in info.iter() {
             let item = item.borrow();
-            let mut cmd: Command = item.build(self)?;
-            let mut child = cmd.spawn()?;
+            let args = item.build_args(self).map_err(|e| ExecError::in_command(item, e))?;
+            let b = self.builtins.get(&args[0]).cloned();
+            if b.is_none() && self.parent_builtins.contains(&args[0]) {
+                let e = format!("Builtin `{}` is not available in parallel branches", args[0]);
+                return Err(ExecError::in_command(item, e).into());
+            }
+            stages.push((item, args, b));
+        }
+        let builtin_reads: HashSet<&String> = stages.iter()
+            .filter(|&&(_, _, ref b)| b.is_some())
+            .filter_map(|&(item, _, _)| match item.stdin {
+                StdioConfig::Pipe(ref name) => Some(name),
+                _ => None
+            })
+            .collect();
 
-            if let &StdioConfig::Pipe(ref name) = &item.stdout {
-                stdout_pipes.insert(
-                    name.clone(),
-                    Some(std::mem::replace(&mut child.stdout, None).unwrap())
-                );
+        // Builtins run on this thread once all children are spawned, so
+        // that output going to a child can stream through an OS pipe.
+        // Output going to another builtin is buffered.
+        let mut tasks = Vec::new();
+        let mut streaming = false;
+        for (item, args, b) in stages {
+            if let Some(b) = b {
+                let stream = match item.stdout {
+                    StdioConfig::Pipe(ref name) if !builtin_reads.contains(name) => {
+                        let (reader, writer) = std::io::pipe().map_err(|e| ExecError::in_command(item, e))?;
+                        stdout_pipes.insert(name.clone(), Some(PipeSource::Os(reader)));
+                        streaming = true;
+                        Some(writer)
+                    },
+                    _ => None
+                };
+                tasks.push((ExecTask::Builtin(b, args, stream), item));
+                continue;
             }
 
-            children.push((child, item));
-        }
+            let mut cmd: Command = item.build(self).map_err(|e| ExecError::in_command(item, e))?;
+            let mut child = cmd.spawn().map_err(|e| ExecError::in_command(item, e))?;
 
-        for &mut (ref mut child, info) in children.iter_mut() {
-            if let &StdioConfig::Pipe(ref name) = &info.stdin {
+            // Feed the child before later stages run, since a builtin
+            // further down reads its input to the end right away.
+            if let &StdioConfig::Pipe(ref name) = &item.stdin {
                 let target_stdin = std::mem::replace(&mut child.stdin, None).unwrap();
-                let target_stdout = std::mem::replace(stdout_pipes.get_mut(name).unwrap(), None).unwrap();
+                let target_stdout = match stdout_pipes.get_mut(name).and_then(|v| std::mem::replace(v, None)) {
+                    Some(v) => v,
+                    None => return Err(ExecError::in_command(item, "Pipe consumed but never produced").into())
+                };
 
                 std::thread::spawn(move || {
                     let mut writer = std::io::BufWriter::new(target_stdin);
@@ -490,26 +1848,425 @@ impl Engine {
                     }
                 });
             }
+
+            if let &StdioConfig::Pipe(ref name) = &item.stdout {
+                let mut stdout = std::mem::replace(&mut child.stdout, None).unwrap();
+                let source = if streaming && builtin_reads.contains(name) {
+                    // A builtin streaming into an earlier stage may only
+                    // finish once this output is read, but the builtin
+                    // reading it runs after that one.
+                    PipeSource::Drain(Some(std::thread::spawn(move || {
+                        let mut buf = Vec::new();
+                        let _ = stdout.read_to_end(&mut buf);
+                        buf
+                    })))
+                } else {
+                    PipeSource::Child(stdout)
+                };
+                stdout_pipes.insert(name.clone(), Some(source));
+            }
+
+            tasks.push((ExecTask::Child(child), item));
+        }
+
+        for task in tasks.iter_mut() {
+            let (b, args, stream) = match std::mem::replace(&mut task.0, ExecTask::Done(0)) {
+                ExecTask::Builtin(b, args, stream) => (b, args, stream),
+                other => {
+                    task.0 = other;
+                    continue;
+                }
+            };
+            let (status, cwd) = self.run_builtin(&*b, task.1, &args, stream, &mut stdout_pipes)
+                .map_err(|e| ExecError::in_command(task.1, e))?;
+            // Pipeline stages are subshells.
+            if info.len() == 1 {
+                self.cwd = cwd;
+            }
+            task.0 = ExecTask::Done(status);
+        }
+
+        for (task, info) in tasks {
+            self.last_exit_status = match task {
+                ExecTask::Child(mut c) => c.wait().map_err(|e| ExecError::in_command(info, e))?.code().unwrap_or(-1),
+                ExecTask::Done(status) => status,
+                ExecTask::Builtin(..) => unreachable!()
+            };
+        }
+
+        Ok(())
+    }
+
+    // `stream` is the write end of the OS pipe for piped output, if the
+    // output isn't buffered.
+    fn run_builtin(
+        &self,
+        b: &builtin::Builtin,
+        info: &ExecInfo,
+        args: &[String],
+        stream: Option<std::io::PipeWriter>,
+        pipes: &mut HashMap<String, Option<PipeSource>>
+    ) -> Result<(i32, Option<String>), Box<Error>> {
+        let env = info.build_env(self);
+        let mut cwd = self.cwd.clone();
+
+        let stdin = std::io::stdin();
+        let mut stdin: Box<Read> = match info.stdin {
+            StdioConfig::Inherit => Box::new(stdin.lock()),
+            StdioConfig::Pipe(ref name) => match pipes.get_mut(name).and_then(|v| std::mem::replace(v, None)) {
+                Some(v) => Box::new(v),
+                None => return Err("Pipe consumed but never produced".into())
+            },
+            _ => Box::new(info.stdin.open_file(self, false)?.unwrap())
+        };
+
+        let status = match info.stdout {
+            StdioConfig::Inherit => {
+                let mut out = output::SinkWriter::new(self.output.clone());
+                b.run(args, env.as_slice(), &mut builtin::BuiltinIo {
+                    stdin: &mut *stdin,
+                    stdout: &mut out,
+                    cwd: &mut cwd
+                })
+            },
+            StdioConfig::Pipe(ref name) => match stream {
+                // Dropped when done, which ends the reader's input.
+                Some(mut writer) => b.run(args, env.as_slice(), &mut builtin::BuiltinIo {
+                    stdin: &mut *stdin,
+                    stdout: &mut writer,
+                    cwd: &mut cwd
+                }),
+                None => {
+                    let mut buf: Vec<u8> = Vec::new();
+                    let status = b.run(args, env.as_slice(), &mut builtin::BuiltinIo {
+                        stdin: &mut *stdin,
+                        stdout: &mut buf,
+                        cwd: &mut cwd
+                    });
+                    pipes.insert(name.clone(), Some(PipeSource::Buffer(std::io::Cursor::new(buf))));
+                    status
+                }
+            },
+            _ => {
+                let mut f = info.stdout.open_file(self, true)?.unwrap();
+                b.run(args, env.as_slice(), &mut builtin::BuiltinIo {
+                    stdin: &mut *stdin,
+                    stdout: &mut f,
+                    cwd: &mut cwd
+                })
+            }
+        };
+        Ok((status, cwd))
+    }
+
+    /// Pushes the call and script frames for a call to the function body
+    /// `blk`. Calls are left with `leave_call`, which also cleans up after
+    /// nested calls that didn't return normally.
+    pub fn enter_call(&mut self, name: &str, blk: &Block) -> CallDepths {
+        let depths = CallDepths {
+            call_stack: self.call_stack.len(),
+            script_stack: self.script_stack.len()
+        };
+        self.call_stack.push(Box::new(FunctionState::new()));
+        self.script_stack.push(ScriptFrame::new(name, blk));
+        depths
+    }
+
+    pub fn leave_call(&mut self, depths: CallDepths) {
+        self.call_stack.truncate(depths.call_stack);
+        self.script_stack.truncate(depths.script_stack);
+    }
+
+    /// Pushes a block scope onto the current frame and returns its depth,
+    /// which the scope is truncated to when the block finishes.
+    pub fn enter_block_scope(&mut self) -> usize {
+        let frame = self.call_stack.last_mut().unwrap();
+        frame.blocks.push(BlockScope::default());
+        frame.blocks.len() - 1
+    }
+
+    pub fn register_builtin<T: builtin::Builtin + 'static>(&mut self, name: &str, b: T) {
+        self.builtins.insert(name.to_string(), Rc::new(b));
+    }
+
+    pub fn unregister_builtin(&mut self, name: &str) -> bool {
+        self.builtins.remove(name).is_some()
+    }
+
+    /// Enables printing each op, as one line of shell-like text, to the
+    /// error stream before it runs. Blocks are interpreted while enabled.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Makes backtraces include the native stack, which shows the
+    /// engine's own and compiled code.
+    pub fn set_native_backtrace(&mut self, enabled: bool) {
+        self.native_backtrace = enabled;
+    }
+
+    /// Records where the current frame is. `at` points anywhere inside
+    /// the op being run.
+    pub fn set_current_op(&mut self, at: *const u8) {
+        if let Some(frame) = self.script_stack.last_mut() {
+            frame.at = at;
+        }
+    }
+
+    /// The variable a local name refers to. The scopes of the current
+    /// frame are searched innermost first: the scoped blocks being run,
+    /// then the frame itself. The first scope that declares the name
+    /// decides; one that declared it global refers to the global variable
+    /// of that name. Names no scope declares also refer to globals.
+    ///
+    /// Callers' frames are never searched, and top-level code runs in the
+    /// engine's root frame.
+    pub fn local(&self, name: &str) -> Option<&var::Variable> {
+        let frame = self.call_stack.last().unwrap();
+        match frame.binding(name) {
+            Some(Binding::Block(i)) => frame.blocks[i].vars.get(name),
+            Some(Binding::Frame) => frame.vars.get(name),
+            Some(Binding::Global) | None => self.vars.get(name)
+        }
+    }
+
+    // Copies of the locals `local` finds, with their attributes.
+    fn visible_locals(&self) -> BranchLocals {
+        let frame = self.call_stack.last().unwrap();
+        let names: HashSet<&String> = frame.vars.keys()
+            .chain(frame.blocks.iter().flat_map(|v| v.vars.keys()))
+            .collect();
+
+        let mut ret = BranchLocals::default();
+        for name in names {
+            match frame.binding(name) {
+                Some(Binding::Global) | None => continue,
+                _ => {}
+            }
+            let (vars, attributes) = self.scope_of(Scope::Local, name);
+            if let Some(v) = vars.get(name) {
+                ret.vars.insert(name.clone(), v.impl_ref().value.clone());
+            }
+            if let Some(v) = attributes.get(name) {
+                ret.attributes.insert(name.clone(), v.clone());
+            }
         }
+        ret
+    }
 
-        for mut child in children {
-            let exit_status = child.0.wait()?;
-            self.last_exit_status = exit_status.code().unwrap_or(-1);
+    // The variables and attributes an assignment to `name` in `scope`
+    // goes to: for locals, the scope `local` finds, or the current frame
+    // if none declares the name.
+    fn scope_of(&self, scope: Scope, name: &str) -> (&HashMap<String, var::Variable>, &HashMap<String, Attributes>) {
+        let frame = self.call_stack.last().unwrap();
+        let binding = match scope {
+            Scope::Global => Binding::Global,
+            Scope::Local => frame.binding(name).unwrap_or(Binding::Frame)
+        };
+        match binding {
+            Binding::Block(i) => (&frame.blocks[i].vars, &frame.blocks[i].attributes),
+            Binding::Frame => (&frame.vars, &frame.attributes),
+            Binding::Global => (&self.vars, &self.attributes)
         }
+    }
 
+    fn scope_of_mut(&mut self, scope: Scope, name: &str) -> (&mut HashMap<String, var::Variable>, &mut HashMap<String, Attributes>) {
+        let frame = self.call_stack.last_mut().unwrap();
+        let binding = match scope {
+            Scope::Global => Binding::Global,
+            Scope::Local => frame.binding(name).unwrap_or(Binding::Frame)
+        };
+        match binding {
+            Binding::Block(i) => {
+                let block = &mut frame.blocks[i];
+                (&mut block.vars, &mut block.attributes)
+            },
+            Binding::Frame => (&mut frame.vars, &mut frame.attributes),
+            Binding::Global => (&mut self.vars, &mut self.attributes)
+        }
+    }
+
+    /// Whether a value of type `ty`, `None` for null, may be assigned to
+    /// `name` in `scope`.
+    pub fn check_assign(&self, scope: Scope, name: &str, ty: Option<var::Type>) -> Result<(), Box<Error>> {
+        match self.scope_of(scope, name).1.get(name) {
+            Some(attributes) => attributes.check_assign(name, ty),
+            None => Ok(())
+        }
+    }
+
+    /// Assigns `v` to `name` in `scope`, unless its attributes forbid it.
+    /// A local name no scope declares becomes a local of the current frame.
+    pub fn assign(&mut self, scope: Scope, name: &str, v: var::Variable) -> Result<(), Box<Error>> {
+        let ty = v.impl_ref().value.type_of();
+        self.check_assign(scope, name, ty)?;
+        self.assign_unchecked(scope, name, v);
+        Ok(())
+    }
+
+    /// `assign` with the value `val` yields, which must be defined.
+    pub fn assign_source(&mut self, scope: Scope, name: &str, val: &ValueSource) -> Result<(), Box<Error>> {
+        match val.fetch(self) {
+            Some(v) => self.assign(scope, name, v),
+            None => Err(format!("Undefined value assigned to `{}`", name).into())
+        }
+    }
+
+    /// `assign` for callers that already ran `check_assign`.
+    pub fn assign_unchecked(&mut self, scope: Scope, name: &str, v: var::Variable) {
+        self.scope_of_mut(scope, name).0.insert(name.to_string(), v);
+    }
+
+    /// Removes `name` and its attributes from the scope `assign` would
+    /// write to. An unset local is no longer declared there, so the name
+    /// refers to outer scopes again. Readonly variables can't be unset.
+    pub fn unset(&mut self, scope: Scope, name: &str) -> Result<(), Box<Error>> {
+        let (vars, attributes) = self.scope_of_mut(scope, name);
+        if attributes.get(name).map(|v| v.readonly).unwrap_or(false) {
+            return Err(readonly_error(name));
+        }
+        vars.remove(name);
+        attributes.remove(name);
+        Ok(())
+    }
+
+    /// Replaces the attributes of `name` in the scope `assign` would write
+    /// to. The variable doesn't need to exist, but if it does its value
+    /// must have the type required. Readonly variables keep theirs.
+    pub fn set_attributes(&mut self, scope: Scope, name: &str, attributes: Attributes) -> Result<(), Box<Error>> {
+        let (vars, current) = self.scope_of_mut(scope, name);
+        if current.get(name).map(|v| v.readonly).unwrap_or(false) {
+            return Err(readonly_error(name));
+        }
+        if let (Some(expected), Some(v)) = (attributes.value_type, vars.get(name)) {
+            if v.impl_ref().value.type_of() != Some(expected) {
+                return Err(format!("Variable `{}` isn't of type {}", name, expected).into());
+            }
+        }
+        current.insert(name.to_string(), attributes);
         Ok(())
     }
 
+    /// Declares `name` in the innermost scope of the current frame. A local
+    /// declaration creates a null variable there, shadowing outer scopes;
+    /// a global one makes the name refer to the global variable. Either
+    /// drops attributes the scope had for the name, so readonly variables
+    /// can't be redeclared.
+    pub fn declare(&mut self, scope: Scope, name: &str) -> Result<(), Box<Error>> {
+        let frame = self.call_stack.last_mut().unwrap();
+        let (vars, attributes, globals) = match frame.blocks.last_mut() {
+            Some(v) => (&mut v.vars, &mut v.attributes, &mut v.globals),
+            None => (&mut frame.vars, &mut frame.attributes, &mut frame.globals)
+        };
+        if attributes.get(name).map(|v| v.readonly).unwrap_or(false) {
+            return Err(readonly_error(name));
+        }
+        attributes.remove(name);
+        match scope {
+            Scope::Local => {
+                globals.remove(name);
+                vars.insert(name.to_string(), var::Variable::from_value(var::Value::Null));
+            },
+            Scope::Global => {
+                vars.remove(name);
+                globals.insert(name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The script call stack, innermost frame first, one `  at` line per
+    /// frame. Includes the native stack if enabled.
+    pub fn script_backtrace(&self) -> String {
+        let mut ret = String::new();
+        for frame in self.script_stack.iter().rev() {
+            ret += format!("  at {}", frame.function).as_str();
+            if let Some((path, span)) = frame.current_op() {
+                let path: Vec<String> = path.iter().map(|v| v.to_string()).collect();
+                ret += format!(", op {}", path.join(".")).as_str();
+                if let Some(span) = span {
+                    ret += format!(" ({})", span).as_str();
+                }
+            }
+            ret.push('\n');
+        }
+        if self.native_backtrace {
+            ret += format!("Native backtrace:\n{:?}\n", backtrace::Backtrace::new()).as_str();
+        }
+        ret
+    }
+
+    fn trace_op(&self, op: &Operation) {
+        let text = op.to_string();
+        let text = text.lines().next().unwrap_or("");
+        match self.script_stack.last().and_then(|v| v.op_span()) {
+            Some(span) => self.output.write_error(format!("+ {}: {}\n", span, text).as_str()),
+            None => self.output.write_error(format!("+ {}\n", text).as_str())
+        }
+    }
+
+    pub fn set_jit_policy(&mut self, policy: jit::JitPolicy) {
+        self.jit_policy = policy;
+    }
+
+    pub fn set_glob_options(&mut self, options: glob::Options) {
+        self.glob_options = options;
+    }
+
+    /// Caches optimized code for compiled blocks in `dir`. Blocks are then
+    /// compiled on first evaluation unless the JIT is off.
+    pub fn set_aot_cache(&mut self, cache: Option<jit::AotCache>) {
+        self.aot_cache = cache;
+    }
+
+    pub fn set_jit_ir_dump(&mut self, dump: Option<jit::IrDump>) {
+        self.jit_ir_dump = dump;
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<String> {
+        std::mem::replace(&mut self.diagnostics, Vec::new())
+    }
+
+    pub fn set_output_sink<T: output::OutputSink + 'static>(&mut self, sink: T) {
+        self.output = Rc::new(sink);
+    }
+
+    pub fn report_error(&self, e: &Error) {
+        self.output.write_error(format!("{}\n", e).as_str());
+    }
+
+    pub fn check_interrupt(&self) -> Result<(), Box<Error>> {
+        if self.interrupted.load(Ordering::SeqCst) {
+            Err("Interrupted".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reports the error, if any, with a backtrace and maps the result to
+    /// a control status.
+    pub fn check_result(&self, ret: Result<(), Box<Error>>) -> i32 {
+        match ret {
+            Ok(_) => signals::OK,
+            Err(e) => {
+                if !e.is::<PropagatedException>() {
+                    self.report_error(&*e);
+                    self.output.write_error(self.script_backtrace().as_str());
+                }
+                signals::EXCEPTION
+            }
+        }
+    }
+
     pub fn handle_print(&self, src: &StringSource) {
-        println!("{}", match src.fetch(self) {
+        self.output.write_output(format!("{}\n", match src.fetch(self) {
             Some(v) => v,
             None => "(undefined)".to_string()
-        });
+        }).as_str());
     }
 
     pub fn handle_engine_backtrace(&self) {
-        let bt = backtrace::Backtrace::new();
-        println!("{:?}", bt);
+        self.output.write_output(self.script_backtrace().as_str());
     }
 
     pub fn handle_check_eq(&mut self, left: &ValueSource, right: &ValueSource) {
@@ -1,4 +1,18 @@
 use engine;
+use span;
+use var;
+use glob;
+use builtin;
+use output;
+use signals;
+use jit;
+use owned;
+use parser;
+use repl;
+use serde_json;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{Read, Write};
 
 #[test]
 fn test_engine_exec() {
@@ -202,3 +216,1797 @@ fn test_engine_exec() {
         }
     }
 }
+
+#[test]
+fn test_engine_snapshot() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "AssignGlobal": [
+                "var1",
+                {
+                    "Plain": {
+                        "Integer": 42
+                    }
+                }
+            ]
+        },
+        {
+            "AssignGlobal": [
+                "f",
+                {
+                    "Plain": {
+                        "Function": {
+                            "ops": [
+                                {
+                                    "Print": {
+                                        "Plain": "In function!"
+                                    }
+                                }
+                            ]
+                        }
+                    }
+                }
+            ]
+        }
+    ]
+}
+    "#;
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+
+    // Top-level locals live in the root frame.
+    let mut locals = engine::Engine::load_block(r#"{ "ops": [
+        { "AssignLocal": [ "top", { "Plain": { "String": "local" } } ] },
+        { "SetAttributes": [ "Local", "top", { "readonly": true } ] },
+        { "Declare": [ "Global", "var1" ] }
+    ] }"#).unwrap();
+    assert_eq!(eng.eval_block(&mut locals), 0);
+
+    eng.borrow_mut().env.insert("ONESHELL_TEST".to_string(), "1".to_string());
+    eng.borrow_mut().cwd = Some("/".to_string());
+    eng.borrow_mut().last_exit_status = 7;
+
+    let snapshot = eng.borrow().snapshot().unwrap();
+
+    let restored: engine::EngineHandle = engine::Engine::new().into();
+    restored.borrow_mut().restore(snapshot.as_str()).unwrap();
+
+    {
+        let restored = restored.borrow();
+        assert!(restored.vars.get("var1").unwrap().impl_ref().value == var::Value::Integer(42));
+        assert!(restored.vars.get("f").is_some());
+        assert_eq!(restored.env.get("ONESHELL_TEST").unwrap(), "1");
+        assert_eq!(restored.cwd, Some("/".to_string()));
+        assert_eq!(restored.last_exit_status, 7);
+
+        assert_eq!(restored.local("top").unwrap().to_string(), "local");
+        assert!(restored.call_stack[0].attributes["top"].readonly);
+        assert!(restored.call_stack[0].globals.contains("var1"));
+    }
+    let mut assign = engine::Engine::load_block(r#"{ "ops": [
+        { "AssignLocal": [ "top", { "Plain": { "String": "changed" } } ] }
+    ] }"#).unwrap();
+    restored.borrow_mut().set_output_sink(output::BufferSink::default());
+    assert_eq!(restored.eval_block(&mut assign), signals::EXCEPTION);
+
+    // Snapshots from before top-level locals still load.
+    let mut old = engine::Engine::new();
+    old.restore(r#"{ "vars": { "a": { "Integer": 1 } }, "env": {}, "cwd": null, "last_exit_status": 0 }"#).unwrap();
+    assert_eq!(old.vars["a"].to_string(), "1");
+    assert!(old.call_stack[0].vars.is_empty());
+}
+
+struct CaptureBuiltin {
+    output: Rc<RefCell<String>>
+}
+
+impl builtin::Builtin for CaptureBuiltin {
+    fn run(&self, args: &[String], _: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        let mut input = String::new();
+        io.stdin.read_to_string(&mut input).unwrap();
+        *self.output.borrow_mut() = format!("{}:{}", args[1], input);
+        3
+    }
+}
+
+#[test]
+fn test_engine_builtin() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "ParallelExec": [
+                {
+                    "command": [
+                        {
+                            "Plain": "echo"
+                        },
+                        {
+                            "Plain": "hello"
+                        }
+                    ],
+                    "env": [],
+                    "stdin": "Inherit",
+                    "stdout": {
+                        "Pipe": "p1"
+                    }
+                },
+                {
+                    "command": [
+                        {
+                            "Plain": "capture"
+                        },
+                        {
+                            "Plain": "arg"
+                        }
+                    ],
+                    "env": [],
+                    "stdin": {
+                        "Pipe": "p1"
+                    },
+                    "stdout": "Inherit"
+                }
+            ]
+        }
+    ]
+}
+    "#;
+
+    let output = Rc::new(RefCell::new(String::new()));
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().register_builtin("capture", CaptureBuiltin {
+        output: output.clone()
+    });
+
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert_eq!(eng.borrow().last_exit_status, 3);
+    assert_eq!(output.borrow().as_str(), "arg:hello\n");
+}
+
+struct UpperBuiltin;
+
+impl builtin::Builtin for UpperBuiltin {
+    fn run(&self, _: &[String], _: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        let mut input = String::new();
+        io.stdin.read_to_string(&mut input).unwrap();
+        io.stdout.write_all(input.to_uppercase().as_bytes()).unwrap();
+        0
+    }
+}
+
+#[test]
+fn test_engine_builtin_pipeline() {
+    let sink = output::BufferSink::default();
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().register_builtin("upper", UpperBuiltin);
+
+    // Later stages only get input if the middle ones are fed before the
+    // builtin at the end runs.
+    let mut blk = engine::Engine::parse_script("printf 'a\\nb\\nab\\n' | grep a | tr a x | upper").unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert_eq!(sink.output().as_str(), "X\nXB\n");
+}
+
+// Writes `y` lines until its output goes away.
+struct YesBuiltin;
+
+impl builtin::Builtin for YesBuiltin {
+    fn run(&self, _: &[String], _: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        while io.stdout.write_all(b"y\n").is_ok() {}
+        0
+    }
+}
+
+// Records what the sink had received by the time its first line was
+// written.
+struct ProgressBuiltin {
+    sink: output::BufferSink,
+    seen: Rc<RefCell<String>>
+}
+
+impl builtin::Builtin for ProgressBuiltin {
+    fn run(&self, _: &[String], _: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        io.stdout.write_all("first\nsec".as_bytes()).unwrap();
+        *self.seen.borrow_mut() = self.sink.output();
+        io.stdout.write_all("ond".as_bytes()).unwrap();
+        0
+    }
+}
+
+#[test]
+fn test_engine_builtin_streaming() {
+    let sink = output::BufferSink::default();
+    let seen = Rc::new(RefCell::new(String::new()));
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().register_builtin("yes", YesBuiltin);
+    eng.borrow_mut().register_builtin("upper", UpperBuiltin);
+    eng.borrow_mut().register_builtin("progress", ProgressBuiltin {
+        sink: sink.clone(),
+        seen: seen.clone()
+    });
+
+    let mut blk = engine::Engine::parse_script("progress").unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert_eq!(seen.borrow().as_str(), "first\n");
+    assert_eq!(sink.output().as_str(), "first\nsecond");
+
+    // Only end once `head` stops reading. `upper` runs after `yes`, so
+    // the output of `head` is read in the meantime.
+    sink.clear();
+    let mut blk = engine::Engine::parse_script("yes | head -n 2 | upper").unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert_eq!(sink.output().as_str(), "Y\nY\n");
+}
+
+#[test]
+fn test_engine_output_sink() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "AssignGlobal": [
+                "test_function",
+                {
+                    "Plain": {
+                        "Function": {
+                            "ops": [
+                                {
+                                    "Print": {
+                                        "Plain": "In function!"
+                                    }
+                                }
+                            ]
+                        }
+                    }
+                }
+            ]
+        },
+        {
+            "Call": {
+                "GlobalVariable": "test_function"
+            }
+        },
+        {
+            "Print": {
+                "Join": [
+                    {
+                        "Plain": "Value is "
+                    },
+                    {
+                        "Value": {
+                            "Plain": {
+                                "Integer": 42
+                            }
+                        }
+                    }
+                ]
+            }
+        },
+        {
+            "Call": {
+                "GlobalVariable": "missing_function"
+            }
+        },
+        {
+            "Print": {
+                "Plain": "Unreachable"
+            }
+        }
+    ]
+}
+    "#;
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+
+    assert_eq!(sink.output().as_str(), "In function!\nValue is 42\n");
+    assert_eq!(sink.error().as_str(), "Call target is undefined\n  at <main>, op 3\n");
+}
+
+#[test]
+fn test_engine_load_error() {
+    let err = engine::Engine::load_block("{\"ops\": [\"Break\", {\"Foo\": 1}]}").err().unwrap();
+    assert_eq!(err.kind, engine::LoadErrorKind::UnknownOperation);
+    assert_eq!(err.path, "$.ops[1].Foo");
+
+    let err = engine::Engine::load_block("{\"ops\": [{\"Print\": {\"Plain\": 1}}]}").err().unwrap();
+    assert_eq!(err.kind, engine::LoadErrorKind::TypeMismatch);
+    assert_eq!(err.path, "$.ops[0].Print.Plain");
+
+    let err = engine::Engine::load_block("{\"ops\": [{\"Break\": null, \"span\": {\"line\": \"x\"}}]}").err().unwrap();
+    assert_eq!(err.kind, engine::LoadErrorKind::TypeMismatch);
+    assert_eq!(err.path, "$.ops[0].span.line");
+
+    let err = engine::Engine::load_block("{\"ops\": [\n\"Break\",\n]}").err().unwrap();
+    assert_eq!(err.kind, engine::LoadErrorKind::Syntax);
+    assert_eq!(err.line, 3);
+}
+
+#[test]
+fn test_engine_validate() {
+    let ast = r#"
+{
+    "ops": [
+        { "Exec": { "command": [], "env": [], "stdin": "Inherit", "stdout": "Inherit" }, "span": { "line": 4 } },
+        "Break",
+        { "Loop": { "ops": [ { "IfElse": [ { "ops": [ "Break" ] }, { "ops": [] } ] } ] } },
+        { "ParallelExec": [
+            { "command": [ { "Plain": "a" } ], "env": [], "stdin": { "Pipe": "p0" }, "stdout": { "Pipe": "p1" } },
+            { "command": [ { "Plain": "b" } ], "env": [], "stdin": "Inherit", "stdout": "Inherit" }
+        ] },
+        { "AssignLocal": [ "x", { "String": { "Join": [ { "Plain": "-" }, { "LocalVariable": "y" } ] } } ] },
+        { "AssignGlobal": [ "f", { "Plain": { "Function": { "ops": [
+            { "AssignLocal": [ "x", { "Plain": { "Integer": 1 } } ] },
+            { "Print": { "LocalVariable": "x" } },
+            "Break"
+        ] } } } ] },
+        { "Parallel": { "blocks": [ { "ops": [ { "Print": { "LocalVariable": "x" } }, "Break" ] } ] } }
+    ]
+}
+    "#;
+
+    let err = engine::Engine::load_block(ast).err().unwrap();
+    assert_eq!(err.kind, engine::LoadErrorKind::Invalid);
+    assert_eq!((err.line, err.column, err.path.as_str()), (4, 0, "$.ops[0].Exec.command"));
+
+    let diagnostics: Vec<String> = err.diagnostics.iter().map(|v| v.to_string()).collect();
+    assert_eq!(diagnostics, vec![
+        "4: Command is empty (at $.ops[0].Exec.command)",
+        "Break outside of a loop (at $.ops[1].Break)",
+        "Pipe `p0` is consumed but never produced (at $.ops[3].ParallelExec[0].stdin.Pipe)",
+        "Pipe `p1` is produced but never consumed (at $.ops[3].ParallelExec[0].stdout.Pipe)",
+        "Break outside of a loop (at $.ops[5].AssignGlobal[1].Plain.Function.ops[2].Break)",
+        "Break outside of a loop (at $.ops[6].Parallel.blocks[0].ops[1].Break)"
+    ]);
+
+    let blk = engine::Engine::load_block_unchecked(ast).unwrap();
+    assert_eq!(blk.validate(), err.diagnostics);
+
+    // The script parser only produces valid blocks.
+    let blk = engine::Engine::parse_script("f() {\n    local x=1\n    while true; do break; done\n}\na | b | c\nf\n").unwrap();
+    assert!(blk.validate().is_empty());
+}
+
+#[test]
+fn test_engine_scopes() {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/scopes.json");
+    let mut ast = String::new();
+    std::fs::File::open(path).unwrap().read_to_string(&mut ast).unwrap();
+
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+
+        let mut blk = engine::Engine::load_block(ast.as_str()).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), 0);
+        assert_eq!(sink.output().as_str(), concat!(
+            "global x\n", "top y\n", "inner y\n",
+            "top y\n", "x set through a declaration\n", "6\n", "(undefined)\n",
+            "(undefined)\n", "f's y\n", "top y\n",
+            "(undefined)\n", "(undefined)\n", "(undefined)\n", "3\n"
+        ), "{:?}", policy);
+
+        // Top-level locals live in the root frame, and block scopes are gone.
+        let eng = eng.borrow();
+        assert_eq!(eng.call_stack.len(), 1);
+        assert!(eng.call_stack[0].blocks.is_empty());
+        let mut locals: Vec<&String> = eng.call_stack[0].vars.keys().collect();
+        locals.sort();
+        assert_eq!(locals, vec!["n", "y", "z"]);
+        assert!(eng.vars.get("y").is_none());
+    }
+}
+
+#[test]
+fn test_engine_attributes() {
+    let blocks = vec![
+        r#"{"ops": [
+            { "AssignGlobal": [ "x", { "Plain": { "String": "global" } } ] },
+            { "AssignLocal": [ "x", { "Plain": { "String": "top" } } ] },
+            { "Unset": [ "Local", "x" ] },
+            { "Print": { "LocalVariable": "x" } },
+            { "SetAttributes": [ "Global", "g", { "readonly": true } ] }
+        ]}"#,
+        r#"{"ops": [
+            { "SetAttributes": [ "Local", "y", { "readonly": true } ] },
+            { "AssignLocal": [ "y", { "Plain": { "Integer": 1 } } ] }
+        ]}"#,
+        r#"{"ops": [ { "Declare": [ "Local", "y" ] } ]}"#,
+        r#"{"ops": [
+            { "AssignGlobal": [ "n", { "Plain": { "String": "str" } } ] },
+            { "SetAttributes": [ "Global", "n", { "value_type": "Integer" } ] }
+        ]}"#,
+        r#"{"ops": [
+            { "IfElse": [ { "ops": [] }, { "scoped": true, "ops": [
+                { "Declare": [ "Local", "k" ] },
+                { "AssignLocal": [ "k", { "Plain": { "Integer": 1 } } ] },
+                { "SetAttributes": [ "Local", "k", { "value_type": "Integer" } ] },
+                { "AssignLocal": [ "k", { "Plain": { "Integer": 2 } } ] },
+                { "AssignLocal": [ "k", { "Plain": { "String": "s" } } ] }
+            ] } ] }
+        ]}"#
+    ];
+
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+
+        let statuses: Vec<i32> = blocks.iter()
+            .map(|ast| eng.eval_block(&mut engine::Engine::load_block(ast).unwrap()))
+            .collect();
+        assert_eq!(statuses, vec![0, 3, 3, 3, 3], "{:?}", policy);
+        assert_eq!(sink.output().as_str(), "global\n");
+        assert_eq!(sink.error().as_str(), concat!(
+            "Variable `y` is readonly\n", "  at <main>, op 1\n",
+            "Variable `y` is readonly\n", "  at <main>, op 0\n",
+            "Variable `n` isn't of type integer\n", "  at <main>, op 1\n",
+            "Variable `k` can only hold integer values\n", "  at <main>, op 0.4\n"
+        ), "{:?}", policy);
+
+        // Attributes of the block scope went with it.
+        let snapshot = {
+            let eng = eng.borrow();
+            assert!(eng.call_stack[0].blocks.is_empty());
+            let mut names: Vec<&String> = eng.call_stack[0].attributes.keys().collect();
+            names.sort();
+            assert_eq!(names, vec!["y"]);
+            eng.snapshot().unwrap()
+        };
+
+        // Global attributes are part of snapshots.
+        let mut restored = engine::Engine::new();
+        restored.restore(snapshot.as_str()).unwrap();
+        assert_eq!(restored.attributes.get("g"), Some(&engine::Attributes {
+            readonly: true,
+            value_type: None
+        }));
+        assert!(restored.assign(engine::Scope::Global, "g", var::Variable::from_value(var::Value::Null)).is_err());
+        assert!(restored.unset(engine::Scope::Global, "g").is_err());
+
+        // Hosts go through the same checks.
+        let mut owned = owned::OwnedEngine::new();
+        owned.restore(snapshot.as_str()).unwrap();
+        assert!(owned.set_global("g", &var::Value::Integer(1)).is_err());
+        assert!(owned.unset_global("g").is_err());
+        assert_eq!(owned.unset_global("missing").unwrap(), false);
+    }
+}
+
+#[test]
+fn test_engine_jit_across_clones() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "AssignGlobal": [
+                "result",
+                {
+                    "GlobalVariable": "input"
+                }
+            ]
+        },
+        {
+            "CheckEq": [
+                {
+                    "GlobalVariable": "input"
+                },
+                {
+                    "Plain": {
+                        "Integer": 2
+                    }
+                }
+            ]
+        },
+        {
+            "IfElse": [
+                {
+                    "ops": [
+                        {
+                            "AssignGlobal": [
+                                "branch",
+                                {
+                                    "Plain": {
+                                        "String": "if"
+                                    }
+                                }
+                            ]
+                        }
+                    ]
+                },
+                {
+                    "ops": [
+                        {
+                            "AssignGlobal": [
+                                "branch",
+                                {
+                                    "Plain": {
+                                        "String": "else"
+                                    }
+                                }
+                            ]
+                        }
+                    ]
+                }
+            ]
+        }
+    ]
+}
+    "#;
+
+    let eng1: engine::EngineHandle = engine::Engine::new().into();
+    eng1.borrow_mut().set_jit_policy(jit::JitPolicy::AfterCalls(3));
+    eng1.borrow_mut().vars.insert("input".to_string(), var::Variable::from_value(var::Value::Integer(1)));
+
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    for _ in 0..5 {
+        assert_eq!(eng1.eval_block(&mut blk), 0);
+    }
+    assert!(blk.jit_info.is_some());
+
+    let eng2: engine::EngineHandle = eng1.borrow().clone().into();
+    eng2.borrow_mut().vars.insert("input".to_string(), var::Variable::from_value(var::Value::Integer(2)));
+    assert_eq!(eng2.eval_block(&mut blk), 0);
+
+    let get = |eng: &engine::EngineHandle, name: &str| eng.borrow().vars.get(name).unwrap().to_string();
+    assert_eq!(get(&eng1, "result"), "1");
+    assert_eq!(get(&eng1, "branch"), "else");
+    assert_eq!(get(&eng2, "result"), "2");
+    assert_eq!(get(&eng2, "branch"), "if");
+}
+
+#[test]
+fn test_engine_jit_policy() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "AssignGlobal": [
+                "a",
+                {
+                    "Plain": {
+                        "Integer": 1
+                    }
+                }
+            ]
+        }
+    ]
+}
+    "#;
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+
+    eng.borrow_mut().set_jit_policy(jit::JitPolicy::Off);
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    for _ in 0..5 {
+        assert_eq!(eng.eval_block(&mut blk), 0);
+    }
+    assert!(blk.jit_info.is_none());
+
+    eng.borrow_mut().set_jit_policy(jit::JitPolicy::Eager);
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert!(blk.jit_info.is_some());
+
+    // Per-block hints override the engine policy.
+    let hinted = r#"{ "ops": [], "jit": "Off" }"#;
+    let mut blk = engine::Engine::load_block(hinted).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    assert!(blk.jit_info.is_none());
+
+    assert!(eng.borrow_mut().take_diagnostics().is_empty());
+    assert_eq!(jit::JitPolicy::parse("10"), Some(jit::JitPolicy::AfterCalls(10)));
+    assert_eq!(jit::JitPolicy::parse("bogus"), None);
+}
+
+#[test]
+fn test_engine_jit_call_site_cache() {
+    let define = |name: &str| format!(r#"
+{{
+    "ops": [
+        {{
+            "AssignGlobal": [
+                "f",
+                {{
+                    "Plain": {{
+                        "Function": {{
+                            "ops": [
+                                {{
+                                    "Print": {{
+                                        "Plain": "{}"
+                                    }}
+                                }}
+                            ]
+                        }}
+                    }}
+                }}
+            ]
+        }}
+    ]
+}}
+    "#, name);
+    let call = r#"{ "ops": [ { "Call": { "GlobalVariable": "f" } } ] }"#;
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_jit_policy(jit::JitPolicy::Eager);
+    eng.borrow_mut().set_output_sink(sink.clone());
+
+    let mut call_blk = engine::Engine::load_block(call).unwrap();
+
+    assert_eq!(eng.eval_block(&mut engine::Engine::load_block(define("a").as_str()).unwrap()), 0);
+    for _ in 0..3 {
+        assert_eq!(eng.eval_block(&mut call_blk), 0);
+    }
+
+    // Reassignment must invalidate the cached callee.
+    assert_eq!(eng.eval_block(&mut engine::Engine::load_block(define("b").as_str()).unwrap()), 0);
+    for _ in 0..2 {
+        assert_eq!(eng.eval_block(&mut call_blk), 0);
+    }
+
+    assert_eq!(sink.output().as_str(), "a\na\na\nb\nb\n");
+    assert_eq!(eng.borrow().call_stack.len(), 1);
+
+    // Cached calls to scoped bodies run in a block scope, as uncached ones do.
+    let define_scoped = r#"{ "ops": [ { "AssignGlobal": [ "f", { "Plain": { "Function": { "scoped": true, "ops": [
+        { "Declare": [ "Local", "v" ] },
+        { "AssignLocal": [ "v", { "Plain": { "String": "c" } } ] },
+        { "AssignLocal": [ "w", { "LocalVariable": "v" } ] },
+        { "Print": { "LocalVariable": "w" } }
+    ] } } } ] } ] }"#;
+    assert_eq!(eng.eval_block(&mut engine::Engine::load_block(define_scoped).unwrap()), 0);
+    for _ in 0..3 {
+        assert_eq!(eng.eval_block(&mut call_blk), 0);
+    }
+    assert_eq!(sink.output().as_str(), "a\na\na\nb\nb\nc\nc\nc\n");
+
+    // Traced calls take the slow path, which interprets the callee.
+    eng.borrow_mut().set_trace(true);
+    assert_eq!(eng.eval_block(&mut call_blk), 0);
+    assert!(sink.error().contains("+ local v=c\n"), "{}", sink.error());
+
+    let eng = eng.borrow();
+    assert_eq!(eng.call_stack.len(), 1);
+    assert!(eng.call_stack[0].blocks.is_empty());
+    assert!(eng.vars.get("v").is_none() && eng.vars.get("w").is_none());
+}
+
+#[test]
+fn test_engine_jit_native_arith() {
+    let ast = r#"
+{
+    "ops": [
+        { "AssignGlobal": [ "a", { "Plain": { "Integer": 2 } } ] },
+        { "AssignGlobal": [ "b", { "Arith": [ "Mul", { "GlobalVariable": "a" }, { "Plain": { "Integer": 21 } } ] } ] },
+        { "CheckEq": [ { "GlobalVariable": "b" }, { "Plain": { "Integer": 42 } } ] },
+        { "AssignGlobal": [ "eq", "LastExitStatus" ] },
+        { "AssignGlobal": [ "f", { "Arith": [ "Div", { "Plain": { "Float": 3.0 } }, { "Plain": { "Float": 2.0 } } ] } ] },
+        { "AssignGlobal": [ "mixed", { "Arith": [ "Add", { "GlobalVariable": "b" }, { "GlobalVariable": "f" } ] } ] },
+        { "AssignGlobal": [ "i", { "Plain": { "Integer": 0 } } ] },
+        {
+            "Loop": {
+                "ops": [
+                    { "AssignGlobal": [ "i", { "Arith": [ "Add", { "GlobalVariable": "i" }, { "Plain": { "Integer": 1 } } ] } ] },
+                    { "CheckEq": [ { "GlobalVariable": "i" }, { "Plain": { "Integer": 10 } } ] },
+                    { "IfElse": [ { "ops": [ "Break" ] }, { "ops": [] } ] }
+                ]
+            }
+        },
+        { "CheckEq": [ { "GlobalVariable": "i" }, { "Plain": { "Integer": 11 } } ] }
+    ]
+}
+    "#;
+
+    let run = |policy: jit::JitPolicy| {
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(output::BufferSink::default());
+        eng.borrow_mut().set_jit_policy(policy);
+        eng.borrow_mut().set_jit_ir_dump(Some(jit::IrDump::Output));
+        let mut blk = engine::Engine::load_block(ast).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), 0);
+        assert_eq!(blk.jit_info.is_some(), policy == jit::JitPolicy::Eager);
+
+        // `i` is only known to be an integer at runtime, so the counter is
+        // guarded rather than left to the dynamic path.
+        if policy == jit::JitPolicy::Eager {
+            let body = match blk.ops[7] {
+                engine::Operation::Loop(ref body) => body,
+                _ => unreachable!()
+            };
+            assert!(body.jit_info.as_ref().unwrap().ir.as_ref().unwrap().contains("add i64"));
+        }
+
+        let eng = eng.borrow();
+        let mut vars: Vec<String> = eng.vars.iter()
+            .map(|(k, v)| format!("{}={}", k, v.to_string()))
+            .collect();
+        vars.sort();
+        (vars, eng.last_exit_status)
+    };
+
+    let interpreted = run(jit::JitPolicy::Off);
+    assert_eq!(interpreted.0, vec!["a=2", "b=42", "eq=1", "f=1.5", "i=10", "mixed=43.5"]);
+    assert_eq!(interpreted.1, 0);
+    assert!(run(jit::JitPolicy::Eager) == interpreted);
+}
+
+#[test]
+fn test_engine_interrupt() {
+    // Neither loop ever reaches an exec or a builtin.
+    let counter = r#"
+{
+    "ops": [
+        { "AssignGlobal": [ "i", { "Plain": { "Integer": 0 } } ] },
+        {
+            "Loop": {
+                "ops": [
+                    { "AssignGlobal": [ "i", { "Arith": [ "Add", { "GlobalVariable": "i" }, { "Plain": { "Integer": 1 } } ] } ] }
+                ]
+            }
+        }
+    ]
+}
+    "#;
+    let empty = r#"{ "ops": [ { "Loop": { "ops": [] } } ] }"#;
+
+    for &policy in [jit::JitPolicy::Off, jit::JitPolicy::Eager].iter() {
+        for ast in [counter, empty].iter() {
+            let sink = output::BufferSink::default();
+            let eng: engine::EngineHandle = engine::Engine::new().into();
+            eng.borrow_mut().set_output_sink(sink.clone());
+            eng.borrow_mut().set_jit_policy(policy);
+
+            let flag = eng.borrow().interrupted.clone();
+            let interrupter = std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+            let mut blk = engine::Engine::load_block(ast).unwrap();
+            assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+            interrupter.join().unwrap();
+            assert!(sink.error().starts_with("Interrupted\n"));
+
+            // Loops fail right away until the flag is cleared.
+            sink.clear();
+            let mut blk = engine::Engine::load_block(ast).unwrap();
+            assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+            assert!(sink.error().starts_with("Interrupted\n"));
+        }
+    }
+}
+
+#[test]
+fn test_engine_jit_ir_dump_and_stats() {
+    let ast = r#"
+{
+    "ops": [
+        { "AssignGlobal": [ "a", { "Plain": { "Integer": 1 } } ] },
+        { "Loop": { "ops": [ "Break" ] } }
+    ]
+}
+    "#;
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().set_jit_policy(jit::JitPolicy::AfterCalls(1));
+    eng.borrow_mut().set_jit_ir_dump(Some(jit::IrDump::Output));
+
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    for _ in 0..3 {
+        assert_eq!(eng.eval_block(&mut blk), 0);
+    }
+
+    assert!(sink.output().contains("define i32 @entry"));
+    assert!(blk.jit_info.as_ref().unwrap().ir.is_some());
+
+    let stats = eng.borrow().jit_stats.clone();
+    assert_eq!(stats.blocks_compiled, 2); // outer block and loop body
+    assert_eq!(stats.interpreted_executions, 2);
+    assert_eq!(stats.jit_executions, 4);
+    assert_eq!(stats.fallbacks, 0);
+}
+
+#[test]
+fn test_engine_aot_cache() {
+    let ast = r#"
+{
+    "ops": [
+        { "AssignGlobal": [ "f", { "Plain": { "Function": { "ops": [
+            { "AssignGlobal": [ "n", { "Arith": [ "Add", { "GlobalVariable": "n" }, { "Plain": { "Integer": 1 } } ] } ] }
+        ] } } } ] },
+        { "AssignGlobal": [ "n", { "Plain": { "Integer": 0 } } ] },
+        { "Call": { "GlobalVariable": "f" } },
+        { "Call": { "GlobalVariable": "f" } }
+    ]
+}
+    "#;
+
+    let dir = std::env::temp_dir().join(format!("oneshell-aot-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let run = || {
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_aot_cache(Some(jit::AotCache::new(dir.clone())));
+        let mut blk = engine::Engine::load_block(ast).unwrap();
+        assert!(eng.compile_aot(&mut blk));
+        assert_eq!(eng.eval_block(&mut blk), 0);
+        assert_eq!(eng.borrow().vars["n"].to_string(), "2");
+        let stats = eng.borrow().jit_stats.clone();
+        stats
+    };
+
+    // The function body assigned to `f` is a copy of the precompiled
+    // literal, so it already comes from the cache.
+    let first = run();
+    assert_eq!(first.blocks_compiled, 3);
+    assert_eq!(first.aot_cache_hits, 1);
+    assert_eq!(first.fallbacks, 0);
+
+    let second = run();
+    assert_eq!(second.blocks_compiled, 3);
+    assert_eq!(second.aot_cache_hits, 3);
+
+    // Entries whose relocation count doesn't match are misses, and get
+    // replaced.
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let mut data = Vec::new();
+        std::fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let end = data.iter().position(|&b| b == b'\n').unwrap();
+        let mut fields: Vec<String> = String::from_utf8(data[..end].to_vec()).unwrap()
+            .split(' ').map(|v| v.to_string()).collect();
+        fields[2] = (fields[2].parse::<usize>().unwrap() + 1).to_string();
+        let mut tampered = fields.join(" ").into_bytes();
+        tampered.extend_from_slice(&data[end..]);
+        std::fs::File::create(&path).unwrap().write_all(tampered.as_slice()).unwrap();
+    }
+
+    let third = run();
+    assert_eq!(third.aot_cache_hits, 1);
+    assert_eq!(third.fallbacks, 0);
+    assert_eq!(run().aot_cache_hits, 3);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_engine_aot_cache_typed_assignments() {
+    // Typed assignments call checks that write slots back on failure, which
+    // cached modules must keep doing.
+    let ast = r#"{ "ops": [
+        { "AssignLocal": [ "k", { "Plain": { "Integer": 1 } } ] },
+        { "SetAttributes": [ "Local", "k", { "value_type": "Integer" } ] },
+        { "AssignLocal": [ "k", { "Arith": [ "Add", { "LocalVariable": "k" }, { "Plain": { "Integer": 1 } } ] } ] },
+        { "Print": { "LocalVariable": "k" } },
+        { "AssignLocal": [ "k", { "Plain": { "String": "s" } } ] }
+    ] }"#;
+
+    let dir = std::env::temp_dir().join(format!("oneshell-aot-typed-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    for hits in vec![0, 1] {
+        let sink = output::BufferSink::default();
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_aot_cache(Some(jit::AotCache::new(dir.clone())));
+
+        let mut blk = engine::Engine::load_block(ast).unwrap();
+        assert!(eng.compile_aot(&mut blk));
+        assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+        assert_eq!(eng.borrow().jit_stats.aot_cache_hits, hits);
+        assert_eq!(sink.output().as_str(), "2\n");
+        assert_eq!(sink.error().as_str(), "Variable `k` can only hold integer values\n  at <main>, op 4\n");
+        assert_eq!(eng.borrow().call_stack[0].vars["k"].to_string(), "2");
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Writes its arguments to the engine's output sink.
+struct EmitBuiltin {
+    sink: output::BufferSink
+}
+
+impl builtin::Builtin for EmitBuiltin {
+    fn run(&self, args: &[String], _: &[(String, String)], _: &mut builtin::BuiltinIo) -> i32 {
+        use output::OutputSink;
+
+        self.sink.write_output(format!("{}\n", args[1..].join(" ")).as_str());
+        0
+    }
+}
+
+// Copies its stdin to the engine's output sink, like `cat` without
+// arguments.
+struct CatBuiltin {
+    sink: output::BufferSink
+}
+
+impl builtin::Builtin for CatBuiltin {
+    fn run(&self, _: &[String], _: &[(String, String)], io: &mut builtin::BuiltinIo) -> i32 {
+        use output::OutputSink;
+
+        let mut input = String::new();
+        io.stdin.read_to_string(&mut input).unwrap();
+        self.sink.write_output(input.as_str());
+        0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct DifferentialRun {
+    statuses: Vec<i32>,
+    last_exit_status: i32,
+    vars: Vec<String>,
+    output: String,
+    error: String
+}
+
+fn run_differential(ast: &str, policy: jit::JitPolicy) -> DifferentialRun {
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().set_jit_policy(policy);
+    eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+        sink: sink.clone()
+    });
+    eng.borrow_mut().register_builtin("cat", CatBuiltin {
+        sink: sink.clone()
+    });
+
+    // Evaluated twice so that warm call sites are covered as well. Some
+    // fixtures cover runtime behavior that validation would reject.
+    let mut blk = engine::Engine::load_block_unchecked(ast).unwrap();
+    let statuses = vec![eng.eval_block(&mut blk), eng.eval_block(&mut blk)];
+    assert_eq!(blk.jit_info.is_some(), policy == jit::JitPolicy::Eager);
+
+    let eng = eng.borrow();
+    let mut vars: Vec<String> = eng.vars.iter()
+        .map(|(k, v)| format!("{}={}", k, v.to_string()))
+        .collect();
+    vars.sort();
+
+    let output = sink.output();
+    let error = sink.error();
+    DifferentialRun {
+        statuses: statuses,
+        last_exit_status: eng.last_exit_status,
+        vars: vars,
+        output: output,
+        error: error
+    }
+}
+
+fn collect_operations(blk: &engine::Block, seen: &mut Vec<&'static str>) {
+    use engine::Operation;
+
+    for op in blk.ops.iter() {
+        // No wildcard, so that new operations have to be added here and to
+        // the fixtures.
+        let name = match *op {
+            Operation::Exec(_) => "Exec",
+            Operation::ParallelExec(_) => "ParallelExec",
+            Operation::BackgroundExec(_) => "BackgroundExec",
+            Operation::IfElse(..) => "IfElse",
+            Operation::Loop(_) => "Loop",
+            Operation::Break => "Break",
+            Operation::AssignGlobal(..) => "AssignGlobal",
+            Operation::AssignLocal(..) => "AssignLocal",
+            Operation::Declare(..) => "Declare",
+            Operation::Unset(..) => "Unset",
+            Operation::SetAttributes(..) => "SetAttributes",
+            Operation::EngineBacktrace => "EngineBacktrace",
+            Operation::Print(_) => "Print",
+            Operation::CheckEq(..) => "CheckEq",
+            Operation::Call(_) => "Call",
+            Operation::Parallel(_) => "Parallel",
+            Operation::ForEach(_) => "ForEach"
+        };
+        if !seen.contains(&name) {
+            seen.push(name);
+        }
+    }
+
+    let mut blk = blk.clone();
+    for child in blk.children_mut() {
+        collect_operations(child, seen);
+    }
+}
+
+#[test]
+fn test_engine_jit_differential() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(&dir).unwrap()
+        .map(|v| v.unwrap().path())
+        .filter(|v| v.extension().map(|ext| ext == "json").unwrap_or(false))
+        .collect();
+    paths.sort();
+
+    let mut seen: Vec<&'static str> = Vec::new();
+
+    for path in paths.iter() {
+        let mut ast = String::new();
+        std::fs::File::open(path).unwrap().read_to_string(&mut ast).unwrap();
+        collect_operations(&engine::Engine::load_block_unchecked(ast.as_str()).unwrap(), &mut seen);
+
+        let interpreted = run_differential(ast.as_str(), jit::JitPolicy::Off);
+        let compiled = run_differential(ast.as_str(), jit::JitPolicy::Eager);
+
+        assert!(interpreted == compiled, "{}: interpreter {:?} != JIT {:?}", path.display(), interpreted, compiled);
+    }
+
+    assert_eq!(seen.len(), 17, "fixtures only cover {:?}", seen);
+}
+
+#[test]
+fn test_engine_owned_across_threads() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "AssignGlobal": [
+                "f",
+                { "Plain": { "Function": { "ops": [
+                    { "AssignGlobal": [ "n", { "Arith": [ "Add", { "GlobalVariable": "n" }, { "Plain": { "Integer": 1 } } ] } ] }
+                ] } } }
+            ]
+        },
+        { "Call": { "GlobalVariable": "f" } },
+        { "Print": { "Value": { "GlobalVariable": "n" } } }
+    ]
+}
+    "#;
+
+    let sink = output::BufferSink::default();
+
+    let mut eng = owned::OwnedEngine::new();
+    eng.set_output_sink(sink.clone());
+    eng.set_jit_policy(jit::JitPolicy::Eager);
+    eng.set_global("n", &var::Value::Integer(0)).unwrap();
+    let blk = eng.load_block(ast).unwrap();
+
+    // Compiled code and call-site caches move along with the engine.
+    for _ in 0..3 {
+        eng = std::thread::spawn(move || {
+            assert_eq!(eng.eval_block(blk), 0);
+            eng
+        }).join().unwrap();
+    }
+
+    assert!(eng.get_global("n") == Some(var::Value::Integer(3)));
+    assert_eq!(sink.output().as_str(), "1\n2\n3\n");
+    assert!(eng.jit_stats().jit_executions > 0);
+
+    let mut forked = eng.fork().unwrap();
+    assert!(!forked.unload_block(blk));
+    assert_eq!(forked.eval_block(blk), signals::EXCEPTION);
+    assert!(forked.get_global("n") == Some(var::Value::Integer(3)));
+
+    assert!(eng.unload_block(blk));
+    assert_eq!(eng.eval_block(blk), signals::EXCEPTION);
+}
+
+#[test]
+fn test_engine_parallel() {
+    let ast = r#"
+{
+    "ops": [
+        {
+            "Parallel": {
+                "blocks": [
+                    { "ops": [
+                        { "AssignGlobal": [ "x", { "Plain": { "Integer": 1 } } ] },
+                        { "Call": { "GlobalVariable": "missing" } }
+                    ] },
+                    { "ops": [ { "AssignGlobal": [ "y", { "Plain": { "Integer": 2 } } ] } ] },
+                    { "ops": [ { "AssignGlobal": [ "z", { "Plain": { "Integer": 3 } } ] } ] }
+                ],
+                "max_concurrency": 1,
+                "merge_globals": [ "x", "y", "z" ],
+                "fail_fast": true
+            }
+        }
+    ]
+}
+    "#;
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+
+    let mut blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+
+    // With a single worker, nothing starts after the first branch fails.
+    let eng = eng.borrow();
+    assert!(eng.branch_statuses == vec![
+        engine::BranchStatus { signal: Some(signals::EXCEPTION), exit_status: 0 },
+        engine::BranchStatus { signal: None, exit_status: 0 },
+        engine::BranchStatus { signal: None, exit_status: 0 }
+    ]);
+    assert_eq!(eng.vars["x"].to_string(), "1");
+    assert!(eng.vars.get("y").is_none());
+    assert_eq!(
+        sink.error().as_str(),
+        "Call target is undefined\n  at <main>, op 1\nException in parallel branch 0\n  at <main>, op 0\n"
+    );
+}
+
+#[test]
+fn test_engine_parallel_context() {
+    // Branches get copies of the locals, but can't run the engine's builtins.
+    let ast = r#"{ "ops": [
+        { "AssignLocal": [ "v", { "Plain": { "String": "local v" } } ] },
+        { "Parallel": {
+            "blocks": [
+                { "ops": [
+                    { "Print": { "LocalVariable": "v" } },
+                    { "AssignGlobal": [ "w", { "LocalVariable": "v" } ] }
+                ] },
+                { "ops": [ { "Exec": {
+                    "command": [ { "Plain": "upper" } ],
+                    "env": [],
+                    "stdin": "Inherit",
+                    "stdout": "Inherit"
+                } } ] }
+            ],
+            "max_concurrency": 1,
+            "merge_globals": [ "w" ]
+        } }
+    ] }"#;
+
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+        eng.borrow_mut().register_builtin("upper", UpperBuiltin);
+
+        let mut blk = engine::Engine::load_block(ast).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION, "{:?}", policy);
+        assert_eq!(sink.output().as_str(), "local v\n", "{:?}", policy);
+        assert_eq!(
+            sink.error().as_str(),
+            "Builtin `upper` is not available in parallel branches\n  at <main>, op 0\nException in parallel branch 1\n  at <main>, op 1\n",
+            "{:?}", policy
+        );
+        assert_eq!(eng.borrow().vars["w"].to_string(), "local v", "{:?}", policy);
+    }
+
+    // Output is written as branches finish, not after all of them: the
+    // slow branch only finishes once the fast one's output arrived, or
+    // gives up after ten seconds so that the test fails instead of hanging.
+    let flag = std::env::temp_dir().join(format!("oneshell-parallel-test-{}", std::process::id()));
+    let _ = std::fs::remove_file(&flag);
+    let ast = format!(r#"{{ "ops": [ {{ "Parallel": {{ "blocks": [
+        {{ "ops": [
+            {{ "Exec": {{
+                "command": [ {{ "Plain": "sh" }}, {{ "Plain": "-c" }}, {{ "Plain": "i=0; until [ -e \"$1\" ] || [ $i = 1000 ]; do sleep 0.01; i=$((i + 1)); done" }}, {{ "Plain": "sh" }}, {{ "Plain": {:?} }} ],
+                "env": [],
+                "stdin": "Inherit",
+                "stdout": "Inherit"
+            }} }},
+            {{ "Print": {{ "Plain": "slow" }} }}
+        ] }},
+        {{ "ops": [ {{ "Print": {{ "Plain": "fast" }} }} ] }}
+    ] }} }} ] }}"#, flag.to_str().unwrap());
+
+    let sink = output::BufferSink::default();
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(FlagSink {
+        inner: sink.clone(),
+        flag: flag.clone()
+    });
+    assert_eq!(eng.eval_block(&mut engine::Engine::load_block(ast.as_str()).unwrap()), 0);
+    assert_eq!(sink.output().as_str(), "fast\nslow\n");
+    let _ = std::fs::remove_file(&flag);
+}
+
+// Creates `flag` once `fast` was written to it.
+struct FlagSink {
+    inner: output::BufferSink,
+    flag: std::path::PathBuf
+}
+
+impl output::OutputSink for FlagSink {
+    fn write_output(&self, data: &str) {
+        self.inner.write_output(data);
+        if data.contains("fast") {
+            std::fs::File::create(&self.flag).unwrap();
+        }
+    }
+
+    fn write_error(&self, data: &str) {
+        self.inner.write_error(data);
+    }
+}
+
+#[test]
+fn test_engine_parse_script() {
+    let script = r#"
+# Comments and blank lines are ignored.
+greeting='hello'
+count=0
+
+greet() {
+    local who="$greeting world"
+    emit "$who"
+}
+
+greet
+for i in a "b c"; do
+    emit item "$i"
+done
+
+if sh -c 'echo redirected' > "$tmp" && ! sh -c 'exit 3'; then
+    cat < "$tmp"
+    sh -c 'echo appended' >> "$tmp"
+    cat < "$tmp"
+
+    emit then-branch
+elif true; then
+    emit elif-branch
+else
+    emit else-branch
+fi
+
+while true; do
+    count=done
+    break
+done
+
+sh -c 'exit 5' || emit "status $?"
+sh -c 'echo piped' | cat
+NAME=env sh -c 'exit 0'
+(inner=1; sh -c 'exit 0')
+"#;
+
+    let tmp = std::env::temp_dir().join(format!("oneshell-parse-test-{}", std::process::id()));
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+        sink: sink.clone()
+    });
+    eng.borrow_mut().register_builtin("cat", CatBuiltin {
+        sink: sink.clone()
+    });
+
+    eng.borrow_mut().vars.insert("tmp".to_string(), var::Variable::from_value(
+        var::Value::String(tmp.to_str().unwrap().to_string())
+    ));
+
+    let mut blk = engine::Engine::parse_script(script).unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+    let _ = std::fs::remove_file(&tmp);
+
+    assert_eq!(sink.error().as_str(), "");
+    assert_eq!(
+        sink.output().as_str(),
+        "hello world\nitem a\nitem b c\nredirected\nredirected\nappended\nthen-branch\nstatus 5\npiped\n"
+    );
+    assert_eq!(eng.borrow().vars["count"].to_string(), "done");
+    assert!(eng.borrow().vars.get("inner").is_none());
+
+    let cases = vec![
+        ("if true; then\n  emit x\n", "3:1: expected 'fi' before end of input"),
+        ("emit 'unterminated", "1:6: unterminated single quote"),
+        ("echo a |", "1:9: unexpected end of input"),
+        ("break", "1:1: break outside of a loop"),
+        ("f() { emit; }\nf arg", "2:1: arguments to functions are not supported"),
+        ("emit $(date)", "1:7: command substitution is not supported"),
+        ("fi", "1:1: unexpected 'fi'")
+    ];
+    for (src, expected) in cases {
+        let err = engine::Engine::parse_script(src).err().unwrap();
+        assert!(err.kind == engine::LoadErrorKind::Syntax);
+        assert_eq!(err.to_string(), expected);
+    }
+}
+
+#[test]
+fn test_engine_parameter_expansion() {
+    let script = r#"
+path=/usr/local/lib/libfoo.so.1
+empty=
+star='a*b'
+n=2
+v=xab
+p='?'
+emit "${unset:-default}" "${empty:-fallback}" "${empty-kept}" "${path:+set}" "${unset:-${path##*/}}"
+emit "${path#*/}" "${path##*/}" "${path%.*}" "${path%%.*}"
+emit "${path/lib/LIB}" "${path//lib/LIB}" "${path//[aeiou]/}" "${path/#/x}"
+emit "${#path}" "${path:5}" "${path:5:5}" "${path: -3}" "${path:1:-3}" "${path:n:2}"
+emit "${star#"a*"}" "${star#a\*}" "${star#a*}" "${star%'}'}"
+emit "${v#"$p"}" "${v#$p}" "${v#"${p}"x}" "${v/"$p"/-}" "${v/$p/-}"
+"#;
+    let expected = concat!(
+        "default fallback  set libfoo.so.1\n",
+        "usr/local/lib/libfoo.so.1 libfoo.so.1 /usr/local/lib/libfoo.so /usr/local/lib/libfoo\n",
+        "/usr/local/LIB/libfoo.so.1 /usr/local/LIB/LIBfoo.so.1 /sr/lcl/lb/lbf.s.1 /usr/local/lib/libfoo.so.1\n",
+        "26 local/lib/libfoo.so.1 local o.1 usr/local/lib/libfoo.s sr\n",
+        "b b *b a*b\n",
+        "xab ab xab xab -ab\n"
+    );
+
+    // Printed scripts parse back into the same expansions.
+    let blk = engine::Engine::parse_script(script).unwrap();
+    let printed = blk.to_string();
+    for src in vec![script, printed.as_str()] {
+        let sink = output::BufferSink::default();
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+            sink: sink.clone()
+        });
+
+        let mut blk = engine::Engine::parse_script(src).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), 0, "{}", src);
+        assert_eq!(sink.output().as_str(), expected, "{}", src);
+    }
+
+    // Unset operands are empty, so assigning them never fails.
+    let assignments = r#"
+path=/usr/lib
+a=${unset#x}; b=${unset%x}; c=${unset/a/b}; d=${#unset}
+e=${path:3:-30}; f=${path:zz:2}; g=${path:x}
+emit "[$a][$b][$c][$d][$e][$f][$g]"
+"#;
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+        eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+            sink: sink.clone()
+        });
+
+        let mut blk = engine::Engine::parse_script(assignments).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), 0);
+        assert_eq!(sink.output().as_str(), "[][][][0][][/u][/usr/lib]\n");
+    }
+
+    let cases = vec![
+        ("emit ${path", "1:7: unterminated parameter expansion"),
+        ("emit ${path:-\"x}", "1:14: unterminated double quote"),
+        ("emit ${path=x}", "1:7: unsupported parameter expansion"),
+        ("emit ${#path:-x}", "1:7: unsupported parameter expansion")
+    ];
+    for (src, expected) in cases {
+        let err = engine::Engine::parse_script(src).err().unwrap();
+        assert_eq!(err.to_string(), expected);
+    }
+
+    assert!(glob::matches("[!a-c]?*.[ch]", "d1.c"));
+    assert!(!glob::matches("[!a-c]?*.[ch]", "b1.c"));
+    assert!(glob::matches("[]x]\\*", "]*"));
+    assert!(glob::matches("a[", "a["));
+    assert!(glob::matches(glob::escape("*[?]").as_str(), "*[?]"));
+}
+
+#[test]
+fn test_engine_glob() {
+    let dir = std::env::temp_dir().join(format!("oneshell-glob-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    for sub in ["src/net", "src/.cache", "docs"].iter() {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    for file in ["a.c", "b.c", "b.h", "c1.txt", ".hidden", "src/main.rs", "src/net/tcp.rs", "src/.cache/x.rs", "docs/a b.md"].iter() {
+        std::fs::File::create(dir.join(file)).unwrap();
+    }
+
+    let script = r#"
+emit *.c ?.h [ab].* "*.c" \*.c
+emit **/*.rs src/**
+emit */ docs/* d*/a\ b.md
+emit {a,b}.{c,h} x{1..3} {c..a}{,!} {single} *.none
+emit '{a,b}' [ a{b
+for f in [ab].c x{1,2}; do emit "<$f>"; done
+for f in *.none; do emit "$f"; done
+"#;
+    let expected = concat!(
+        "a.c b.c b.h a.c b.c b.h *.c *.c\n",
+        "src/main.rs src/net/tcp.rs src/main.rs src/net src/net/tcp.rs\n",
+        "docs/ src/ docs/a b.md docs/a b.md\n",
+        "a.c a.h b.c b.h x1 x2 x3 c c! b b! a a! {single} *.none\n",
+        "{a,b} [ a{b\n",
+        "<a.c>\n", "<b.c>\n", "<x1>\n", "<x2>\n",
+        "*.none\n"
+    );
+
+    let run = |src: &str, options: glob::Options| {
+        let sink = output::BufferSink::default();
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+            sink: sink.clone()
+        });
+        eng.borrow_mut().cwd = Some(dir.to_str().unwrap().to_string());
+        eng.borrow_mut().set_glob_options(options);
+
+        let mut blk = engine::Engine::parse_script(src).unwrap();
+        let status = eng.eval_block(&mut blk);
+        let output = sink.output();
+        (status, output)
+    };
+
+    // Printed scripts keep their patterns.
+    let blk = engine::Engine::parse_script(script).unwrap();
+    let printed = blk.to_string();
+    for src in vec![script, printed.as_str()] {
+        assert_eq!(run(src, glob::Options::default()), (0, expected.to_string()), "{}", src);
+    }
+
+    let dotglob = glob::Options {
+        no_match: glob::NoMatch::Keep,
+        dotglob: true
+    };
+    assert_eq!(run("emit * src/**/*.rs", dotglob).1, ".hidden a.c b.c b.h c1.txt docs src src/.cache/x.rs src/main.rs src/net/tcp.rs\n");
+    assert_eq!(run("emit .* src/.*/*", glob::Options::default()).1, ".hidden src/.cache/x.rs\n");
+
+    let nullglob = glob::Options {
+        no_match: glob::NoMatch::Remove,
+        dotglob: false
+    };
+    assert_eq!(run("emit *.none a.c *.h", nullglob).1, "a.c b.h\n");
+    assert_eq!(run("for f in *.none; do emit $f; done; emit done", nullglob).1, "done\n");
+
+    let failglob = glob::Options {
+        no_match: glob::NoMatch::Fail,
+        dotglob: false
+    };
+    let (status, output) = run("emit *.c *.none; emit after", failglob);
+    assert_ne!(status, 0);
+    assert_eq!(output, "");
+
+    // Quoted expansions in arguments match literally.
+    std::fs::create_dir(dir.join("obj")).unwrap();
+    for file in ["obj/a[1]x.o", "obj/a1x.o"].iter() {
+        std::fs::File::create(dir.join(file)).unwrap();
+    }
+    let script = "p='obj/a[1]'\nemit \"$p\"*.o $p*.o\nfor f in \"$p\"*; do emit \"<$f>\"; done\n";
+    let printed = engine::Engine::parse_script(script).unwrap().to_string();
+    for src in vec![script, printed.as_str()] {
+        assert_eq!(run(src, glob::Options::default()).1, "obj/a[1]x.o obj/a1x.o\n<obj/a[1]x.o>\n", "{}", src);
+    }
+
+    assert_eq!(glob::expand_braces("{a,{b,c}d}e"), vec!["ae", "bde", "cde"]);
+    assert_eq!(glob::expand_braces("{a\\,b}{}"), vec!["{a\\,b}{}"]);
+    assert_eq!(glob::expand_braces("{-1..-3}"), vec!["-1", "-2", "-3"]);
+    assert_eq!(glob::expand_braces("{0..9223372036854775807}"), vec!["{0..9223372036854775807}"]);
+    assert_eq!(glob::expand_braces("{-9223372036854775808..1}"), vec!["{-9223372036854775808..1}"]);
+    assert_eq!(glob::expand_braces("{1..10000000000}").len(), 1);
+    assert_eq!(glob::expand_braces("{1..65536}").len(), 65536);
+    assert_eq!(glob::expand_braces("{1..256}{1..256}").len(), 65536);
+    assert_eq!(glob::expand_braces("{1..256}{1..257}"), vec!["{1..256}{1..257}"]);
+    assert_eq!(glob::expand_braces("x{1..60000}{1..60000}"), vec!["x{1..60000}{1..60000}"]);
+    assert!(glob::has_magic("a[bc]"));
+    assert!(!glob::has_magic("a\\*[b"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_engine_pretty_print() {
+    let script = r#"
+greet() {
+    local who="$greeting world"
+    emit "$who" 'it'\''s' > out.txt
+}
+x=1
+greet && emit ok || emit "failed: $?"
+sh -c 'echo a' | grep a | LANG=C sort >> sorted.txt
+while sh -c 'exit 1'; do
+    break
+done
+until true; do
+    emit
+done
+for i in a b; do
+    emit "$i"
+done
+if true; then
+    emit yes
+else
+    emit no
+fi
+(emit sub)
+sleep 1 &
+"#;
+
+    let expected = r#"greet() {
+    local who="${greeting} world"
+    emit "${who}" 'it'\''s' > out.txt
+}
+x=1
+greet && emit ok || emit "failed: $?"
+sh -c 'echo a' | grep a | LANG=C sort >> sorted.txt
+while sh -c 'exit 1'; do
+    break
+done
+until true; do
+    emit
+done
+while true; do
+    i=a
+    emit "${i}"
+    i=b
+    emit "${i}"
+    break
+done
+true
+if [ $? -eq 0 ]; then
+    emit yes
+else
+    emit no
+fi
+(
+    emit sub
+)
+sleep 1 &
+"#;
+
+    let blk = engine::Engine::parse_script(script).unwrap();
+    assert_eq!(blk.to_string(), expected);
+
+    // Printed scripts parse back to the same text, except for standalone
+    // `IfElse`, whose `[ $? -eq 0 ]` condition becomes a real command.
+    let reprinted = expected.replace("true\nif [ $? -eq 0 ]; then\n    emit yes\nelse\n    emit no\nfi\n", "");
+    assert_eq!(engine::Engine::parse_script(reprinted.as_str()).unwrap().to_string(), reprinted);
+
+    let ast = r#"
+{
+    "ops": [
+        { "AssignGlobal": [ "n", { "Arith": [ "Mul", { "Arith": [ "Add", { "GlobalVariable": "n" }, { "Plain": { "Integer": 1 } } ] }, { "Plain": { "Float": 2.0 } } ] } ] },
+        { "Print": { "Join": [ { "Plain": "n is " }, { "Value": { "GlobalVariable": "n" } }, { "Plain": " \"$\"" } ] } },
+        { "CheckEq": [ "LastExitStatus", { "Plain": "Null" } ] }
+    ]
+}
+    "#;
+    let blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(blk.to_string(), "n=\"$(((n + 1) * 2.0))\"\nprint \"n is ${n} \\\"\\$\\\"\"\ncheck_eq \"$?\" null\n");
+}
+
+#[test]
+fn test_engine_trace() {
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().set_jit_policy(jit::JitPolicy::Eager);
+    eng.borrow_mut().set_trace(true);
+
+    let mut blk = engine::Engine::parse_script("x=1\nwhile true; do\n    break\ndone\n").unwrap();
+    assert_eq!(eng.eval_block(&mut blk), 0);
+
+    // Tracing keeps blocks in the interpreter.
+    assert!(blk.jit_info.is_none());
+    assert_eq!(sink.error().as_str(), "+ 1:1: x=1\n+ 2:1: while true; do\n+ 2:7: true\n+ 2:1: if [ $? -eq 0 ]; then\n+ 3:5: break\n");
+}
+
+#[test]
+fn test_engine_repl() {
+    let dir = std::env::temp_dir().join(format!("oneshell-repl-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("subdir")).unwrap();
+    std::fs::write(dir.join("alpha.txt"), "").unwrap();
+    std::fs::write(dir.join("alpine.txt"), "").unwrap();
+    std::fs::write(dir.join(".hidden"), "").unwrap();
+    std::fs::create_dir_all(dir.join("bin")).unwrap();
+    std::fs::write(dir.join("bin/greeter"), "").unwrap();
+    std::fs::write(dir.join("bin/grep-data"), "").unwrap();
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.join("bin/greeter"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let history_path = dir.join("history");
+
+    let sink = output::BufferSink::default();
+
+    let eng: engine::EngineHandle = engine::Engine::new().into();
+    eng.borrow_mut().set_output_sink(sink.clone());
+    eng.borrow_mut().register_builtin("emit", EmitBuiltin {
+        sink: sink.clone()
+    });
+    eng.borrow_mut().cwd = Some(dir.to_str().unwrap().to_string());
+    eng.borrow_mut().env.insert("PATH".to_string(), dir.join("bin").to_str().unwrap().to_string());
+
+    let mut session = repl::Repl::new(eng.clone());
+    session.history = repl::History::open(&history_path).unwrap();
+    session.prompt = parser::parse_string("[$?] ${name}\\$ ").unwrap();
+    eng.borrow_mut().vars.insert("name".to_string(), var::Variable::from_value(
+        var::Value::String("sh".to_string())
+    ));
+    assert_eq!(session.prompt(), "[0] sh$ ");
+
+    // Functions and variables defined by one input are visible to later ones.
+    assert_eq!(session.feed_line("greet() {"), None);
+    assert_eq!(session.prompt(), "> ");
+    assert_eq!(session.feed_line("    emit hello \"$who\""), None);
+    assert_eq!(session.feed_line("}"), Some(signals::OK));
+    assert_eq!(session.feed_line("who=world"), Some(signals::OK));
+    assert_eq!(session.feed_line("greet"), Some(signals::OK));
+    assert_eq!(session.feed_line("emit 'multi"), None);
+    assert_eq!(session.feed_line("line' \\"), None);
+    assert_eq!(session.feed_line("done"), Some(signals::OK));
+    assert_eq!(sink.output().as_str(), "hello world\nmulti\nline done\n");
+
+    assert_eq!(session.feed_line("fi"), Some(signals::EXCEPTION));
+    assert_eq!(sink.error().as_str(), "1:1: unexpected 'fi'\n");
+    assert_eq!(session.prompt(), "[2] sh$ ");
+
+    assert_eq!(session.feed_line("if true; then"), None);
+    session.cancel();
+    assert_eq!(session.prompt(), "[2] sh$ ");
+
+    // History is kept across sessions.
+    let expected = vec!["greet() {\n    emit hello \"$who\"\n}", "who=world", "greet", "emit 'multi\nline' \\\ndone", "fi"];
+    assert_eq!(session.history.entries(), expected.as_slice());
+    session.history.add("fi").unwrap();
+    assert_eq!(repl::History::open(&history_path).unwrap().entries(), expected.as_slice());
+
+    let complete = |line: &str| {
+        let c = repl::complete(&eng.borrow(), line, line.len());
+        (c.start, c.candidates)
+    };
+    assert_eq!(complete("emit $w"), (5, vec!["$who".to_string()]));
+    assert_eq!(complete("emit x${na"), (5, vec!["x${name}".to_string()]));
+    assert_eq!(complete("gr"), (0, vec!["greet".to_string(), "greeter".to_string()]));
+    assert_eq!(complete("true && em"), (8, vec!["emit".to_string()]));
+    assert_eq!(complete("if whi"), (3, vec!["while".to_string()]));
+    assert_eq!(complete("emit gr"), (5, vec![]));
+    assert_eq!(complete("emit al"), (5, vec!["alpha.txt".to_string(), "alpine.txt".to_string()]));
+    assert_eq!(complete("emit < su"), (7, vec!["subdir/".to_string()]));
+    assert_eq!(complete("emit ."), (5, vec![".hidden".to_string()]));
+    assert_eq!(complete("emit ../nonexistent-dir/"), (5, vec![]));
+
+    // So do top-level locals and the working directory.
+    eng.borrow_mut().register_builtin("cd", builtin::ChangeDir);
+    assert_eq!(session.feed_line("local place=here"), Some(signals::OK));
+    assert_eq!(session.feed_line("cd subdir"), Some(signals::OK));
+    assert_eq!(session.feed_line("emit got \"$place\""), Some(signals::OK));
+    assert!(sink.output().ends_with("got here\n"));
+    assert!(eng.borrow().vars.get("place").is_none());
+    let subdir = dir.join("subdir").canonicalize().unwrap();
+    assert_eq!(eng.borrow().cwd, Some(subdir.to_str().unwrap().to_string()));
+    assert_eq!(complete("emit ../al"), (5, vec!["../alpha.txt".to_string(), "../alpine.txt".to_string()]));
+
+    // Not in pipelines, which run in subshells.
+    assert_eq!(session.feed_line("cd .. | emit piped"), Some(signals::OK));
+    session.feed_line("cd nonexistent-dir");
+    assert_eq!(eng.borrow().last_exit_status, 1);
+    assert_eq!(eng.borrow().cwd, Some(subdir.to_str().unwrap().to_string()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_engine_script_backtrace() {
+    let script = r#"
+inner() {
+    if true; then
+        /nonexistent/command
+    fi
+}
+outer() {
+    inner
+}
+x=1
+outer
+"#;
+
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+
+        let mut blk = engine::Engine::parse_script(script).unwrap();
+        assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+
+        // Only the failing op reports; the calls it propagates through don't.
+        assert_eq!(
+            sink.error().as_str(),
+            "4:9: No such file or directory (os error 2)\n  at inner, op 1.0 (4:9)\n  at outer, op 0 (8:5)\n  at <main>, op 3 (11:1)\n",
+            "{:?}", policy
+        );
+        assert!(eng.borrow().script_stack.is_empty());
+        assert_eq!(eng.borrow().call_stack.len(), 1);
+
+        sink.clear();
+        eng.borrow_mut().set_native_backtrace(true);
+        assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+        assert!(sink.error().contains("  at <main>, op 3 (11:1)\nNative backtrace:\n"));
+    }
+}
+
+#[test]
+fn test_engine_spans() {
+    let ast = r#"
+{
+    "ops": [
+        { "EngineBacktrace": null, "span": { "line": 1 } },
+        { "Loop": { "ops": [
+            {
+                "Exec": {
+                    "command": [ { "Plain": "/nonexistent/command" } ],
+                    "env": [],
+                    "stdin": "Inherit",
+                    "stdout": "Inherit",
+                    "span": { "id": "cmd-1" }
+                },
+                "span": { "file": "gen.sh", "line": 2, "column": 3 }
+            },
+            "Break"
+        ] } }
+    ]
+}
+    "#;
+    let blk = engine::Engine::load_block(ast).unwrap();
+    assert_eq!(blk.span(0), Some(&span::Span::at(1, 0)));
+    assert_eq!(blk.span(1), None);
+
+    // Spans survive cloning and a round trip through JSON.
+    let blk = blk.clone();
+    let json = serde_json::to_string(&*blk).unwrap();
+    let mut blk = engine::Engine::load_block(json.as_str()).unwrap();
+    assert_eq!(serde_json::to_string(&*blk).unwrap(), json);
+
+    for policy in vec![jit::JitPolicy::Off, jit::JitPolicy::Eager] {
+        let sink = output::BufferSink::default();
+
+        let eng: engine::EngineHandle = engine::Engine::new().into();
+        eng.borrow_mut().set_output_sink(sink.clone());
+        eng.borrow_mut().set_jit_policy(policy);
+
+        assert_eq!(eng.eval_block(&mut blk), signals::EXCEPTION);
+        assert_eq!(sink.output().as_str(), "  at <main>, op 0 (1)\n", "{:?}", policy);
+        assert_eq!(
+            sink.error().as_str(),
+            "cmd-1: No such file or directory (os error 2)\n  at <main>, op 1.0 (gen.sh:2:3)\n",
+            "{:?}", policy
+        );
+    }
+
+    let mut blk = engine::Engine::parse_script("if true; then\n    emit a b c | emit d e f\nfi\n").unwrap();
+    blk.set_span_file("test.sh");
+    let then_blk = match blk.ops[1] {
+        engine::Operation::IfElse(_, ref then_blk) => then_blk,
+        _ => panic!("expected IfElse")
+    };
+    assert_eq!(blk.span(1).unwrap().to_string(), "test.sh:1:1");
+    match then_blk.ops[0] {
+        engine::Operation::ParallelExec(ref infos) => {
+            assert_eq!(then_blk.span(0).unwrap().to_string(), "test.sh:2:5");
+            assert_eq!(infos[1].span.as_ref().unwrap().to_string(), "test.sh:2:18");
+        },
+        _ => panic!("expected ParallelExec")
+    }
+}
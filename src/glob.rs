@@ -0,0 +1,400 @@
+use std;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// What a pattern that matches no path expands to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NoMatch {
+    Keep, // the pattern itself, without escapes
+    Remove, // nothing, like bash's `nullglob`
+    Fail // an error, like bash's `failglob`
+}
+
+impl Default for NoMatch {
+    fn default() -> NoMatch {
+        NoMatch::Keep
+    }
+}
+
+// Longer sequences, and words expanding to more words, are left as they
+// are.
+const MAX_SEQUENCE_LEN: u64 = 65536;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Options {
+    #[serde(default)]
+    pub no_match: NoMatch,
+
+    // Whether wildcards match names starting with `.`.
+    #[serde(default)]
+    pub dotglob: bool
+}
+
+/// Whether all of `text` matches `pattern`. `*` matches any string, `?`
+/// any character and `[...]` any character in the set, which may contain
+/// ranges like `a-z` and is negated by a leading `!` or `^`. A backslash
+/// makes the next character literal, and so does a `[` without a closing
+/// `]`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(pattern.as_slice(), text.as_slice())
+}
+
+/// Makes all characters of `s` match literally.
+pub fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        if is_special(c) {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+/// Removes the escaping backslashes from `pattern`.
+pub fn unescape(pattern: &str) -> String {
+    let mut ret = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => ret.push(chars.next().unwrap_or('\\')),
+            _ => ret.push(c)
+        }
+    }
+    ret
+}
+
+/// Whether `pattern` contains an unescaped wildcard, that is, whether it
+/// can match anything but itself.
+pub fn has_magic(pattern: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < p.len() {
+        match p[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if class(&p[i..], '\0').is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Expands braces in `pattern`, then replaces each resulting word that has
+/// wildcards by the paths it matches, in sorted order. Relative patterns
+/// are matched in `cwd`, and a `**` component matches any number of
+/// directories. Words without wildcards are kept as they are.
+pub fn expand(pattern: &str, cwd: &Path, options: Options) -> Result<Vec<String>, Box<Error>> {
+    let mut ret = Vec::new();
+    for word in expand_braces(pattern) {
+        if !has_magic(&word) {
+            ret.push(unescape(&word));
+            continue;
+        }
+
+        let mut paths = match_paths(&word, cwd, options);
+        if paths.len() == 0 {
+            match options.no_match {
+                NoMatch::Keep => ret.push(unescape(&word)),
+                NoMatch::Remove => {},
+                NoMatch::Fail => return Err(format!("No match for pattern `{}`", unescape(&word)).into())
+            }
+        }
+        ret.append(&mut paths);
+    }
+    Ok(ret)
+}
+
+/// Expands `a{b,c}d` to `abd` and `acd`, and `{1..3}` or `{a..c}` to a
+/// sequence of at most 65536 items. Alternatives may nest. Braces without
+/// a top-level comma or a valid sequence stay as they are, as does the
+/// whole word if it would expand to more than 65536 words.
+pub fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    if expand_braces_into(pattern, &mut ret) {
+        ret
+    } else {
+        vec![pattern.to_string()]
+    }
+}
+
+// Returns false as soon as `out` would grow past `MAX_SEQUENCE_LEN`.
+fn expand_braces_into(pattern: &str, out: &mut Vec<String>) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+
+    let mut i = 0;
+    while i < p.len() {
+        match p[i] {
+            '\\' => i += 1,
+            '{' => if let Some((end, items)) = brace_items(&p, i) {
+                let prefix: String = p[..i].iter().collect();
+                let suffix: String = p[end + 1..].iter().collect();
+                for item in items {
+                    if !expand_braces_into(&format!("{}{}{}", prefix, item, suffix), out) {
+                        return false;
+                    }
+                }
+                return true;
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    if out.len() as u64 == MAX_SEQUENCE_LEN {
+        return false;
+    }
+    out.push(pattern.to_string());
+    true
+}
+
+fn is_special(c: char) -> bool {
+    match c {
+        '*' | '?' | '[' | ']' | '\\' | '{' | '}' | ',' => true,
+        _ => false
+    }
+}
+
+// The index of the `}` closing the brace at `start`, and the items between
+// them, if they form a list or a sequence.
+fn brace_items(p: &[char], start: usize) -> Option<(usize, Vec<String>)> {
+    let mut items = Vec::new();
+    let mut item_start = start + 1;
+    let mut depth = 0;
+
+    let mut i = start + 1;
+    while i < p.len() {
+        match p[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(p[item_start..i].iter().collect());
+                item_start = i + 1;
+            },
+            '}' => {
+                if items.len() > 0 {
+                    items.push(p[item_start..i].iter().collect());
+                    return Some((i, items));
+                }
+                let body: String = p[start + 1..i].iter().collect();
+                return sequence(&body).map(|items| (i, items));
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// `1..5`, `5..1` or `a..e`.
+fn sequence(body: &str) -> Option<Vec<String>> {
+    let mut parts = body.splitn(2, "..");
+    let (from, to) = match (parts.next(), parts.next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return None
+    };
+
+    if let (Ok(from), Ok(to)) = (from.parse::<i64>(), to.parse::<i64>()) {
+        let (lo, hi) = if from <= to { (from, to) } else { (to, from) };
+        match hi.checked_sub(lo) {
+            Some(v) if (v as u64) < MAX_SEQUENCE_LEN => {},
+            _ => return None
+        }
+        return Some(if from <= to {
+            (from..=to).map(|v| v.to_string()).collect()
+        } else {
+            (to..=from).rev().map(|v| v.to_string()).collect()
+        });
+    }
+
+    let mut from_chars = from.chars();
+    let mut to_chars = to.chars();
+    match (from_chars.next(), from_chars.next(), to_chars.next(), to_chars.next()) {
+        (Some(a), None, Some(b), None) if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            let (a, b) = (a as u8, b as u8);
+            Some(if a <= b {
+                (a..=b).map(|v| (v as char).to_string()).collect()
+            } else {
+                (b..=a).rev().map(|v| (v as char).to_string()).collect()
+            })
+        },
+        _ => None
+    }
+}
+
+// Matches `pattern` component by component. Each candidate is kept both as
+// the text to return, which follows the pattern's form, and as the path to
+// look at.
+fn match_paths(pattern: &str, cwd: &Path, options: Options) -> Vec<String> {
+    let dirs_only = pattern.ends_with('/');
+    let mut components: Vec<&str> = pattern.split('/').filter(|v| v.len() > 0).collect();
+    if components.last() == Some(&"**") {
+        components.push("*");
+    }
+
+    let mut candidates: Vec<(String, PathBuf)> = if pattern.starts_with('/') {
+        vec![("/".to_string(), PathBuf::from("/"))]
+    } else {
+        vec![("".to_string(), cwd.to_path_buf())]
+    };
+
+    for component in components {
+        let mut next = Vec::new();
+        for (text, path) in candidates {
+            if component == "**" {
+                descend(text, path, options, &mut next);
+            } else if has_magic(component) {
+                let explicit_dot = component.starts_with('.') || component.starts_with("\\.");
+                for name in read_names(&path) {
+                    if name.starts_with('.') && !explicit_dot && !options.dotglob {
+                        continue;
+                    }
+                    if matches(component, &name) {
+                        next.push((join(&text, &name), path.join(&name)));
+                    }
+                }
+            } else {
+                let name = unescape(component);
+                next.push((join(&text, &name), path.join(&name)));
+            }
+        }
+        candidates = next;
+    }
+
+    let mut ret: Vec<String> = candidates.into_iter()
+        .filter(|&(_, ref path)| if dirs_only {
+            path.is_dir()
+        } else {
+            std::fs::symlink_metadata(path).is_ok()
+        })
+        .map(|(text, _)| if dirs_only { text + "/" } else { text })
+        .collect();
+    ret.sort();
+    ret
+}
+
+// Adds `path` and every directory below it, without following symlinks.
+fn descend(text: String, path: PathBuf, options: Options, out: &mut Vec<(String, PathBuf)>) {
+    if let Ok(entries) = std::fs::read_dir(&path) {
+        let mut dirs: Vec<String> = entries
+            .filter_map(|v| v.ok())
+            .filter(|v| v.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .filter_map(|v| v.file_name().into_string().ok())
+            .filter(|v| options.dotglob || !v.starts_with('.'))
+            .collect();
+        dirs.sort();
+
+        out.push((text.clone(), path.clone()));
+        for name in dirs {
+            let child = path.join(&name);
+            descend(join(&text, &name), child, options, out);
+        }
+    }
+}
+
+fn read_names(path: &Path) -> Vec<String> {
+    let mut ret: Vec<String> = match std::fs::read_dir(path) {
+        Ok(v) => v.filter_map(|v| v.ok())
+            .filter_map(|v| v.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    ret.sort();
+    ret
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.len() == 0 || dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn match_chars(p: &[char], t: &[char]) -> bool {
+    let (mut pi, mut ti) = (0, 0);
+    // Where to resume after the last `*`: its pattern end and the text it
+    // has consumed up to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        let step = match p.get(pi) {
+            Some(&'*') => {
+                star = Some((pi + 1, ti));
+                pi += 1;
+                continue;
+            },
+            Some(&'?') => Some(1),
+            Some(&'[') => match class(&p[pi..], t[ti]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None if t[ti] == '[' => Some(1),
+                None => None
+            },
+            Some(&'\\') if pi + 1 < p.len() => if p[pi + 1] == t[ti] { Some(2) } else { None },
+            Some(&c) => if c == t[ti] { Some(1) } else { None },
+            None => None
+        };
+
+        match (step, star) {
+            (Some(len), _) => {
+                pi += len;
+                ti += 1;
+            },
+            (None, Some((star_end, consumed))) => {
+                // Let the `*` take one more character.
+                pi = star_end;
+                ti = consumed + 1;
+                star = Some((star_end, consumed + 1));
+            },
+            (None, None) => return false
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+// Matches `c` against the bracket expression `p` starts with. Returns
+// whether it matched and the expression's length, or `None` if `p` has no
+// closing `]`.
+fn class(p: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = match p.get(i) {
+        Some(&'!') | Some(&'^') => {
+            i += 1;
+            true
+        },
+        _ => false
+    };
+
+    let mut matched = false;
+    let first = i;
+    loop {
+        let lo = match p.get(i) {
+            Some(&']') if i > first => break,
+            Some(&'\\') if i + 1 < p.len() => {
+                i += 1;
+                p[i]
+            },
+            Some(&v) => v,
+            None => return None
+        };
+        i += 1;
+
+        let hi = match (p.get(i), p.get(i + 1)) {
+            (Some(&'-'), Some(&v)) if v != ']' => {
+                i += 2;
+                v
+            },
+            _ => lo
+        };
+        if lo <= c && c <= hi {
+            matched = true;
+        }
+    }
+
+    Some((matched != negated, i + 1))
+}
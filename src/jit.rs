@@ -1,5 +1,12 @@
 use std;
 use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use llvm_sys;
+use serde_json;
 use std::error::Error;
 use std::os::raw::c_void;
 use engine;
@@ -8,121 +15,281 @@ use cervus::engine::Action;
 use cervus::value_type::ValueType;
 use engine::Operation;
 use signals;
+use var;
 
+pub const JIT_POLICY_ENV: &'static str = "ONESHELL_JIT";
+
+/// When blocks get compiled. A block's own `jit` hint, if present,
+/// overrides the engine-wide policy.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JitPolicy {
+    Off,
+    Eager,
+    AfterCalls(usize)
+}
+
+impl Default for JitPolicy {
+    fn default() -> JitPolicy {
+        JitPolicy::AfterCalls(3)
+    }
+}
+
+impl JitPolicy {
+    /// Accepts `off`, `eager` or a call count.
+    pub fn parse(s: &str) -> Option<JitPolicy> {
+        match s.trim() {
+            "off" => Some(JitPolicy::Off),
+            "eager" => Some(JitPolicy::Eager),
+            v => match v.parse::<usize>() {
+                Ok(n) => Some(JitPolicy::AfterCalls(n)),
+                Err(_) => None
+            }
+        }
+    }
+
+    pub fn from_env() -> Option<JitPolicy> {
+        match std::env::var(JIT_POLICY_ENV) {
+            Ok(v) => JitPolicy::parse(v.as_str()),
+            Err(_) => None
+        }
+    }
+
+    pub fn should_compile(&self, call_count: usize) -> bool {
+        match *self {
+            JitPolicy::Off => false,
+            JitPolicy::Eager => true,
+            JitPolicy::AfterCalls(n) => call_count >= n
+        }
+    }
+}
+
+// Monomorphic inline cache for a `Call` site. Filled by the slow path once
+// the callee's body is compiled; the fast path calls the body's entry
+// directly as long as the target still resolves to the same variable.
+#[repr(C)]
+struct CallSiteCache {
+    callee_relocs: Cell<u64>, // set by `call_site_enter` for the fast path
+    target: RefCell<Option<var::WeakVariable>>,
+    active: RefCell<Vec<(var::Variable, engine::CallDepths)>> // keeps callees alive during fast calls
+}
+
+/// Where generated IR goes when dumping is enabled on an engine.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IrDump {
+    Output, // the engine's output sink
+    File(String) // appended to
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct JitStats {
+    pub blocks_compiled: u64,
+    pub compile_time_ns: u64,
+    pub interpreted_executions: u64,
+    pub jit_executions: u64,
+    pub fallbacks: u64, // compilation failures
+    pub aot_cache_hits: u64
+}
+
+pub type JitEntry = extern fn (*const engine::EngineHandleImpl, *const u64) -> i32;
+
+/// Compiled code for a block. Generated code holds raw pointers into the
+/// block's ops, and `_resources` holds call-site caches referencing
+/// variables of the engine that evaluated it, so the block may only be used
+/// on that engine's thread, or moved together with it as `owned::OwnedEngine`
+/// does.
 pub struct BlockJitInfo {
     _resources: Vec<Box<Any>>,
     _ee: cervus::engine::ExecutionEngine,
-    pub entry: extern fn () -> i32
+    relocs: Vec<u64>,
+    pub entry: JitEntry,
+    pub ir: Option<String>, // unoptimized IR, if requested at build time
+    pub from_cache: bool
+}
+
+impl BlockJitInfo {
+    pub fn call(&self, eh: &engine::EngineHandleImpl) -> i32 {
+        (self.entry)(eh, self.relocs.as_ptr())
+    }
+
+    pub fn relocs_ptr(&self) -> *const u64 {
+        self.relocs.as_ptr()
+    }
+}
+
+/// On-disk cache of optimized modules, as LLVM bitcode. Keys cover the
+/// block's AST, the IR generated for it and the wrapper each relocation
+/// refers to, so entries written by a different build or for different
+/// code generation are never used.
+///
+/// A hit only saves the optimization passes: the IR is still generated,
+/// since the relocation table comes from it, and the cached module is
+/// still compiled to native code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AotCache {
+    pub dir: PathBuf
+}
+
+const AOT_CACHE_MAGIC: &'static str = "ONESHELL-AOT";
+
+// What a cached module must be loaded with.
+#[derive(Clone, Copy)]
+struct AotEntry {
+    key: u64,
+    ast_hash: u64, // checked against key collisions
+    n_relocs: usize
+}
+
+impl AotCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> AotCache {
+        AotCache {
+            dir: dir.into()
+        }
+    }
+
+    fn header(entry: AotEntry) -> String {
+        format!("{} {} {} {:016x}\n", AOT_CACHE_MAGIC, env!("CARGO_PKG_VERSION"), entry.n_relocs, entry.ast_hash)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bc", key))
+    }
+
+    // Entries whose header doesn't match are misses.
+    fn load(&self, entry: AotEntry) -> Option<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::new();
+        match std::fs::File::open(self.path(entry.key)) {
+            Ok(mut f) => if f.read_to_end(&mut data).is_err() {
+                return None;
+            },
+            Err(_) => return None
+        }
+
+        let header = AotCache::header(entry);
+        if data.starts_with(header.as_bytes()) {
+            Some(data.split_off(header.len()))
+        } else {
+            None
+        }
+    }
+
+    fn store(&self, entry: AotEntry, bitcode: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        // Write to a temporary file first so that concurrent readers never
+        // see a partial entry.
+        let tmp_path = self.dir.join(format!("{:016x}.{}.tmp", entry.key, std::process::id()));
+        {
+            let mut f = std::fs::File::create(&tmp_path)?;
+            f.write_all(AotCache::header(entry).as_bytes())?;
+            f.write_all(bitcode)?;
+        }
+        std::fs::rename(&tmp_path, self.path(entry.key))
+    }
+}
+
+// FNV-1a, which unlike `DefaultHasher` is stable across Rust releases.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Every address baked into generated code - wrappers, AST nodes and
+// resources - is loaded from a per-block table passed to `entry` at runtime.
+// This keeps the module position independent, so it can be cached across
+// processes. Emission order is deterministic, so rebuilding the IR for the
+// same AST yields a matching table.
+struct Relocs {
+    table: cervus::engine::Value,
+    values: Vec<u64>,
+    layout: Vec<Option<i64>> // per entry, a wrapper's offset from `fnv1a`
+}
+
+impl Relocs {
+    fn get(&mut self, builder: &cervus::engine::Builder, addr: u64, ty: ValueType) -> cervus::engine::Value {
+        self.entry(builder, addr, ty, None)
+    }
+
+    fn entry(&mut self, builder: &cervus::engine::Builder, addr: u64, ty: ValueType, code_offset: Option<i64>) -> cervus::engine::Value {
+        let index = self.values.len() as u64;
+        self.values.push(addr);
+        self.layout.push(code_offset);
+
+        let p = builder.append(Action::PtrOffset(self.table.clone(), index.into()));
+        let v = builder.append(Action::Load(p));
+        builder.append(Action::IntToPtr(v, ty))
+    }
+
+    fn ptr(&mut self, builder: &cervus::engine::Builder, addr: u64) -> cervus::engine::Value {
+        self.get(builder, addr, void_ptr())
+    }
+
+    fn func(&mut self, builder: &cervus::engine::Builder, f: *const c_void, ret: ValueType, params: Vec<ValueType>) -> cervus::engine::Value {
+        // Wrappers are linked into the same image as `fnv1a`, so their
+        // offsets only change with the build.
+        let offset = (f as i64).wrapping_sub(fnv1a as *const c_void as i64);
+        self.entry(builder, f as u64, ValueType::Pointer(Box::new(
+            ValueType::Function(Box::new(ret), params)
+        )), Some(offset))
+    }
+}
+
+// Identifies generated code and the relocation table it expects, for AOT
+// cache keys.
+fn layout_hash(ir: &str, layout: &[Option<i64>]) -> u64 {
+    fnv1a(format!("{}\n{:?}", ir, layout).as_bytes())
+}
+
+// Opaque handle type. LLVM has no `void*`: the JIT tolerates it, but the
+// bitcode reader rejects it.
+fn void_ptr() -> ValueType {
+    ValueType::Pointer(Box::new(ValueType::Int8))
 }
 
 impl engine::Block {
-    pub fn build_jit(&mut self, eh: &engine::EngineHandleImpl) -> Result<(), Box<Error>> {
+    // The AOT cache entry for the block, given the hash of its generated
+    // code from `layout_hash`.
+    fn aot_entry(&self, layout_hash: u64, n_relocs: usize) -> Result<AotEntry, Box<Error>> {
+        let ast = serde_json::to_string(self)?;
+        Ok(AotEntry {
+            key: fnv1a(format!("{}\n{:016x}\n{}", env!("CARGO_PKG_VERSION"), layout_hash, ast).as_bytes()),
+            ast_hash: fnv1a(ast.as_bytes()),
+            n_relocs: n_relocs
+        })
+    }
+
+    pub fn build_jit(&mut self, dump_ir: bool, aot_cache: Option<&AotCache>) -> Result<(), Box<Error>> {
         let mut resources: Vec<Box<Any>> = Vec::new();
         let m = cervus::engine::Module::new("");
 
         let entry;
+        let relocs;
+        let layout;
 
         {
             let entry_fn = cervus::engine::Function::new(
                 &m,
                 "entry",
                 ValueType::Int32,
-                vec![]
+                vec![
+                    void_ptr(),
+                    ValueType::Pointer(Box::new(ValueType::Int64))
+                ]
             );
+            let eh = entry_fn.get_param(0).unwrap();
+            let mut rt = Relocs {
+                table: entry_fn.get_param(1).unwrap(),
+                values: Vec::new(),
+                layout: Vec::new()
+            };
             let mut bb = cervus::engine::BasicBlock::new(&entry_fn, "");
             let mut new_bb: Option<cervus::engine::BasicBlock> = None;
 
-            let handle_exec_wrapper_fn = cervus::engine::Value::from(handle_exec_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_parallel_exec_wrapper_fn = cervus::engine::Value::from(handle_parallel_exec_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_background_exec_wrapper_fn = cervus::engine::Value::from(handle_background_exec_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_global_assign_fn = cervus::engine::Value::from(handle_global_assign as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_local_assign_fn = cervus::engine::Value::from(handle_local_assign as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_engine_backtrace_wrapper_fn = cervus::engine::Value::from(handle_engine_backtrace_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_print_wrapper_fn = cervus::engine::Value::from(handle_print_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
-            let handle_check_eq_wrapper_fn = cervus::engine::Value::from(handle_check_eq_wrapper as *const c_void as u64)
-                .const_int_to_ptr(ValueType::Pointer(Box::new(
-                    ValueType::Function(
-                        Box::new(ValueType::Void),
-                        vec![
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void)),
-                            ValueType::Pointer(Box::new(ValueType::Void))
-                        ]
-                    )
-                )));
-
             let mut fn_control_status: i32 = signals::OK;
+            let mut slots = TypedSlots::new();
 
             for op in self.ops.iter_mut() {
                 if new_bb.is_some() {
@@ -130,59 +297,46 @@ impl engine::Block {
                 }
                 let builder = cervus::engine::Builder::new(&bb);
 
+                if slots.build_native_op(&eh, &mut rt, &entry_fn, &builder, op, &mut new_bb) {
+                    continue;
+                }
+
+                // Anything else may observe or modify engine state.
+                slots.flush(&eh, &mut rt, &builder);
+
+                let op_handle = &*op as *const Operation as u64;
                 match op {
                     &mut Operation::Exec(ref info) => {
-                        builder.append(
-                            Action::Call(
-                                handle_exec_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(info as *const engine::ExecInfo as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        let f = rt.func(&builder, handle_exec_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+                        let info = rt.ptr(&builder, info as *const engine::ExecInfo as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), info]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
                     },
                     &mut Operation::ParallelExec(ref info) => {
+                        // The wrapper gets a copy, so it can't tell where it is.
+                        build_set_current_op(&eh, &mut rt, &builder, op_handle);
                         let info = Box::new(info.to_vec());
 
-                        builder.append(
-                            Action::Call(
-                                handle_parallel_exec_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(&*info as *const Vec<engine::ExecInfo> as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        let f = rt.func(&builder, handle_parallel_exec_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+                        let info_handle = rt.ptr(&builder, &*info as *const Vec<engine::ExecInfo> as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), info_handle]));
                         resources.push(info as Box<Any>);
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
                     },
                     &mut Operation::BackgroundExec(ref info) => {
-                        builder.append(
-                            Action::Call(
-                                handle_background_exec_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(info as *const engine::ExecInfo as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        let f = rt.func(&builder, handle_background_exec_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+                        let info = rt.ptr(&builder, info as *const engine::ExecInfo as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), info]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
+                    },
+                    &mut Operation::Parallel(ref info) => {
+                        let f = rt.func(&builder, handle_parallel_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+                        let info = rt.ptr(&builder, info as *const engine::ParallelInfo as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), info]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
                     },
                     &mut Operation::IfElse(ref mut if_blk, ref mut else_blk) => {
-                        let last_exit_status_ptr = &eh.borrow().last_exit_status as *const i32;
-                        let last_exit_status_ptr_handle = cervus::engine::Value::from(last_exit_status_ptr as u64)
-                            .const_int_to_ptr(ValueType::Pointer(Box::new(ValueType::Int32)));
+                        let get_last_exit_status_fn = rt.func(&builder, get_last_exit_status_wrapper as *const c_void, ValueType::Int32, vec![void_ptr()]);
 
                         let if_bb = cervus::engine::BasicBlock::new(&entry_fn, "");
                         let else_bb = cervus::engine::BasicBlock::new(&entry_fn, "");
@@ -193,7 +347,7 @@ impl engine::Block {
                                 builder.append(
                                     Action::IntNotEqual(
                                         builder.append(
-                                            Action::Load(last_exit_status_ptr_handle)
+                                            Action::Call(get_last_exit_status_fn, vec![eh.clone()])
                                         ),
                                         (0 as i32).into()
                                     )
@@ -206,7 +360,8 @@ impl engine::Block {
                         {
                             let if_builder = cervus::engine::Builder::new(&if_bb);
                             let cont_1 = build_block_call(
-                                eh,
+                                &eh,
+                                &mut rt,
                                 &entry_fn,
                                 &if_builder,
                                 if_blk,
@@ -219,7 +374,8 @@ impl engine::Block {
                         {
                             let else_builder = cervus::engine::Builder::new(&else_bb);
                             let cont_2 = build_block_call(
-                                eh,
+                                &eh,
+                                &mut rt,
                                 &entry_fn,
                                 &else_builder,
                                 else_blk,
@@ -233,107 +389,81 @@ impl engine::Block {
                     },
                     &mut Operation::Loop(ref mut blk) => {
                         new_bb = Some(build_block_call(
-                            eh,
+                            &eh,
+                            &mut rt,
                             &entry_fn,
                             &builder,
                             blk,
                             true
                         ));
                     },
+                    &mut Operation::ForEach(ref mut info) => {
+                        let f = rt.func(&builder, handle_for_each_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+                        let info = rt.ptr(&builder, info as *mut engine::ForEachInfo as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), info]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
+                    },
                     &mut Operation::Break => {
                         fn_control_status = signals::BREAK;
                         break;
                     },
-                    &mut Operation::AssignGlobal(ref name, ref val) => {
-                        builder.append(
-                            Action::Call(
-                                handle_global_assign_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(name as *const String as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(val as *const engine::ValueSource as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                    &mut Operation::AssignGlobal(ref name, ref val) | &mut Operation::AssignLocal(ref name, ref val) => {
+                        let is_global: i32 = if let Operation::AssignGlobal(..) = *op { 1 } else { 0 };
+                        let f = rt.func(&builder, handle_assign as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), void_ptr()]);
+                        let name = rt.ptr(&builder, name as *const String as u64);
+                        let val = rt.ptr(&builder, val as *const engine::ValueSource as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), is_global.into(), name, val]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
                     },
-                    &mut Operation::AssignLocal(ref name, ref val) => {
-                        builder.append(
-                            Action::Call(
-                                handle_local_assign_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(name as *const String as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(val as *const engine::ValueSource as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                    &mut Operation::Declare(scope, ref name) | &mut Operation::Unset(scope, ref name) => {
+                        let wrapper = if let Operation::Declare(..) = *op { handle_declare } else { handle_unset };
+                        let f = rt.func(&builder, wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr()]);
+                        let name = rt.ptr(&builder, name as *const String as u64);
+                        let is_global = (scope == engine::Scope::Global) as i32;
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), is_global.into(), name]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
+                    },
+                    &mut Operation::SetAttributes(scope, ref name, ref attributes) => {
+                        let f = rt.func(&builder, handle_set_attributes as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), void_ptr()]);
+                        let is_global = (scope == engine::Scope::Global) as i32;
+                        let name = rt.ptr(&builder, name as *const String as u64);
+                        let attributes = rt.ptr(&builder, attributes as *const engine::Attributes as u64);
+                        let ret = builder.append(Action::Call(f, vec![eh.clone(), is_global.into(), name, attributes]));
+                        new_bb = Some(build_status_check(&entry_fn, &builder, &ret));
                     },
                     &mut Operation::EngineBacktrace => {
-                        builder.append(
-                            Action::Call(
-                                handle_engine_backtrace_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        build_set_current_op(&eh, &mut rt, &builder, op_handle);
+                        let f = rt.func(&builder, handle_engine_backtrace_wrapper as *const c_void, ValueType::Void, vec![void_ptr()]);
+                        builder.append(Action::Call(f, vec![eh.clone()]));
                     },
                     &mut Operation::Print(ref src) => {
-                        builder.append(
-                            Action::Call(
-                                handle_print_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(src as *const engine::StringSource as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        let f = rt.func(&builder, handle_print_wrapper as *const c_void, ValueType::Void, vec![void_ptr(), void_ptr()]);
+                        let src = rt.ptr(&builder, src as *const engine::StringSource as u64);
+                        builder.append(Action::Call(f, vec![eh.clone(), src]));
                     },
                     &mut Operation::CheckEq(ref left, ref right) => {
-                        builder.append(
-                            Action::Call(
-                                handle_check_eq_wrapper_fn.clone(),
-                                vec![
-                                    cervus::engine::Value::from(&*eh.borrow() as *const engine::Engine as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(left as *const engine::ValueSource as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    ),
-                                    cervus::engine::Value::from(right as *const engine::ValueSource as u64).const_int_to_ptr(
-                                        ValueType::Pointer(Box::new(ValueType::Void))
-                                    )
-                                ]
-                            )
-                        );
+                        let f = rt.func(&builder, handle_check_eq_wrapper as *const c_void, ValueType::Void, vec![void_ptr(), void_ptr(), void_ptr()]);
+                        let left = rt.ptr(&builder, left as *const engine::ValueSource as u64);
+                        let right = rt.ptr(&builder, right as *const engine::ValueSource as u64);
+                        builder.append(Action::Call(f, vec![eh.clone(), left, right]));
                     },
                     &mut Operation::Call(ref target) => {
+                        let cache = Box::new(CallSiteCache {
+                            callee_relocs: Cell::new(0),
+                            target: RefCell::new(None),
+                            active: RefCell::new(Vec::new())
+                        });
                         new_bb = Some(
                             build_function_call(
-                                eh,
+                                &eh,
+                                &mut rt,
                                 &entry_fn,
                                 &builder,
-                                target
+                                target,
+                                &*cache
                             )
                         );
+                        resources.push(cache as Box<Any>);
                     }
                 }
             }
@@ -343,19 +473,66 @@ impl engine::Block {
             }
             let builder = cervus::engine::Builder::new(&bb);
 
+            slots.flush(&eh, &mut rt, &builder);
             builder.append(Action::Return(fn_control_status.into()));
             entry = entry_fn.to_null_handle();
+            relocs = rt.values;
+            layout = rt.layout;
         }
 
+        let ir = if dump_ir || aot_cache.is_some() {
+            Some(module_ir(&m))
+        } else {
+            None
+        };
+        let aot_entry = match (aot_cache, ir.as_ref()) {
+            (Some(_), Some(ir)) => {
+                Some(self.aot_entry(layout_hash(ir.as_str(), layout.as_slice()), relocs.len())?)
+            },
+            _ => None
+        };
+
+        let cached = match (aot_cache, aot_entry) {
+            (Some(cache), Some(entry)) => cache.load(entry).and_then(|v| cervus::engine::Module::from_bitcode("", v.as_slice())),
+            _ => None
+        };
+        let from_cache = cached.is_some();
+
+        // On a cache hit the module built above is only needed for its
+        // relocation table.
+        let m = match cached {
+            Some(v) => v,
+            None => m
+        };
+        let ir = if dump_ir {
+            ir
+        } else {
+            None
+        };
+
         let ee = cervus::engine::ExecutionEngine::new(m);
-        ee.prepare();
+        if !from_cache {
+            ee.prepare();
+
+            if let (Some(cache), Some(entry)) = (aot_cache, aot_entry) {
+                cache.store(entry, module_bitcode(ee.get_module()).as_slice())?;
+            }
+        }
 
-        let entry = ee.get_callable_0::<i32>(&entry);
+        // get_callable_* only accepts primitive parameter types.
+        let entry = unsafe {
+            std::mem::transmute::<*const c_void, JitEntry>(
+                ee.get_raw_callable(&entry)
+            )
+        };
 
         let jit_info = BlockJitInfo {
             _resources: resources,
             _ee: ee,
-            entry: entry
+            relocs: relocs,
+            entry: entry,
+            ir: ir,
+            from_cache: from_cache
         };
 
         self.jit_info = Some(jit_info);
@@ -363,6 +540,389 @@ impl engine::Block {
     }
 }
 
+// LLVM calls made outside of cervus take its lock too, since branches of
+// `Parallel` ops compile on several threads.
+fn module_ir(m: &cervus::engine::Module) -> String {
+    let _llvm = cervus::engine::GLOBAL_LLVM_LOCK.lock().unwrap();
+    unsafe {
+        let raw = llvm_sys::core::LLVMPrintModuleToString(*m._ref.borrow());
+        let ret = CStr::from_ptr(raw).to_string_lossy().into_owned();
+        llvm_sys::core::LLVMDisposeMessage(raw);
+        ret
+    }
+}
+
+fn module_bitcode(m: &cervus::engine::Module) -> Vec<u8> {
+    let _llvm = cervus::engine::GLOBAL_LLVM_LOCK.lock().unwrap();
+    unsafe {
+        let buf = llvm_sys::bit_writer::LLVMWriteBitcodeToMemoryBuffer(*m._ref.borrow());
+        let ret = std::slice::from_raw_parts(
+            llvm_sys::core::LLVMGetBufferStart(buf) as *const u8,
+            llvm_sys::core::LLVMGetBufferSize(buf)
+        ).to_vec();
+        llvm_sys::core::LLVMDisposeMemoryBuffer(buf);
+        ret
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum SlotType {
+    Int,
+    Float
+}
+
+struct TypedSlot {
+    name: *const String, // owned by the block's ops
+    ty: SlotType,
+    value: cervus::engine::Value
+}
+
+// Variables assigned statically typed values are kept in SSA values instead
+// of the engine until the next op that may observe them. Slots never live
+// across basic blocks: every op that creates one flushes them first.
+//
+// Which variable a local name refers to depends on declarations, which
+// may even be made outside the block, so a name never has a global and a
+// local slot at once: the two could be the same variable. Declarations
+// always flush, so one slot per name stays consistent with the engine.
+struct TypedSlots {
+    globals: HashMap<String, TypedSlot>,
+    locals: HashMap<String, TypedSlot>,
+    last_exit_status: Option<cervus::engine::Value>
+}
+
+impl TypedSlots {
+    fn new() -> TypedSlots {
+        TypedSlots {
+            globals: HashMap::new(),
+            locals: HashMap::new(),
+            last_exit_status: None
+        }
+    }
+
+    fn static_type(&self, src: &engine::ValueSource) -> Option<SlotType> {
+        self.typed(src, None)
+    }
+
+    // With a `guess`, variables without a slot are assumed to hold values
+    // of that type, which guarded ops check at runtime.
+    fn typed(&self, src: &engine::ValueSource, guess: Option<SlotType>) -> Option<SlotType> {
+        use engine::ValueSource;
+
+        match *src {
+            ValueSource::Plain(var::Value::Integer(_)) => Some(SlotType::Int),
+            ValueSource::Plain(var::Value::Float(_)) => Some(SlotType::Float),
+            ValueSource::GlobalVariable(ref name) => self.globals.get(name).map(|v| v.ty).or(guess),
+            ValueSource::LocalVariable(ref name) => self.locals.get(name).map(|v| v.ty).or(guess),
+            ValueSource::LastExitStatus => Some(SlotType::Int),
+            ValueSource::Arith(op, ref left, ref right) => match (self.typed(left, guess), self.typed(right, guess)) {
+                (Some(SlotType::Int), Some(SlotType::Int)) => match op {
+                    // May fail at runtime on a zero divisor.
+                    engine::ArithOp::Div | engine::ArithOp::Rem => None,
+                    _ => Some(SlotType::Int)
+                },
+                (Some(SlotType::Float), Some(SlotType::Float)) => Some(SlotType::Float),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // Only valid after `typed` returned `Some` for `src` and `guess`.
+    fn build_value(
+        &self,
+        eh: &cervus::engine::Value,
+        rt: &mut Relocs,
+        builder: &cervus::engine::Builder,
+        src: &engine::ValueSource,
+        guess: Option<SlotType>
+    ) -> cervus::engine::Value {
+        use engine::ValueSource;
+
+        match *src {
+            ValueSource::Plain(var::Value::Integer(v)) => v.into(),
+            ValueSource::Plain(var::Value::Float(v)) => v.into(),
+            ValueSource::GlobalVariable(ref name) | ValueSource::LocalVariable(ref name) => {
+                let (is_global, slot) = match *src {
+                    ValueSource::GlobalVariable(_) => (true, self.globals.get(name)),
+                    _ => (false, self.locals.get(name))
+                };
+                if let Some(slot) = slot {
+                    return slot.value.clone();
+                }
+
+                // Unslotted reads only happen in guarded ops.
+                let (f, value_type) = match guess.unwrap() {
+                    SlotType::Int => (load_int_wrapper as *const c_void, ValueType::Int64),
+                    SlotType::Float => (load_float_wrapper as *const c_void, ValueType::Float64)
+                };
+                let f = rt.func(builder, f, value_type, vec![void_ptr(), ValueType::Int32, void_ptr()]);
+                let name = rt.ptr(builder, name as *const String as u64);
+                builder.append(Action::Call(f, vec![eh.clone(), (is_global as i32).into(), name]))
+            },
+            ValueSource::LastExitStatus => {
+                let status = match self.last_exit_status {
+                    Some(ref v) => v.clone(),
+                    None => {
+                        let f = rt.func(builder, get_last_exit_status_wrapper as *const c_void, ValueType::Int32, vec![void_ptr()]);
+                        builder.append(Action::Call(f, vec![eh.clone()]))
+                    }
+                };
+                builder.append(Action::IntCast(status, ValueType::Int64))
+            },
+            ValueSource::Arith(op, ref left, ref right) => {
+                let ty = self.typed(left, guess).unwrap();
+                let left = self.build_value(eh, rt, builder, left, guess);
+                let right = self.build_value(eh, rt, builder, right, guess);
+                builder.append(match (ty, op) {
+                    (SlotType::Int, engine::ArithOp::Add) => Action::IntAdd(left, right),
+                    (SlotType::Int, engine::ArithOp::Sub) => Action::IntSub(left, right),
+                    (SlotType::Int, engine::ArithOp::Mul) => Action::IntMul(left, right),
+                    (SlotType::Int, _) => unreachable!(),
+                    (SlotType::Float, engine::ArithOp::Add) => Action::FloatAdd(left, right),
+                    (SlotType::Float, engine::ArithOp::Sub) => Action::FloatSub(left, right),
+                    (SlotType::Float, engine::ArithOp::Mul) => Action::FloatMul(left, right),
+                    (SlotType::Float, engine::ArithOp::Div) => Action::FloatDiv(left, right),
+                    (SlotType::Float, engine::ArithOp::Rem) => Action::FloatRem(left, right)
+                })
+            },
+            _ => unreachable!()
+        }
+    }
+
+    /// Emits native code for `op` if all values involved are statically
+    /// typed. Returns false if `op` needs the dynamic path. Code for later
+    /// ops goes to `next_bb` if this sets it.
+    fn build_native_op<'a>(
+        &mut self,
+        eh: &cervus::engine::Value,
+        rt: &mut Relocs,
+        f: &'a cervus::engine::Function,
+        builder: &cervus::engine::Builder,
+        op: &Operation,
+        next_bb: &mut Option<cervus::engine::BasicBlock<'a>>
+    ) -> bool {
+        match *op {
+            Operation::AssignGlobal(ref name, ref val) | Operation::AssignLocal(ref name, ref val) => {
+                let aliased = match *op {
+                    Operation::AssignGlobal(..) => self.locals.contains_key(name),
+                    _ => self.globals.contains_key(name)
+                };
+                if aliased {
+                    return false;
+                }
+                let ty = match self.static_type(val) {
+                    Some(v) => v,
+                    None => return self.build_guarded_assign(eh, rt, f, builder, op, next_bb)
+                };
+                let slot = TypedSlot {
+                    name: name as *const String,
+                    ty: ty,
+                    value: self.build_value(eh, rt, builder, val, None)
+                };
+
+                // Attributes can't change until the next flush, so checking
+                // them here is enough. On failure, earlier assignments are
+                // written back before returning the exception.
+                let is_global: i32 = if let Operation::AssignGlobal(..) = *op { 1 } else { 0 };
+                let check_fn = rt.func(builder, check_assign_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), ValueType::Int32]);
+                let name_handle = rt.ptr(builder, name as *const String as u64);
+                let ret = builder.append(Action::Call(check_fn, vec![
+                    eh.clone(),
+                    is_global.into(),
+                    name_handle,
+                    ((ty == SlotType::Float) as i32).into()
+                ]));
+
+                let cont_bb = cervus::engine::BasicBlock::new(f, "");
+                let fail_bb = cervus::engine::BasicBlock::new(f, "");
+                builder.append(Action::ConditionalBranch(
+                    builder.append(Action::IntEqual(ret.clone(), signals::OK.into())),
+                    &cont_bb,
+                    &fail_bb
+                ));
+                {
+                    let fail_builder = cervus::engine::Builder::new(&fail_bb);
+                    self.build_stores(eh, rt, &fail_builder);
+                    fail_builder.append(Action::Return(ret));
+                }
+                *next_bb = Some(cont_bb);
+
+                if let Operation::AssignGlobal(..) = *op {
+                    self.globals.insert(name.clone(), slot);
+                } else {
+                    self.locals.insert(name.clone(), slot);
+                }
+                true
+            },
+            Operation::CheckEq(ref left, ref right) => {
+                // cervus has no float comparisons.
+                match (self.static_type(left), self.static_type(right)) {
+                    (Some(SlotType::Int), Some(SlotType::Int)) => {},
+                    _ => return false
+                }
+                let left = self.build_value(eh, rt, builder, left, None);
+                let right = self.build_value(eh, rt, builder, right, None);
+                let eq = builder.append(Action::IntEqual(left, right));
+
+                // IntCast sign-extends i1, so mask the result down to 0/1.
+                let eq = builder.append(Action::IntCast(eq, ValueType::Int32));
+                self.last_exit_status = Some(builder.append(Action::And(eq, (1 as i32).into())));
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Emits an assignment of arithmetic on variables without slots, like
+    /// a loop counter read at the top of the loop body. The variables'
+    /// types are checked at runtime: if they hold the guessed type, the
+    /// value is computed natively and stored right away, otherwise the
+    /// dynamic path runs. Either way, no slot is kept, as the two paths
+    /// can't share SSA values.
+    fn build_guarded_assign<'a>(
+        &mut self,
+        eh: &cervus::engine::Value,
+        rt: &mut Relocs,
+        f: &'a cervus::engine::Function,
+        builder: &cervus::engine::Builder,
+        op: &Operation,
+        next_bb: &mut Option<cervus::engine::BasicBlock<'a>>
+    ) -> bool {
+        let (is_global, name, val) = match *op {
+            Operation::AssignGlobal(ref name, ref val) => (true, name, val),
+            Operation::AssignLocal(ref name, ref val) => (false, name, val),
+            _ => unreachable!()
+        };
+
+        // Slots may alias the variables read, so the engine has to be
+        // up to date before checking them. This also leaves all reads to
+        // the guards.
+        self.flush(eh, rt, builder);
+
+        let (guess, ty) = match [SlotType::Int, SlotType::Float].iter()
+            .filter_map(|&g| self.typed(val, Some(g)).map(|t| (g, t)))
+            .next() {
+            Some(v) => v,
+            None => return false
+        };
+
+        let mut reads = Vec::new();
+        guarded_reads(val, &mut reads);
+        let guard_fn = rt.func(builder, guard_type_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), ValueType::Int32]);
+        let mut all_ok: cervus::engine::Value = 1i32.into();
+        for (read_global, read_name) in reads {
+            let read_name = rt.ptr(builder, read_name as *const String as u64);
+            let ok = builder.append(Action::Call(guard_fn.clone(), vec![
+                eh.clone(),
+                (read_global as i32).into(),
+                read_name,
+                ((guess == SlotType::Float) as i32).into()
+            ]));
+            all_ok = builder.append(Action::And(all_ok, ok));
+        }
+
+        let native_bb = cervus::engine::BasicBlock::new(f, "");
+        let dynamic_bb = cervus::engine::BasicBlock::new(f, "");
+        let cont_bb = cervus::engine::BasicBlock::new(f, "");
+        builder.append(Action::ConditionalBranch(
+            builder.append(Action::IntEqual(all_ok, 1i32.into())),
+            &native_bb,
+            &dynamic_bb
+        ));
+
+        {
+            let native_builder = cervus::engine::Builder::new(&native_bb);
+            let value = self.build_value(eh, rt, &native_builder, val, Some(guess));
+
+            let check_fn = rt.func(&native_builder, check_assign_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), ValueType::Int32]);
+            let name_handle = rt.ptr(&native_builder, name as *const String as u64);
+            let ret = native_builder.append(Action::Call(check_fn, vec![
+                eh.clone(),
+                (is_global as i32).into(),
+                name_handle.clone(),
+                ((ty == SlotType::Float) as i32).into()
+            ]));
+
+            let store_bb = build_status_check(f, &native_builder, &ret);
+            let store_builder = cervus::engine::Builder::new(&store_bb);
+            let (store_fn, value_type) = match (is_global, ty) {
+                (true, SlotType::Int) => (store_global_int as *const c_void, ValueType::Int64),
+                (true, SlotType::Float) => (store_global_float as *const c_void, ValueType::Float64),
+                (false, SlotType::Int) => (store_local_int as *const c_void, ValueType::Int64),
+                (false, SlotType::Float) => (store_local_float as *const c_void, ValueType::Float64)
+            };
+            let store_fn = rt.func(&store_builder, store_fn, ValueType::Void, vec![void_ptr(), void_ptr(), value_type]);
+            store_builder.append(Action::Call(store_fn, vec![eh.clone(), name_handle, value]));
+            store_builder.append(Action::Branch(&cont_bb));
+        }
+        {
+            let dynamic_builder = cervus::engine::Builder::new(&dynamic_bb);
+            let assign_fn = rt.func(&dynamic_builder, handle_assign as *const c_void, ValueType::Int32, vec![void_ptr(), ValueType::Int32, void_ptr(), void_ptr()]);
+            let name_handle = rt.ptr(&dynamic_builder, name as *const String as u64);
+            let val = rt.ptr(&dynamic_builder, val as *const engine::ValueSource as u64);
+            let ret = dynamic_builder.append(Action::Call(assign_fn, vec![eh.clone(), (is_global as i32).into(), name_handle, val]));
+            let done_bb = build_status_check(f, &dynamic_builder, &ret);
+            cervus::engine::Builder::new(&done_bb).append(Action::Branch(&cont_bb));
+        }
+        *next_bb = Some(cont_bb);
+        true
+    }
+
+    /// Writes all slots back to the engine and forgets them.
+    fn flush(&mut self, eh: &cervus::engine::Value, rt: &mut Relocs, builder: &cervus::engine::Builder) {
+        self.build_stores(eh, rt, builder);
+        self.globals.clear();
+        self.locals.clear();
+        self.last_exit_status = None;
+    }
+
+    fn build_stores(&self, eh: &cervus::engine::Value, rt: &mut Relocs, builder: &cervus::engine::Builder) {
+        // Sorted so that emission, and with it the relocation table, is
+        // deterministic.
+        let mut slots: Vec<(bool, &TypedSlot)> = self.globals.values().map(|v| (false, v))
+            .chain(self.locals.values().map(|v| (true, v)))
+            .collect();
+        slots.sort_by_key(|&(is_local, slot)| (is_local, unsafe { &*slot.name }.clone()));
+
+        for (is_local, slot) in slots {
+            let (f, value_type) = match (is_local, slot.ty) {
+                (false, SlotType::Int) => (store_global_int as *const c_void, ValueType::Int64),
+                (false, SlotType::Float) => (store_global_float as *const c_void, ValueType::Float64),
+                (true, SlotType::Int) => (store_local_int as *const c_void, ValueType::Int64),
+                (true, SlotType::Float) => (store_local_float as *const c_void, ValueType::Float64)
+            };
+            let f = rt.func(builder, f, ValueType::Void, vec![void_ptr(), void_ptr(), value_type]);
+            let name = rt.ptr(builder, slot.name as u64);
+            builder.append(Action::Call(f, vec![eh.clone(), name, slot.value.clone()]));
+        }
+
+        if let Some(ref status) = self.last_exit_status {
+            let f = rt.func(builder, set_last_exit_status_wrapper as *const c_void, ValueType::Void, vec![void_ptr(), ValueType::Int32]);
+            builder.append(Action::Call(f, vec![eh.clone(), status.clone()]));
+        }
+    }
+}
+
+// Variables read by `src`, in order and with their scope, for guards.
+fn guarded_reads<'a>(src: &'a engine::ValueSource, out: &mut Vec<(bool, &'a String)>) {
+    use engine::ValueSource;
+
+    let read = match *src {
+        ValueSource::GlobalVariable(ref name) => (true, name),
+        ValueSource::LocalVariable(ref name) => (false, name),
+        ValueSource::Arith(_, ref left, ref right) => {
+            guarded_reads(left, out);
+            guarded_reads(right, out);
+            return;
+        },
+        _ => return
+    };
+    if !out.contains(&read) {
+        out.push(read);
+    }
+}
+
 fn build_final_check<'a>(
     f: &'a cervus::engine::Function,
     val: &cervus::engine::Value,
@@ -388,47 +948,128 @@ fn build_final_check<'a>(
     final_check_bb
 }
 
+fn build_status_check<'a>(
+    f: &'a cervus::engine::Function,
+    builder: &cervus::engine::Builder,
+    ret: &cervus::engine::Value
+) -> cervus::engine::BasicBlock<'a> {
+    let cont_bb = cervus::engine::BasicBlock::new(f, "");
+
+    let fc_bb = build_final_check(f, ret, &cont_bb);
+    builder.append(Action::Branch(&fc_bb));
+
+    cont_bb
+}
+
+fn build_set_current_op(eh: &cervus::engine::Value, rt: &mut Relocs, builder: &cervus::engine::Builder, op: u64) {
+    let f = rt.func(builder, set_current_op_wrapper as *const c_void, ValueType::Void, vec![void_ptr(), void_ptr()]);
+    let op = rt.ptr(builder, op);
+    builder.append(Action::Call(f, vec![eh.clone(), op]));
+}
+
 fn build_function_call<'a>(
-    eh: &engine::EngineHandleImpl,
+    eh: &cervus::engine::Value,
+    rt: &mut Relocs,
     f: &'a cervus::engine::Function,
     builder: &cervus::engine::Builder,
-    target: &engine::ValueSource
+    target: &engine::ValueSource,
+    cache: &CallSiteCache
 ) -> cervus::engine::BasicBlock<'a> {
-    let wrapper_fn = cervus::engine::Value::from(call_function_wrapper as *const c_void as u64)
-        .const_int_to_ptr(ValueType::Pointer(Box::new(
-            ValueType::Function(
-                Box::new(ValueType::Int32),
-                vec![
-                    ValueType::Pointer(Box::new(ValueType::Void)),
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                ]
-            )
-        )));
+    let enter_fn = rt.func(builder, call_site_enter as *const c_void, ValueType::Int64, vec![void_ptr(), void_ptr(), void_ptr()]);
+    let target_handle = rt.ptr(builder, target as *const engine::ValueSource as u64);
+    let cache_handle = rt.ptr(builder, cache as *const CallSiteCache as u64);
 
-    let ret = builder.append(
+    let entry = builder.append(
         Action::Call(
-            wrapper_fn,
+            enter_fn,
             vec![
-                cervus::engine::Value::from(eh as *const engine::EngineHandleImpl as u64).const_int_to_ptr(
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                ),
-                cervus::engine::Value::from(target as *const engine::ValueSource as u64).const_int_to_ptr(
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                )
+                eh.clone(),
+                target_handle.clone(),
+                cache_handle.clone()
             ]
         )
     );
 
+    let fast_bb = cervus::engine::BasicBlock::new(f, "");
+    let slow_bb = cervus::engine::BasicBlock::new(f, "");
     let cont_bb = cervus::engine::BasicBlock::new(f, "");
 
-    let fc_bb = build_final_check(f, &ret, &cont_bb);
-    builder.append(Action::Branch(&fc_bb));
+    builder.append(
+        Action::ConditionalBranch(
+            builder.append(Action::IntNotEqual(entry.clone(), (0 as u64).into())),
+            &fast_bb,
+            &slow_bb
+        )
+    );
+
+    {
+        let fast_builder = cervus::engine::Builder::new(&fast_bb);
+        let callee = fast_builder.append(
+            Action::IntToPtr(
+                entry,
+                ValueType::Pointer(Box::new(
+                    ValueType::Function(
+                        Box::new(ValueType::Int32),
+                        vec![
+                            void_ptr(),
+                            ValueType::Pointer(Box::new(ValueType::Int64))
+                        ]
+                    )
+                ))
+            )
+        );
+
+        // `callee_relocs` is the first field of the `#[repr(C)]` cache.
+        let callee_relocs = rt.get(&fast_builder, cache as *const CallSiteCache as u64, ValueType::Pointer(Box::new(ValueType::Int64)));
+        let callee_relocs = fast_builder.append(
+            Action::IntToPtr(
+                fast_builder.append(Action::Load(callee_relocs)),
+                ValueType::Pointer(Box::new(ValueType::Int64))
+            )
+        );
+
+        let ret = fast_builder.append(Action::Call(callee, vec![eh.clone(), callee_relocs]));
+        let leave_fn = rt.func(&fast_builder, call_site_leave as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr(), ValueType::Int32]);
+        let cache_handle = rt.ptr(&fast_builder, cache as *const CallSiteCache as u64);
+        let ret = fast_builder.append(
+            Action::Call(
+                leave_fn,
+                vec![
+                    eh.clone(),
+                    cache_handle,
+                    ret
+                ]
+            )
+        );
+        let fc_bb = build_final_check(f, &ret, &cont_bb);
+        fast_builder.append(Action::Branch(&fc_bb));
+    }
+
+    {
+        let slow_builder = cervus::engine::Builder::new(&slow_bb);
+        let wrapper_fn = rt.func(&slow_builder, call_function_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr(), void_ptr()]);
+        let target_handle = rt.ptr(&slow_builder, target as *const engine::ValueSource as u64);
+        let cache_handle = rt.ptr(&slow_builder, cache as *const CallSiteCache as u64);
+        let ret = slow_builder.append(
+            Action::Call(
+                wrapper_fn,
+                vec![
+                    eh.clone(),
+                    target_handle,
+                    cache_handle
+                ]
+            )
+        );
+        let fc_bb = build_final_check(f, &ret, &cont_bb);
+        slow_builder.append(Action::Branch(&fc_bb));
+    }
 
     cont_bb
 }
 
 fn build_block_call<'a>(
-    eh: &engine::EngineHandleImpl,
+    eh: &cervus::engine::Value,
+    rt: &mut Relocs,
     f: &'a cervus::engine::Function,
     parent_builder: &cervus::engine::Builder,
     blk: &mut engine::Block,
@@ -436,31 +1077,32 @@ fn build_block_call<'a>(
 ) -> cervus::engine::BasicBlock<'a> {
     let cont_bb = cervus::engine::BasicBlock::new(f, "");
 
-    let call_block_wrapper_fn = cervus::engine::Value::from(call_block_wrapper as *const c_void as u64)
-        .const_int_to_ptr(ValueType::Pointer(Box::new(
-            ValueType::Function(
-                Box::new(ValueType::Int32),
-                vec![
-                    ValueType::Pointer(Box::new(ValueType::Void)),
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                ]
-            )
-        )));
+    let head_bb = cervus::engine::BasicBlock::new(f, "");
+    let head_builder = cervus::engine::Builder::new(&head_bb);
+    parent_builder.append(Action::Branch(&head_bb));
 
-    let bb = cervus::engine::BasicBlock::new(f, "");
+    // Natively compiled bodies may never reach the engine, so loops check
+    // for interrupts on every iteration themselves.
+    let bb = if is_loop {
+        let check_fn = rt.func(&head_builder, check_interrupt_wrapper as *const c_void, ValueType::Int32, vec![void_ptr()]);
+        let ret = head_builder.append(Action::Call(check_fn, vec![eh.clone()]));
+        build_status_check(f, &head_builder, &ret)
+    } else {
+        let bb = cervus::engine::BasicBlock::new(f, "");
+        head_builder.append(Action::Branch(&bb));
+        bb
+    };
     let builder = cervus::engine::Builder::new(&bb);
-    parent_builder.append(Action::Branch(&bb));
+
+    let call_block_wrapper_fn = rt.func(&builder, call_block_wrapper as *const c_void, ValueType::Int32, vec![void_ptr(), void_ptr()]);
+    let blk_handle = rt.ptr(&builder, blk as *const engine::Block as u64);
 
     let ret = builder.append(
         Action::Call(
-            call_block_wrapper_fn.clone(),
+            call_block_wrapper_fn,
             vec![
-                cervus::engine::Value::from(eh as *const engine::EngineHandleImpl as u64).const_int_to_ptr(
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                ),
-                cervus::engine::Value::from(blk as *const engine::Block as u64).const_int_to_ptr(
-                    ValueType::Pointer(Box::new(ValueType::Void))
-                ),
+                eh.clone(),
+                blk_handle
             ]
         )
     );
@@ -483,14 +1125,14 @@ fn build_block_call<'a>(
         check_continue_builder.append(
             Action::ConditionalBranch(
                 check_continue_builder.append(Action::IntEqual(ret.clone(), signals::CONTINUE.into())),
-                &bb,
+                &head_bb,
                 &check_ok_bb
             )
         );
         check_ok_builder.append(
             Action::ConditionalBranch(
                 check_ok_builder.append(Action::IntEqual(ret.clone(), signals::OK.into())),
-                &bb,
+                &head_bb,
                 &final_check_bb
             )
         );
@@ -501,59 +1143,232 @@ fn build_block_call<'a>(
     cont_bb
 }
 
-extern "C" fn handle_exec_wrapper(eng: &mut engine::Engine, info: &engine::ExecInfo) {
-    eng.handle_exec(info).unwrap();
+// Wrappers handed a part of their op record it as the current op, so
+// backtraces can place them.
+
+extern "C" fn handle_exec_wrapper(eng: &engine::EngineHandleImpl, info: &engine::ExecInfo) -> i32 {
+    eng.borrow_mut().set_current_op(info as *const engine::ExecInfo as *const u8);
+    let ret = eng.borrow_mut().handle_exec(info);
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn handle_background_exec_wrapper(eng: &mut engine::Engine, info: &engine::ExecInfo) {
-    eng.handle_background_exec(info).unwrap();
+extern "C" fn handle_background_exec_wrapper(eng: &engine::EngineHandleImpl, info: &engine::ExecInfo) -> i32 {
+    eng.borrow_mut().set_current_op(info as *const engine::ExecInfo as *const u8);
+    let ret = eng.borrow_mut().handle_background_exec(info);
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn handle_parallel_exec_wrapper(eng: &mut engine::Engine, info: &Vec<engine::ExecInfo>) {
-    eng.handle_parallel_exec(info.as_slice()).unwrap();
+extern "C" fn handle_parallel_wrapper(eng: &engine::EngineHandleImpl, info: &engine::ParallelInfo) -> i32 {
+    eng.borrow_mut().set_current_op(info as *const engine::ParallelInfo as *const u8);
+    let ret = eng.borrow_mut().handle_parallel(info);
+    eng.borrow().check_result(ret)
+}
+
+extern "C" fn set_current_op_wrapper(eng: &engine::EngineHandleImpl, op: &engine::Operation) {
+    eng.borrow_mut().set_current_op(op as *const engine::Operation as *const u8);
+}
+
+extern "C" fn handle_parallel_exec_wrapper(eng: &engine::EngineHandleImpl, info: &Vec<engine::ExecInfo>) -> i32 {
+    let ret = eng.borrow_mut().handle_parallel_exec(info.as_slice());
+    eng.borrow().check_result(ret)
+}
+
+extern "C" fn handle_for_each_wrapper(eng: &engine::EngineHandleImpl, info: &mut engine::ForEachInfo) -> i32 {
+    eng.borrow_mut().set_current_op(info as *const engine::ForEachInfo as *const u8);
+    eng.eval_for_each(info)
 }
 
 extern "C" fn call_block_wrapper(eng: &engine::EngineHandleImpl, blk: &mut engine::Block) -> i32 {
     eng.eval_block(blk)
 }
 
-extern "C" fn handle_global_assign(eng: &mut engine::Engine, name: &String, val: &engine::ValueSource) {
-    let v = val.fetch(eng).unwrap();
-    eng.vars.insert(
-        name.clone(),
-        v
-    );
+extern "C" fn get_last_exit_status_wrapper(eng: &engine::EngineHandleImpl) -> i32 {
+    eng.borrow().last_exit_status
 }
 
-extern "C" fn handle_local_assign(eng: &mut engine::Engine, name: &String, val: &engine::ValueSource) {
-    let v = val.fetch(eng).unwrap();
-    eng.call_stack.last_mut().unwrap().vars.insert(
-        name.clone(),
-        v
-    );
+extern "C" fn set_last_exit_status_wrapper(eng: &engine::EngineHandleImpl, status: i32) {
+    eng.borrow_mut().last_exit_status = status;
+}
+
+fn scope_of(is_global: i32) -> engine::Scope {
+    if is_global != 0 {
+        engine::Scope::Global
+    } else {
+        engine::Scope::Local
+    }
+}
+
+// Guarded ops only load variables after `guard_type_wrapper` accepted
+// them.
+
+fn guarded_value<T, F: Fn(&var::Value) -> Option<T>>(eng: &engine::EngineHandleImpl, is_global: i32, name: &str, f: F) -> Option<T> {
+    let eng = eng.borrow();
+    let v = if is_global != 0 { eng.vars.get(name) } else { eng.local(name) };
+    v.and_then(|v| f(&v.impl_ref().value))
+}
+
+extern "C" fn guard_type_wrapper(eng: &engine::EngineHandleImpl, is_global: i32, name: &String, is_float: i32) -> i32 {
+    guarded_value(eng, is_global, name, |v| match (v, is_float != 0) {
+        (&var::Value::Integer(_), false) | (&var::Value::Float(_), true) => Some(1),
+        _ => None
+    }).unwrap_or(0)
+}
+
+extern "C" fn load_int_wrapper(eng: &engine::EngineHandleImpl, is_global: i32, name: &String) -> i64 {
+    guarded_value(eng, is_global, name, |v| match *v {
+        var::Value::Integer(v) => Some(v),
+        _ => None
+    }).unwrap()
+}
+
+extern "C" fn load_float_wrapper(eng: &engine::EngineHandleImpl, is_global: i32, name: &String) -> f64 {
+    guarded_value(eng, is_global, name, |v| match *v {
+        var::Value::Float(v) => Some(v),
+        _ => None
+    }).unwrap()
+}
+
+// Slots are stored after `check_assign_wrapper` accepted them.
+
+extern "C" fn store_global_int(eng: &engine::EngineHandleImpl, name: &String, v: i64) {
+    eng.borrow_mut().assign_unchecked(engine::Scope::Global, name, var::Variable::from_value(var::Value::Integer(v)));
+}
+
+extern "C" fn store_global_float(eng: &engine::EngineHandleImpl, name: &String, v: f64) {
+    eng.borrow_mut().assign_unchecked(engine::Scope::Global, name, var::Variable::from_value(var::Value::Float(v)));
+}
+
+extern "C" fn store_local_int(eng: &engine::EngineHandleImpl, name: &String, v: i64) {
+    eng.borrow_mut().assign_unchecked(engine::Scope::Local, name, var::Variable::from_value(var::Value::Integer(v)));
+}
+
+extern "C" fn store_local_float(eng: &engine::EngineHandleImpl, name: &String, v: f64) {
+    eng.borrow_mut().assign_unchecked(engine::Scope::Local, name, var::Variable::from_value(var::Value::Float(v)));
+}
+
+extern "C" fn check_assign_wrapper(eng: &engine::EngineHandleImpl, is_global: i32, name: &String, is_float: i32) -> i32 {
+    eng.borrow_mut().set_current_op(name as *const String as *const u8);
+    let ty = if is_float != 0 { var::Type::Float } else { var::Type::Integer };
+    let ret = eng.borrow().check_assign(scope_of(is_global), name, Some(ty));
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn handle_engine_backtrace_wrapper(eng: &engine::Engine) {
-    eng.handle_engine_backtrace();
+extern "C" fn handle_assign(eng: &engine::EngineHandleImpl, is_global: i32, name: &String, val: &engine::ValueSource) -> i32 {
+    eng.borrow_mut().set_current_op(name as *const String as *const u8);
+    let ret = eng.borrow_mut().assign_source(scope_of(is_global), name, val);
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn handle_print_wrapper(eng: &engine::Engine, src: &engine::StringSource) {
-    eng.handle_print(src);
+extern "C" fn handle_declare(eng: &engine::EngineHandleImpl, is_global: i32, name: &String) -> i32 {
+    eng.borrow_mut().set_current_op(name as *const String as *const u8);
+    let ret = eng.borrow_mut().declare(scope_of(is_global), name);
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn handle_check_eq_wrapper(eng: &mut engine::Engine, left: &engine::ValueSource, right: &engine::ValueSource) {
-    eng.handle_check_eq(left, right);
+extern "C" fn handle_unset(eng: &engine::EngineHandleImpl, is_global: i32, name: &String) -> i32 {
+    eng.borrow_mut().set_current_op(name as *const String as *const u8);
+    let ret = eng.borrow_mut().unset(scope_of(is_global), name);
+    eng.borrow().check_result(ret)
 }
 
-extern "C" fn call_function_wrapper(eng: &engine::EngineHandleImpl, target: &engine::ValueSource) -> i32 {
+extern "C" fn handle_set_attributes(eng: &engine::EngineHandleImpl, is_global: i32, name: &String, attributes: &engine::Attributes) -> i32 {
+    eng.borrow_mut().set_current_op(name as *const String as *const u8);
+    let ret = eng.borrow_mut().set_attributes(scope_of(is_global), name, attributes.clone());
+    eng.borrow().check_result(ret)
+}
+
+extern "C" fn check_interrupt_wrapper(eng: &engine::EngineHandleImpl) -> i32 {
+    let ret = eng.borrow().check_interrupt();
+    eng.borrow().check_result(ret)
+}
+
+extern "C" fn handle_engine_backtrace_wrapper(eng: &engine::EngineHandleImpl) {
+    eng.borrow().handle_engine_backtrace();
+}
+
+extern "C" fn handle_print_wrapper(eng: &engine::EngineHandleImpl, src: &engine::StringSource) {
+    eng.borrow().handle_print(src);
+}
+
+extern "C" fn handle_check_eq_wrapper(eng: &engine::EngineHandleImpl, left: &engine::ValueSource, right: &engine::ValueSource) {
+    eng.borrow_mut().handle_check_eq(left, right);
+}
+
+extern "C" fn call_function_wrapper(eng: &engine::EngineHandleImpl, target: &engine::ValueSource, cache: &CallSiteCache) -> i32 {
+    eng.borrow_mut().set_current_op(target as *const engine::ValueSource as *const u8);
     let f = target.fetch(&*eng.borrow());
 
-    if let Some(f) = f {
-        match f.call(eng) {
-            Ok(_) => signals::OK,
-            Err(_) => signals::EXCEPTION
+    let ret = match f {
+        Some(f) => {
+            let ret = f.call(eng, target.function_name().as_str());
+            if f.function_jit().is_some() {
+                *cache.target.borrow_mut() = Some(f.downgrade());
+            }
+            ret
+        },
+        None => Err("Call target is undefined".into())
+    };
+    eng.borrow().check_result(ret)
+}
+
+// Returns the callee's compiled entry and enters its call frame if the
+// cache still holds, or 0 to take the slow path. The frames are set up the
+// way `Variable::call` and `eval_block` do, except that traced calls always
+// take the slow path since compiled code can't be traced.
+extern "C" fn call_site_enter(eng: &engine::EngineHandleImpl, target: &engine::ValueSource, cache: &CallSiteCache) -> u64 {
+    if eng.borrow().trace {
+        return 0;
+    }
+    let cached = match *cache.target.borrow() {
+        Some(ref v) => match v.upgrade() {
+            Some(v) => v,
+            None => return 0
+        },
+        None => return 0
+    };
+    let current = match target.fetch(&*eng.borrow()) {
+        Some(v) => v,
+        None => return 0
+    };
+    if !cached.ptr_eq(&current) {
+        return 0;
+    }
+    let (entry, relocs) = match cached.function_jit() {
+        Some(v) => v,
+        None => return 0
+    };
+    cache.callee_relocs.set(relocs as u64);
+
+    let depths = {
+        let mut eng = eng.borrow_mut();
+        eng.set_current_op(target as *const engine::ValueSource as *const u8);
+        let callee = cached.impl_ref();
+        let blk = match callee.value {
+            var::Value::Function(ref blk) => blk,
+            _ => return 0
+        };
+        let depths = eng.enter_call(target.function_name().as_str(), blk);
+        if blk.scoped {
+            eng.enter_block_scope();
         }
+        eng.jit_stats.jit_executions += 1;
+        depths
+    };
+    cache.active.borrow_mut().push((cached, depths));
+    entry as *const c_void as u64
+}
+
+extern "C" fn call_site_leave(eng: &engine::EngineHandleImpl, cache: &CallSiteCache, ret: i32) -> i32 {
+    // The block scope of a scoped body goes with the call frame.
+    if let Some((_, depths)) = cache.active.borrow_mut().pop() {
+        eng.borrow_mut().leave_call(depths);
+    }
+
+    if ret == signals::OK {
+        signals::OK
+    } else if ret == signals::EXCEPTION {
+        signals::EXCEPTION // reported by the callee
     } else {
-        signals::EXCEPTION
+        eng.borrow().check_result(Err("Bad control status".into()))
     }
 }
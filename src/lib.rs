@@ -1,12 +1,25 @@
 extern crate cervus;
+extern crate llvm_sys;
+#[macro_use]
+extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate backtrace;
+extern crate libc;
 
+pub mod builtin;
 pub mod engine;
+pub mod glob;
 pub mod jit;
+pub mod output;
+pub mod owned;
+pub mod parser;
+pub mod printer;
+pub mod repl;
 pub mod signals;
+pub mod span;
+pub mod validate;
 pub mod var;
 pub mod api;
 
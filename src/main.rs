@@ -0,0 +1,305 @@
+extern crate oneshell;
+
+use std::io::Read;
+use oneshell::builtin;
+use oneshell::engine;
+use oneshell::glob;
+use oneshell::jit;
+use oneshell::parser;
+use oneshell::repl;
+use oneshell::signals;
+use oneshell::var;
+
+const USAGE: &'static str = "Usage: oneshell [OPTIONS] [FILE | -c CODE] [ARGS...]
+
+Runs a OneShell program from FILE, from CODE, or from stdin.
+
+Options:
+  -c CODE             Run CODE instead of a file
+  -i                  Run an interactive session, reading from stdin
+  --prompt TEXT       Interactive prompt; may use $name and ${name}
+  --history FILE      Interactive history file (default ~/.oneshell_history)
+  --json              Treat the program as a JSON AST
+  --script            Treat the program as a shell script
+  --no-validate       Run JSON ASTs without checking them first
+  --jit POLICY        JIT policy: off, eager or a call count
+  --aot-cache DIR     Cache optimized modules in DIR
+  --dump-ir[=FILE]    Print generated IR, or append it to FILE
+  --jit-stats         Print JIT statistics to stderr on exit
+  -x, --trace         Print each operation to stderr before running it
+  --native-backtrace  Include the native stack in backtraces
+  --nullglob          Drop arguments whose patterns match no path
+  --failglob          Fail commands whose patterns match no path
+  --dotglob           Let wildcards match names starting with .
+  -h, --help          Show this help
+
+Without --json or --script, programs whose name ends in .json or whose
+text starts with '{' are loaded as JSON ASTs. Positional arguments are
+available as $1, $2, ... and their count as $#. The exit status is the
+program's last exit status, or 1 if it raised an exception.
+
+A session is interactive with -i, or when neither FILE nor CODE is given
+and stdin is a terminal. All arguments are then positional. Interactive
+input is always a shell script; press Ctrl-D to leave.";
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Auto,
+    Json,
+    Script
+}
+
+struct Options {
+    format: Format,
+    validate: bool,
+    code: Option<String>,
+    file: Option<String>,
+    args: Vec<String>,
+    jit_policy: Option<jit::JitPolicy>,
+    aot_cache: Option<String>,
+    ir_dump: Option<jit::IrDump>,
+    jit_stats: bool,
+    trace: bool,
+    native_backtrace: bool,
+    glob_options: glob::Options,
+    interactive: bool,
+    prompt: Option<String>,
+    history: Option<String>
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        format: Format::Auto,
+        validate: true,
+        code: None,
+        file: None,
+        args: Vec::new(),
+        jit_policy: None,
+        aot_cache: None,
+        ir_dump: None,
+        jit_stats: false,
+        trace: false,
+        native_backtrace: false,
+        glob_options: glob::Options::default(),
+        interactive: false,
+        prompt: None,
+        history: None
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        i += 1;
+
+        // Options with a separate value.
+        let mut value = || -> Result<String, String> {
+            i += 1;
+            match args.get(i - 1) {
+                Some(v) => Ok(v.clone()),
+                None => Err(format!("{} requires a value", arg))
+            }
+        };
+
+        match arg {
+            "-h" | "--help" => return Err(String::new()),
+            "-c" => {
+                opts.code = Some(value()?);
+                break;
+            },
+            "-i" => opts.interactive = true,
+            "--prompt" => opts.prompt = Some(value()?),
+            "--history" => opts.history = Some(value()?),
+            "--json" => opts.format = Format::Json,
+            "--script" => opts.format = Format::Script,
+            "--no-validate" => opts.validate = false,
+            "--jit" => {
+                let v = value()?;
+                opts.jit_policy = Some(match jit::JitPolicy::parse(v.as_str()) {
+                    Some(v) => v,
+                    None => return Err(format!("invalid JIT policy: {}", v))
+                });
+            },
+            "--aot-cache" => opts.aot_cache = Some(value()?),
+            "--dump-ir" => opts.ir_dump = Some(jit::IrDump::Output),
+            "--jit-stats" => opts.jit_stats = true,
+            "-x" | "--trace" => opts.trace = true,
+            "--native-backtrace" => opts.native_backtrace = true,
+            "--nullglob" => opts.glob_options.no_match = glob::NoMatch::Remove,
+            "--failglob" => opts.glob_options.no_match = glob::NoMatch::Fail,
+            "--dotglob" => opts.glob_options.dotglob = true,
+            "--" => break,
+            _ if arg.starts_with("--dump-ir=") => {
+                opts.ir_dump = Some(jit::IrDump::File(arg["--dump-ir=".len()..].to_string()));
+            },
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option: {}", arg)),
+            _ => {
+                i -= 1;
+                break;
+            }
+        }
+    }
+
+    let mut rest = args[i..].iter().cloned();
+    if opts.code.is_none() && !opts.interactive {
+        opts.file = rest.next();
+    }
+    opts.args = rest.collect();
+
+    if opts.code.is_none() && opts.file.is_none() && repl::stdin_is_terminal() {
+        opts.interactive = true;
+    }
+    if opts.interactive && opts.code.is_some() {
+        return Err("-i can't be used with -c".to_string());
+    }
+    Ok(opts)
+}
+
+fn load(opts: &Options) -> Result<Box<engine::Block>, String> {
+    let (name, src, from_file) = match (&opts.code, &opts.file) {
+        (&Some(ref code), _) => ("-c".to_string(), code.clone(), false),
+        (&None, &Some(ref path)) if path != "-" => {
+            let mut src = String::new();
+            std::fs::File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut src))
+                .map_err(|e| format!("{}: {}", path, e))?;
+            (path.clone(), src, true)
+        },
+        _ => {
+            let mut src = String::new();
+            std::io::stdin().read_to_string(&mut src)
+                .map_err(|e| format!("stdin: {}", e))?;
+            ("stdin".to_string(), src, false)
+        }
+    };
+
+    let json = match opts.format {
+        Format::Json => true,
+        Format::Script => false,
+        Format::Auto => name.ends_with(".json") || src.trim_start().starts_with('{')
+    };
+    let ret = if !json {
+        engine::Engine::parse_script(src.as_str())
+    } else if opts.validate {
+        engine::Engine::load_block(src.as_str())
+    } else {
+        engine::Engine::load_block_unchecked(src.as_str())
+    };
+    let mut ret = ret.map_err(|e| {
+        if e.diagnostics.is_empty() {
+            return format!("{}: {}", name, e);
+        }
+        // One line per problem, each prefixed like the first.
+        let lines: Vec<String> = e.diagnostics.iter().map(|d| format!("{}: {}", name, d)).collect();
+        lines.join("\noneshell: ")
+    })?;
+
+    // Scripts read from a file report locations within it.
+    if !json && from_file {
+        ret.set_span_file(name.as_str());
+    }
+    Ok(ret)
+}
+
+// Runs an interactive session. Errors in the input are reported as they
+// happen and don't end the session.
+fn interact(opts: &Options, eng: engine::EngineHandle) -> Result<i32, String> {
+    let mut session = repl::Repl::new(eng);
+    if let Some(ref text) = opts.prompt {
+        session.prompt = parser::parse_string(text.as_str())
+            .map_err(|e| format!("--prompt: {}", e))?;
+    }
+
+    let history = match opts.history {
+        Some(ref v) => Some(std::path::PathBuf::from(v)),
+        None => std::env::var_os("HOME").map(|v| std::path::Path::new(&v).join(".oneshell_history"))
+    };
+    if let Some(path) = history {
+        match repl::History::open(&path) {
+            Ok(v) => session.history = v,
+            Err(e) => eprintln!("oneshell: {}: {}", path.display(), e)
+        }
+    }
+
+    session.run().map_err(|e| format!("stdin: {}", e))?;
+    Ok(signals::OK)
+}
+
+fn run(opts: Options) -> Result<i32, String> {
+    let mut blk = if opts.interactive {
+        None
+    } else {
+        Some(load(&opts)?)
+    };
+
+    let mut eng = engine::Engine::new();
+    eng.register_builtin("cd", builtin::ChangeDir);
+    if let Some(policy) = opts.jit_policy {
+        eng.set_jit_policy(policy);
+    }
+    if let Some(ref dir) = opts.aot_cache {
+        eng.set_aot_cache(Some(jit::AotCache::new(dir.as_str())));
+    }
+    eng.set_jit_ir_dump(opts.ir_dump.clone());
+    eng.set_trace(opts.trace);
+    eng.set_native_backtrace(opts.native_backtrace);
+    eng.set_glob_options(opts.glob_options);
+
+    let script_name = opts.file.clone().unwrap_or("oneshell".to_string());
+    for (i, arg) in std::iter::once(&script_name).chain(opts.args.iter()).enumerate() {
+        eng.vars.insert(i.to_string(), var::Variable::from_value(var::Value::String(arg.clone())));
+    }
+    eng.vars.insert("#".to_string(), var::Variable::from_value(var::Value::String(opts.args.len().to_string())));
+
+    let eng: engine::EngineHandle = eng.into();
+    let signal = match blk {
+        Some(ref mut blk) => eng.eval_block(blk),
+        None => interact(&opts, eng.clone())?
+    };
+
+    let mut eng = eng.borrow_mut();
+    for msg in eng.take_diagnostics() {
+        eprintln!("oneshell: {}", msg);
+    }
+    if opts.jit_stats {
+        let stats = &eng.jit_stats;
+        eprintln!(
+            "blocks compiled: {}, compile time: {} us, interpreted: {}, jit: {}, fallbacks: {}, aot cache hits: {}",
+            stats.blocks_compiled,
+            stats.compile_time_ns / 1000,
+            stats.interpreted_executions,
+            stats.jit_executions,
+            stats.fallbacks,
+            stats.aot_cache_hits
+        );
+    }
+
+    if signal == signals::EXCEPTION && eng.last_exit_status == 0 {
+        Ok(1)
+    } else {
+        Ok(eng.last_exit_status)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let opts = match parse_options(args.as_slice()) {
+        Ok(v) => v,
+        Err(ref e) if e.is_empty() => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("oneshell: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match run(opts) {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("oneshell: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// Destination for everything the engine itself prints: `Print`,
+/// `EngineBacktrace` and error reports, plus the output of builtins that
+/// isn't redirected. Child processes are not affected.
+pub trait OutputSink {
+    fn write_output(&self, data: &str);
+    fn write_error(&self, data: &str);
+}
+
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write_output(&self, data: &str) {
+        let stdout = ::std::io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(data.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn write_error(&self, data: &str) {
+        let _ = ::std::io::stderr().write_all(data.as_bytes());
+    }
+}
+
+/// Collects everything written to it in memory. Clones share the
+/// buffers, and are `Send` so they can be given to an `OwnedEngine`.
+#[derive(Clone, Default)]
+pub struct BufferSink {
+    output: Arc<Mutex<String>>,
+    error: Arc<Mutex<String>>
+}
+
+impl BufferSink {
+    pub fn output(&self) -> String {
+        self.output.lock().unwrap().clone()
+    }
+
+    pub fn error(&self) -> String {
+        self.error.lock().unwrap().clone()
+    }
+
+    /// Empties both buffers.
+    pub fn clear(&self) {
+        self.output.lock().unwrap().clear();
+        self.error.lock().unwrap().clear();
+    }
+}
+
+impl OutputSink for BufferSink {
+    fn write_output(&self, data: &str) {
+        self.output.lock().unwrap().push_str(data);
+    }
+
+    fn write_error(&self, data: &str) {
+        self.error.lock().unwrap().push_str(data);
+    }
+}
+
+/// Passes what is written to it on to a sink's output as it comes, a
+/// line at a time so that characters aren't split. The rest is passed on
+/// when flushed or dropped.
+pub struct SinkWriter {
+    sink: Rc<OutputSink>,
+    pending: Vec<u8>
+}
+
+impl SinkWriter {
+    pub fn new(sink: Rc<OutputSink>) -> SinkWriter {
+        SinkWriter {
+            sink: sink,
+            pending: Vec::new()
+        }
+    }
+}
+
+impl Write for SinkWriter {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if let Some(i) = self.pending.iter().rposition(|&b| b == b'\n') {
+            let rest = self.pending.split_off(i + 1);
+            self.sink.write_output(String::from_utf8_lossy(&self.pending).as_ref());
+            self.pending = rest;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        if !self.pending.is_empty() {
+            self.sink.write_output(String::from_utf8_lossy(&self.pending).as_ref());
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SinkWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+use engine;
+use var;
+use builtin;
+use output;
+use signals;
+use jit;
+
+/// Identifies a block loaded into an `OwnedEngine`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockId(usize);
+
+/// An engine that owns the blocks evaluated on it and can be moved between
+/// threads as a unit.
+///
+/// `EngineHandle`, variables and compiled blocks share state through `Rc`,
+/// so none of them is `Send` on its own. An `OwnedEngine` never hands out
+/// its handle or its blocks, and only accepts `Send` builtins and output
+/// sinks, so every `Rc` reachable from it is owned by it alone. Values
+/// passed in or out are copied, which drops any JIT state they carry.
+pub struct OwnedEngine {
+    handle: engine::EngineHandle,
+    blocks: Vec<Option<Box<engine::Block>>>
+}
+
+// See the type's documentation. Calls take `&mut self`, so the engine is
+// never used from two threads at once.
+unsafe impl Send for OwnedEngine {}
+
+impl OwnedEngine {
+    pub fn new() -> OwnedEngine {
+        OwnedEngine::from_engine(engine::Engine::new())
+    }
+
+    fn from_engine(eng: engine::Engine) -> OwnedEngine {
+        OwnedEngine {
+            handle: eng.into(),
+            blocks: Vec::new()
+        }
+    }
+
+    pub fn load_block(&mut self, ast: &str) -> Result<BlockId, engine::LoadError> {
+        let blk = engine::Engine::load_block(ast)?;
+        self.blocks.push(Some(blk));
+        Ok(BlockId(self.blocks.len() - 1))
+    }
+
+    /// Returns false if `id` is unknown or was already unloaded.
+    pub fn unload_block(&mut self, id: BlockId) -> bool {
+        match self.blocks.get_mut(id.0) {
+            Some(v) => v.take().is_some(),
+            None => false
+        }
+    }
+
+    /// Returns `signals::EXCEPTION` if `id` is unknown.
+    pub fn eval_block(&mut self, id: BlockId) -> i32 {
+        match self.blocks.get_mut(id.0) {
+            Some(&mut Some(ref mut blk)) => self.handle.eval_block(blk),
+            _ => signals::EXCEPTION
+        }
+    }
+
+    pub fn register_builtin<T: builtin::Builtin + Send + 'static>(&mut self, name: &str, b: T) {
+        self.handle.borrow_mut().register_builtin(name, b);
+    }
+
+    pub fn unregister_builtin(&mut self, name: &str) -> bool {
+        self.handle.borrow_mut().unregister_builtin(name)
+    }
+
+    pub fn set_output_sink<T: output::OutputSink + Send + 'static>(&mut self, sink: T) {
+        self.handle.borrow_mut().set_output_sink(sink);
+    }
+
+    pub fn set_jit_policy(&mut self, policy: jit::JitPolicy) {
+        self.handle.borrow_mut().set_jit_policy(policy);
+    }
+
+    pub fn set_aot_cache(&mut self, cache: Option<jit::AotCache>) {
+        self.handle.borrow_mut().set_aot_cache(cache);
+    }
+
+    /// Fails if the variable is readonly or requires another type.
+    pub fn set_global(&mut self, name: &str, value: &var::Value) -> Result<(), Box<Error>> {
+        self.handle.borrow_mut().assign(engine::Scope::Global, name, var::Variable::from_value(value.clone()))
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<var::Value> {
+        self.handle.borrow().vars.get(name).map(|v| v.impl_ref().value.clone())
+    }
+
+    /// Whether the variable was set. Fails if it is readonly.
+    pub fn unset_global(&mut self, name: &str) -> Result<bool, Box<Error>> {
+        let mut eng = self.handle.borrow_mut();
+        let existed = eng.vars.contains_key(name);
+        eng.unset(engine::Scope::Global, name)?;
+        Ok(existed)
+    }
+
+    pub fn last_exit_status(&self) -> i32 {
+        self.handle.borrow().last_exit_status
+    }
+
+    pub fn jit_stats(&self) -> jit::JitStats {
+        self.handle.borrow().jit_stats.clone()
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<String> {
+        self.handle.borrow_mut().take_diagnostics()
+    }
+
+    pub fn snapshot(&self) -> Result<String, Box<Error>> {
+        self.handle.borrow().snapshot()
+    }
+
+    pub fn restore(&mut self, data: &str) -> Result<(), Box<Error>> {
+        self.handle.borrow_mut().restore(data)
+    }
+
+    /// Creates an independent engine with a copy of this one's state.
+    /// Blocks are not carried over.
+    pub fn fork(&self) -> Result<OwnedEngine, Box<Error>> {
+        // `Engine::clone` shares builtins and the output sink, which would
+        // leave the copy with `Rc`s it doesn't own. Only plain state is
+        // copied.
+        let mut eng = engine::Engine::new();
+        eng.restore(self.snapshot()?.as_str())?;
+        {
+            let src = self.handle.borrow();
+            eng.jit_policy = src.jit_policy;
+            eng.aot_cache = src.aot_cache.clone();
+        }
+        Ok(OwnedEngine::from_engine(eng))
+    }
+}
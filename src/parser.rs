@@ -0,0 +1,1335 @@
+use std;
+use std::collections::HashSet;
+use engine::{Block, Operation, Scope, ExecInfo, EnvInfo, StdioConfig, StringSource, ExpandOp, ValueSource, ParallelInfo, ForEachInfo, LoadError};
+use span::Span;
+use glob;
+use var;
+
+#[derive(Clone, Debug, PartialEq)]
+enum WordPart {
+    Literal(String),
+    Variable(String),
+    LastExitStatus,
+    Expand(String, Box<Expansion>) // `${name<op>...}`
+}
+
+// Operators of `${...}`, with the words they take.
+#[derive(Clone, Debug, PartialEq)]
+enum Expansion {
+    Default(Word, bool), // (word, also if empty)
+    Alternative(Word, bool),
+    RemovePrefix(Word, bool), // (pattern, longest)
+    RemoveSuffix(Word, bool),
+    Replace(Word, Word, bool), // (pattern, replacement, all)
+    Length,
+    Substring(Word, Option<Word>)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Word {
+    parts: Vec<(WordPart, bool)> // (part, quoted)
+}
+
+impl Word {
+    // The word's text if it is a single unquoted literal, which is the only
+    // form recognized as a keyword or name.
+    fn plain(&self) -> Option<&str> {
+        if self.parts.len() != 1 {
+            return None;
+        }
+        match self.parts[0] {
+            (WordPart::Literal(ref s), false) => Some(s.as_str()),
+            _ => None
+        }
+    }
+
+    // Splits `name=value` into its parts.
+    fn assignment(&self) -> Option<(String, Word)> {
+        let first = match self.parts.first() {
+            Some(&(WordPart::Literal(ref s), false)) => s,
+            _ => return None
+        };
+        let eq = match first.find('=') {
+            Some(v) => v,
+            None => return None
+        };
+        if !is_name(&first[..eq]) {
+            return None;
+        }
+
+        let mut value = Word {
+            parts: Vec::new()
+        };
+        if eq + 1 < first.len() {
+            value.parts.push((WordPart::Literal(first[eq + 1..].to_string()), false));
+        }
+        value.parts.extend(self.parts[1..].iter().cloned());
+        Some((first[..eq].to_string(), value))
+    }
+
+    fn push_literal(&mut self, c: char, quoted: bool) {
+        if let Some(&mut (WordPart::Literal(ref mut s), q)) = self.parts.last_mut() {
+            if q == quoted {
+                s.push(c);
+                return;
+            }
+        }
+        self.parts.push((WordPart::Literal(c.to_string()), quoted));
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Reported at the start of the quote, so `is_incomplete` looks for them
+// by message.
+const UNTERMINATED_SINGLE_QUOTE: &'static str = "unterminated single quote";
+const UNTERMINATED_DOUBLE_QUOTE: &'static str = "unterminated double quote";
+const UNTERMINATED_EXPANSION: &'static str = "unterminated parameter expansion";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(Word),
+    And, // &&
+    Or, // ||
+    Pipe,
+    Semi,
+    Amp,
+    Newline,
+    Less,
+    Great,
+    DGreat, // >>
+    LParen,
+    RParen,
+    Eof
+}
+
+struct Spanned {
+    token: Token,
+    line: usize,
+    column: usize
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.pos += 1;
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        c
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, LoadError> {
+        Err(LoadError::syntax(message, self.line, self.column))
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Spanned>, LoadError> {
+        let mut ret: Vec<Spanned> = Vec::new();
+        loop {
+            let tok = self.next_token()?;
+            let done = tok.token == Token::Eof;
+            ret.push(tok);
+            if done {
+                return Ok(ret);
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Spanned, LoadError> {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') => {
+                    self.bump();
+                },
+                Some('\\') if self.peek_at(1) == Some('\n') => {
+                    self.bump();
+                    self.bump();
+                },
+                Some('#') => {
+                    while self.peek().is_some() && self.peek() != Some('\n') {
+                        self.bump();
+                    }
+                },
+                _ => break
+            }
+        }
+
+        let (line, column) = (self.line, self.column);
+        let c = match self.peek() {
+            Some(v) => v,
+            None => return Ok(Spanned {
+                token: Token::Eof,
+                line: line,
+                column: column
+            })
+        };
+
+        let token = match c {
+            '\n' | ';' | '&' | '|' | '<' | '>' | '(' | ')' => {
+                self.bump();
+                let next = self.peek();
+                match (c, next) {
+                    ('\n', _) => Token::Newline,
+                    (';', Some(';')) => return self.error("'case' is not supported"),
+                    (';', _) => Token::Semi,
+                    ('&', Some('&')) => {
+                        self.bump();
+                        Token::And
+                    },
+                    ('&', _) => Token::Amp,
+                    ('|', Some('|')) => {
+                        self.bump();
+                        Token::Or
+                    },
+                    ('|', _) => Token::Pipe,
+                    ('<', Some('<')) => return self.error("here-documents are not supported"),
+                    ('<', Some('&')) | ('<', Some('>')) | ('>', Some('&')) | ('>', Some('|')) => {
+                        return self.error("unsupported redirection");
+                    },
+                    ('<', _) => Token::Less,
+                    ('>', Some('>')) => {
+                        self.bump();
+                        Token::DGreat
+                    },
+                    ('>', _) => Token::Great,
+                    ('(', _) => Token::LParen,
+                    _ => Token::RParen
+                }
+            },
+            _ => Token::Word(self.read_word()?)
+        };
+
+        Ok(Spanned {
+            token: token,
+            line: line,
+            column: column
+        })
+    }
+
+    fn read_word(&mut self) -> Result<Word, LoadError> {
+        let mut word = Word {
+            parts: Vec::new()
+        };
+
+        loop {
+            let c = match self.peek() {
+                Some(v) => v,
+                None => break
+            };
+            match c {
+                ' ' | '\t' | '\r' | '\n' | ';' | '&' | '|' | '(' | ')' => break,
+                '<' | '>' => {
+                    if word.plain().map(|v| v.chars().all(|c| c.is_ascii_digit())).unwrap_or(false) {
+                        return self.error("file descriptor redirections are not supported");
+                    }
+                    break;
+                },
+                '\\' => {
+                    self.bump();
+                    match self.bump() {
+                        Some('\n') => {},
+                        Some(c) => word.push_literal(c, true),
+                        None => word.push_literal('\\', false)
+                    }
+                },
+                '\'' => self.read_single_quoted(&mut word)?,
+                '"' => {
+                    let (line, column) = (self.line, self.column);
+                    self.bump();
+                    word.parts.push((WordPart::Literal(String::new()), true));
+                    if !self.read_double_quoted(&mut word)? {
+                        return Err(LoadError::syntax(UNTERMINATED_DOUBLE_QUOTE, line, column));
+                    }
+                },
+                '$' => {
+                    self.bump();
+                    self.read_dollar(&mut word, false)?;
+                },
+                '`' => return self.error("command substitution is not supported"),
+                _ => {
+                    self.bump();
+                    word.push_literal(c, false);
+                }
+            }
+        }
+
+        // Drop the empty literals pushed for quotes, unless that is all there is.
+        if word.parts.len() > 1 {
+            word.parts.retain(|&(ref part, _)| *part != WordPart::Literal(String::new()));
+        }
+        Ok(word)
+    }
+
+    // Reads a single-quoted string, starting at the opening quote.
+    fn read_single_quoted(&mut self, word: &mut Word) -> Result<(), LoadError> {
+        let (line, column) = (self.line, self.column);
+        self.bump();
+        // An empty quoted string is still a word.
+        word.parts.push((WordPart::Literal(String::new()), true));
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(()),
+                Some(c) => word.push_literal(c, true),
+                None => return Err(LoadError::syntax(UNTERMINATED_SINGLE_QUOTE, line, column))
+            }
+        }
+    }
+
+    // Reads the inside of a double-quoted string up to and including the
+    // closing quote. Returns false if the input ends first.
+    fn read_double_quoted(&mut self, word: &mut Word) -> Result<bool, LoadError> {
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    return Ok(true);
+                },
+                Some('\\') => {
+                    self.bump();
+                    match self.peek() {
+                        Some('\n') => {
+                            self.bump();
+                        },
+                        Some(c) if c == '$' || c == '`' || c == '"' || c == '\\' => {
+                            self.bump();
+                            word.push_literal(c, true);
+                        },
+                        _ => word.push_literal('\\', true)
+                    }
+                },
+                Some('$') => {
+                    self.bump();
+                    self.read_dollar(word, true)?;
+                },
+                Some('`') => return self.error("command substitution is not supported"),
+                Some(c) => {
+                    self.bump();
+                    word.push_literal(c, true);
+                },
+                None => return Ok(false)
+            }
+        }
+    }
+
+    // Called after `$`.
+    fn read_dollar(&mut self, word: &mut Word, quoted: bool) -> Result<(), LoadError> {
+        let part = match self.peek() {
+            Some('{') => {
+                let (line, column) = (self.line, self.column);
+                self.bump();
+                self.read_braced(line, column)?
+            },
+            Some('?') => {
+                self.bump();
+                WordPart::LastExitStatus
+            },
+            Some(c) if c == '#' || c.is_ascii_digit() => {
+                self.bump();
+                WordPart::Variable(c.to_string())
+            },
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(c) = self.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    self.bump();
+                    name.push(c);
+                }
+                WordPart::Variable(name)
+            },
+            Some('(') => return self.error("command substitution is not supported"),
+            Some('@') | Some('*') => return self.error("$@ and $* are not supported"),
+            _ => {
+                word.push_literal('$', quoted);
+                return Ok(());
+            }
+        };
+        word.parts.push((part, quoted));
+        Ok(())
+    }
+
+    // Called after `${`, which starts at `line` and `column`. Reads up to
+    // and including the closing `}`.
+    fn read_braced(&mut self, line: usize, column: usize) -> Result<WordPart, LoadError> {
+        let unsupported = || Err(LoadError::syntax("unsupported parameter expansion", line, column));
+
+        // `${#}` is a parameter of its own.
+        let length = self.peek() == Some('#') && self.peek_at(1).map(|c| c != '}').unwrap_or(false);
+        if length {
+            self.bump();
+        }
+
+        let mut name = String::new();
+        match self.peek() {
+            Some(c) if c == '?' || c == '#' => {
+                self.bump();
+                name.push(c);
+            },
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' => {
+                let digits = c.is_ascii_digit();
+                while let Some(c) = self.peek() {
+                    if !(c.is_ascii_digit() || (!digits && (c.is_ascii_alphabetic() || c == '_'))) {
+                        break;
+                    }
+                    self.bump();
+                    name.push(c);
+                }
+            },
+            Some(_) => return unsupported(),
+            None => return Err(LoadError::syntax(UNTERMINATED_EXPANSION, line, column))
+        }
+
+        let expansion = match self.bump() {
+            None => return Err(LoadError::syntax(UNTERMINATED_EXPANSION, line, column)),
+            Some('}') if length => return Ok(WordPart::Expand(name, Box::new(Expansion::Length))),
+            Some('}') if name == "?" => return Ok(WordPart::LastExitStatus),
+            Some('}') => return Ok(WordPart::Variable(name)),
+            _ if length || name == "?" => return unsupported(),
+            Some(':') => match self.peek() {
+                Some(c) if c == '-' || c == '+' => {
+                    self.bump();
+                    let word = self.read_expansion_word("}", line, column)?;
+                    if c == '-' {
+                        Expansion::Default(word, true)
+                    } else {
+                        Expansion::Alternative(word, true)
+                    }
+                },
+                _ => {
+                    let offset = self.read_expansion_word(":}", line, column)?;
+                    let length = if self.peek() == Some(':') {
+                        self.bump();
+                        Some(self.read_expansion_word("}", line, column)?)
+                    } else {
+                        None
+                    };
+                    Expansion::Substring(offset, length)
+                }
+            },
+            Some('-') => Expansion::Default(self.read_expansion_word("}", line, column)?, false),
+            Some('+') => Expansion::Alternative(self.read_expansion_word("}", line, column)?, false),
+            Some(c) if c == '#' || c == '%' => {
+                let longest = self.peek() == Some(c);
+                if longest {
+                    self.bump();
+                }
+                let pattern = self.read_expansion_word("}", line, column)?;
+                if c == '#' {
+                    Expansion::RemovePrefix(pattern, longest)
+                } else {
+                    Expansion::RemoveSuffix(pattern, longest)
+                }
+            },
+            Some('/') => {
+                let all = self.peek() == Some('/');
+                if all {
+                    self.bump();
+                }
+                let pattern = self.read_expansion_word("/}", line, column)?;
+                let replacement = if self.peek() == Some('/') {
+                    self.bump();
+                    self.read_expansion_word("}", line, column)?
+                } else {
+                    Word {
+                        parts: Vec::new()
+                    }
+                };
+                Expansion::Replace(pattern, replacement, all)
+            },
+            Some(_) => return unsupported()
+        };
+
+        // All words stop at the closing brace, or at a character before it.
+        self.bump();
+        Ok(WordPart::Expand(name, Box::new(expansion)))
+    }
+
+    // Reads a word inside `${...}` up to, but not including, any character
+    // in `stop`. Quotes and expansions nest.
+    fn read_expansion_word(&mut self, stop: &str, line: usize, column: usize) -> Result<Word, LoadError> {
+        let mut word = Word {
+            parts: Vec::new()
+        };
+
+        loop {
+            match self.peek() {
+                Some(c) if stop.contains(c) => break,
+                Some('\\') => {
+                    self.bump();
+                    match self.bump() {
+                        Some('\n') => {},
+                        Some(c) => word.push_literal(c, true),
+                        None => return Err(LoadError::syntax(UNTERMINATED_EXPANSION, line, column))
+                    }
+                },
+                Some('\'') => self.read_single_quoted(&mut word)?,
+                Some('"') => {
+                    let (line, column) = (self.line, self.column);
+                    self.bump();
+                    word.parts.push((WordPart::Literal(String::new()), true));
+                    if !self.read_double_quoted(&mut word)? {
+                        return Err(LoadError::syntax(UNTERMINATED_DOUBLE_QUOTE, line, column));
+                    }
+                },
+                Some('$') => {
+                    self.bump();
+                    self.read_dollar(&mut word, false)?;
+                },
+                Some('`') => return self.error("command substitution is not supported"),
+                Some(c) => {
+                    self.bump();
+                    word.push_literal(c, false);
+                },
+                None => return Err(LoadError::syntax(UNTERMINATED_EXPANSION, line, column))
+            }
+        }
+
+        if word.parts.len() > 1 {
+            word.parts.retain(|&(ref part, _)| *part != WordPart::Literal(String::new()));
+        }
+        Ok(word)
+    }
+}
+
+// Ops with where they start in the source.
+type Ops = Vec<(Operation, Span)>;
+
+fn block(ops: Ops) -> Block {
+    let (ops, spans) = ops.into_iter().map(|(op, span)| (op, Some(span))).unzip();
+    Block::with_spans(ops, spans)
+}
+
+// A command that can still take part in a pipeline or run in the background.
+enum Command {
+    Exec(ExecInfo),
+    Ops(Ops)
+}
+
+impl Command {
+    fn into_ops(self) -> Ops {
+        match self {
+            Command::Exec(info) => {
+                let span = info.span.clone().unwrap_or_default();
+                vec![(Operation::Exec(info), span)]
+            },
+            Command::Ops(ops) => ops
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    functions: HashSet<String>,
+    locals: HashSet<String>, // declared with `local` in the function or at top level
+    loop_depth: usize,
+    next_pipe: usize
+}
+
+const UNSUPPORTED_COMMANDS: &'static [&'static str] = &["continue", "return", "exit", "case", "function", "select"];
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn peek_at(&self, n: usize) -> &Token {
+        let i = std::cmp::min(self.pos + n, self.tokens.len() - 1);
+        &self.tokens[i].token
+    }
+
+    fn advance(&mut self) -> Token {
+        let ret = self.tokens[self.pos].token.clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        ret
+    }
+
+    fn span(&self) -> Span {
+        let tok = &self.tokens[self.pos];
+        Span::at(tok.line, tok.column)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, LoadError> {
+        let tok = &self.tokens[self.pos];
+        Err(LoadError::syntax(message, tok.line, tok.column))
+    }
+
+    fn unexpected<T>(&self) -> Result<T, LoadError> {
+        let message = match *self.peek() {
+            Token::Word(ref w) => match w.plain() {
+                Some(v) => format!("unexpected '{}'", v),
+                None => "unexpected word".to_string()
+            },
+            Token::And => "unexpected '&&'".to_string(),
+            Token::Or => "unexpected '||'".to_string(),
+            Token::Pipe => "unexpected '|'".to_string(),
+            Token::Semi => "unexpected ';'".to_string(),
+            Token::Amp => "unexpected '&'".to_string(),
+            Token::Newline => "unexpected newline".to_string(),
+            Token::Less => "unexpected '<'".to_string(),
+            Token::Great => "unexpected '>'".to_string(),
+            Token::DGreat => "unexpected '>>'".to_string(),
+            Token::LParen => "unexpected '('".to_string(),
+            Token::RParen => "unexpected ')'".to_string(),
+            Token::Eof => "unexpected end of input".to_string()
+        };
+        self.error(message.as_str())
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        match *self.peek() {
+            Token::Word(ref w) => w.plain() == Some(kw),
+            _ => false
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), LoadError> {
+        if !self.is_keyword(kw) {
+            return match *self.peek() {
+                Token::Eof => self.error(format!("expected '{}' before end of input", kw).as_str()),
+                _ => self.error(format!("expected '{}'", kw).as_str())
+            };
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.peek() == Token::Newline {
+            self.advance();
+        }
+    }
+
+    // `terminators` are keywords, or ")", that end the list.
+    fn at_terminator(&self, terminators: &[&str]) -> bool {
+        match *self.peek() {
+            Token::Eof => true,
+            Token::RParen => terminators.contains(&")"),
+            Token::Word(ref w) => w.plain().map(|v| terminators.contains(&v)).unwrap_or(false),
+            _ => false
+        }
+    }
+
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<Ops, LoadError> {
+        let mut ops: Ops = Vec::new();
+        loop {
+            while *self.peek() == Token::Newline || *self.peek() == Token::Semi {
+                self.advance();
+            }
+            if self.at_terminator(terminators) {
+                return Ok(ops);
+            }
+
+            ops.extend(self.parse_and_or()?);
+
+            match *self.peek() {
+                Token::Newline | Token::Semi => {},
+                _ if self.at_terminator(terminators) => {},
+                _ => return self.unexpected()
+            }
+        }
+    }
+
+    // A list that must contain at least one command, as in `if` or `while`.
+    fn parse_compound_list(&mut self, terminators: &[&str]) -> Result<Ops, LoadError> {
+        let ops = self.parse_list(terminators)?;
+        if ops.is_empty() {
+            return self.error("expected a command");
+        }
+        Ok(ops)
+    }
+
+    fn parse_and_or(&mut self) -> Result<Ops, LoadError> {
+        let first = self.parse_pipeline()?;
+
+        if *self.peek() == Token::Amp {
+            return match first {
+                Command::Exec(info) => {
+                    self.advance();
+                    let span = info.span.clone().unwrap_or_default();
+                    Ok(vec![(Operation::BackgroundExec(info), span)])
+                },
+                Command::Ops(_) => self.error("only simple commands can run in the background")
+            };
+        }
+
+        let mut ops = first.into_ops();
+        loop {
+            // IfElse runs its first block if the last status is non-zero.
+            let is_and = match *self.peek() {
+                Token::And => true,
+                Token::Or => false,
+                _ => break
+            };
+            let span = self.span();
+            self.advance();
+            self.skip_newlines();
+
+            let next = block(self.parse_pipeline()?.into_ops());
+            ops.push((if is_and {
+                Operation::IfElse(Block::new(Vec::new()), next)
+            } else {
+                Operation::IfElse(next, Block::new(Vec::new()))
+            }, span));
+        }
+
+        if *self.peek() == Token::Amp {
+            return self.error("only simple commands can run in the background");
+        }
+        Ok(ops)
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Command, LoadError> {
+        let span = self.span();
+        let negate = self.is_keyword("!");
+        if negate {
+            self.advance();
+        }
+
+        let mut cmds: Vec<(Command, usize)> = vec![(self.parse_command()?, self.pos)];
+        while *self.peek() == Token::Pipe {
+            self.advance();
+            self.skip_newlines();
+            let pos = self.pos;
+            cmds.push((self.parse_command()?, pos));
+        }
+
+        let cmd = if cmds.len() == 1 {
+            cmds.pop().unwrap().0
+        } else {
+            let pipe_id = self.next_pipe;
+            self.next_pipe += 1;
+
+            let n = cmds.len();
+            let mut infos: Vec<ExecInfo> = Vec::new();
+            for (i, (cmd, pos)) in cmds.into_iter().enumerate() {
+                let mut info = match cmd {
+                    Command::Exec(v) => v,
+                    Command::Ops(_) => {
+                        self.pos = pos;
+                        return self.error("only simple commands can be piped");
+                    }
+                };
+                if i > 0 {
+                    if let StdioConfig::Inherit = info.stdin {} else {
+                        self.pos = pos;
+                        return self.error("stdin is both piped and redirected");
+                    }
+                    info.stdin = StdioConfig::Pipe(format!("pipe{}_{}", pipe_id, i - 1));
+                }
+                if i + 1 < n {
+                    if let StdioConfig::Inherit = info.stdout {} else {
+                        self.pos = pos;
+                        return self.error("stdout is both piped and redirected");
+                    }
+                    info.stdout = StdioConfig::Pipe(format!("pipe{}_{}", pipe_id, i));
+                }
+                infos.push(info);
+            }
+            Command::Ops(vec![(Operation::ParallelExec(infos), span.clone())])
+        };
+
+        if negate {
+            let mut ops = cmd.into_ops();
+            ops.push((Operation::CheckEq(
+                ValueSource::LastExitStatus,
+                ValueSource::Plain(var::Value::Integer(0))
+            ), span));
+            Ok(Command::Ops(ops))
+        } else {
+            Ok(cmd)
+        }
+    }
+
+    fn parse_command(&mut self) -> Result<Command, LoadError> {
+        let keyword = match *self.peek() {
+            Token::Word(ref w) => w.plain().map(|v| v.to_string()),
+            Token::LParen => Some("(".to_string()),
+            _ => None
+        };
+
+        let span = self.span();
+        let ops = match keyword.as_ref().map(|v| v.as_str()) {
+            Some("if") => {
+                self.advance();
+                self.parse_if_body(span)?
+            },
+            Some("while") => self.parse_while(false)?,
+            Some("until") => self.parse_while(true)?,
+            Some("for") => self.parse_for()?,
+            Some("{") => self.parse_group()?,
+            Some("(") => {
+                // Subshells run on a copy of the engine state.
+                self.advance();
+                let ops = self.parse_compound_list(&[")"])?;
+                if *self.peek() != Token::RParen {
+                    return self.unexpected();
+                }
+                self.advance();
+                vec![(Operation::Parallel(ParallelInfo {
+                    blocks: vec![block(ops)],
+                    max_concurrency: 0,
+                    merge_globals: Vec::new(),
+                    fail_fast: false
+                }), span)]
+            },
+            Some("then") | Some("else") | Some("elif") | Some("fi") | Some("do") | Some("done") | Some("}") | Some("in") => {
+                return self.unexpected();
+            },
+            Some(v) if UNSUPPORTED_COMMANDS.contains(&v) => {
+                return self.error(format!("'{}' is not supported", v).as_str());
+            },
+            Some(_) if *self.peek_at(1) == Token::LParen => self.parse_function()?,
+            _ => return self.parse_simple()
+        };
+
+        match *self.peek() {
+            Token::Less | Token::Great | Token::DGreat => self.error("redirections on compound commands are not supported"),
+            _ => Ok(Command::Ops(ops))
+        }
+    }
+
+    // After `if` or `elif`, which starts at `span`. Consumes the closing `fi`.
+    fn parse_if_body(&mut self, span: Span) -> Result<Ops, LoadError> {
+        let mut ops = self.parse_compound_list(&["then"])?;
+        self.expect_keyword("then")?;
+        let then_ops = self.parse_compound_list(&["elif", "else", "fi"])?;
+
+        let else_ops = if self.is_keyword("elif") {
+            let span = self.span();
+            self.advance();
+            self.parse_if_body(span)?
+        } else {
+            let ret = if self.is_keyword("else") {
+                self.advance();
+                self.parse_compound_list(&["fi"])?
+            } else {
+                Vec::new()
+            };
+            self.expect_keyword("fi")?;
+            ret
+        };
+
+        ops.push((Operation::IfElse(block(else_ops), block(then_ops)), span));
+        Ok(ops)
+    }
+
+    fn parse_loop_body(&mut self) -> Result<Ops, LoadError> {
+        self.expect_keyword("do")?;
+        self.loop_depth += 1;
+        let ret = self.parse_compound_list(&["done"]);
+        self.loop_depth -= 1;
+        let ret = ret?;
+        self.expect_keyword("done")?;
+        Ok(ret)
+    }
+
+    fn parse_while(&mut self, until: bool) -> Result<Ops, LoadError> {
+        let span = self.span();
+        self.advance();
+        let mut ops = self.parse_compound_list(&["do"])?;
+        let body = block(self.parse_loop_body()?);
+        let exit = block(vec![(Operation::Break, span.clone())]);
+
+        ops.push((if until {
+            Operation::IfElse(body, exit)
+        } else {
+            Operation::IfElse(exit, body)
+        }, span.clone()));
+        Ok(vec![(Operation::Loop(block(ops)), span)])
+    }
+
+    // The word list is fixed at parse time, so the loop is unrolled into a
+    // single pass ending in `Break`.
+    fn parse_for(&mut self) -> Result<Ops, LoadError> {
+        let span = self.span();
+        self.advance();
+        let name = match *self.peek() {
+            Token::Word(ref w) => match w.plain() {
+                Some(v) if is_name(v) => v.to_string(),
+                _ => return self.error("expected a variable name")
+            },
+            _ => return self.error("expected a variable name")
+        };
+        self.advance();
+        self.skip_newlines();
+        if !self.is_keyword("in") {
+            return self.error("expected 'in'; positional parameters are not supported");
+        }
+        self.advance();
+
+        let mut words: Vec<(StringSource, Span)> = Vec::new();
+        while let Token::Word(ref w) = *self.peek() {
+            words.push((self.arg_source(w), self.span()));
+            self.pos += 1;
+        }
+        match *self.peek() {
+            Token::Semi | Token::Newline => {
+                self.advance();
+                self.skip_newlines();
+            },
+            _ => return self.unexpected()
+        }
+
+        let body = self.parse_loop_body()?;
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Patterns only know their words at runtime.
+        if words.iter().any(|&(ref w, _)| match *w {
+            StringSource::Glob(_) => true,
+            _ => false
+        }) {
+            return Ok(vec![(Operation::ForEach(ForEachInfo {
+                scope: self.scope_of(name.as_str()),
+                name: name,
+                words: words.into_iter().map(|(w, _)| w).collect(),
+                body: block(body)
+            }), span)]);
+        }
+
+        let mut ops: Ops = Vec::new();
+        for (w, w_span) in words {
+            ops.push((self.assign(name.clone(), w), w_span));
+            ops.extend(body.iter().cloned());
+        }
+        ops.push((Operation::Break, span.clone()));
+        Ok(vec![(Operation::Loop(block(ops)), span)])
+    }
+
+    fn parse_group(&mut self) -> Result<Ops, LoadError> {
+        self.advance();
+        let ops = self.parse_compound_list(&["}"])?;
+        self.expect_keyword("}")?;
+        Ok(ops)
+    }
+
+    fn parse_function(&mut self) -> Result<Ops, LoadError> {
+        let span = self.span();
+        let name = match self.advance() {
+            Token::Word(ref w) => w.plain().unwrap().to_string(),
+            _ => unreachable!()
+        };
+        if !is_name(name.as_str()) {
+            self.pos -= 1;
+            return self.error("invalid function name");
+        }
+        self.advance();
+        if *self.peek() != Token::RParen {
+            return self.error("expected ')'");
+        }
+        self.advance();
+        self.skip_newlines();
+        if !self.is_keyword("{") {
+            return self.error("expected '{' to start the function body");
+        }
+
+        let saved_locals = std::mem::replace(&mut self.locals, HashSet::new());
+        let saved_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.parse_group();
+        self.locals = saved_locals;
+        self.loop_depth = saved_loop_depth;
+
+        Ok(vec![(Operation::AssignGlobal(
+            name,
+            ValueSource::Plain(var::Value::Function(block(body?)))
+        ), span)])
+    }
+
+    fn parse_simple(&mut self) -> Result<Command, LoadError> {
+        let start = self.pos;
+        let span = self.span();
+        let mut assignments: Vec<(String, Word)> = Vec::new();
+        let mut words: Vec<Word> = Vec::new();
+        let mut stdin = StdioConfig::Inherit;
+        let mut stdout = StdioConfig::Inherit;
+
+        loop {
+            match self.peek().clone() {
+                Token::Word(w) => {
+                    self.advance();
+                    match w.assignment() {
+                        Some(v) if words.is_empty() => assignments.push(v),
+                        _ => words.push(w)
+                    }
+                },
+                tok @ Token::Less | tok @ Token::Great | tok @ Token::DGreat => {
+                    self.advance();
+                    let target = match *self.peek() {
+                        Token::Word(ref w) => self.word_source(w),
+                        _ => return self.error("expected a redirection target")
+                    };
+                    self.advance();
+                    match tok {
+                        Token::Less => stdin = StdioConfig::File(target),
+                        Token::Great => stdout = StdioConfig::File(target),
+                        _ => stdout = StdioConfig::AppendFile(target)
+                    }
+                },
+                _ => break
+            }
+        }
+
+        if self.pos == start {
+            return self.unexpected();
+        }
+        let redirected = match (&stdin, &stdout) {
+            (&StdioConfig::Inherit, &StdioConfig::Inherit) => false,
+            _ => true
+        };
+
+        if words.is_empty() {
+            if redirected {
+                self.pos = start;
+                return self.error("redirection without a command");
+            }
+            let ops = assignments.into_iter()
+                .map(|(k, v)| {
+                    let v = self.word_source(&v);
+                    (self.assign(k, v), span.clone())
+                })
+                .collect();
+            return Ok(Command::Ops(ops));
+        }
+
+        let name = words[0].plain().map(|v| v.to_string());
+        let special = name.as_ref().map(|v| v == "break" || v == "local" || self.functions.contains(v)).unwrap_or(false);
+        if special && (redirected || !assignments.is_empty()) {
+            self.pos = start;
+            return self.error(format!("'{}' takes no assignments or redirections", name.unwrap()).as_str());
+        }
+
+        match name.as_ref().map(|v| v.as_str()) {
+            Some("break") => {
+                if words.len() > 1 {
+                    self.pos = start;
+                    return self.error("break with a count is not supported");
+                }
+                if self.loop_depth == 0 {
+                    self.pos = start;
+                    return self.error("break outside of a loop");
+                }
+                return Ok(Command::Ops(vec![(Operation::Break, span)]));
+            },
+            Some("local") => {
+                let mut ops: Ops = Vec::new();
+                for w in words[1..].iter() {
+                    let (k, v) = match w.assignment() {
+                        Some((k, v)) => (k, self.word_source(&v)),
+                        None => match w.plain() {
+                            Some(v) if is_name(v) => (v.to_string(), StringSource::Plain(String::new())),
+                            _ => {
+                                self.pos = start;
+                                return self.error("expected a variable name after local");
+                            }
+                        }
+                    };
+                    self.locals.insert(k.clone());
+                    ops.push((Operation::AssignLocal(k, ValueSource::String(Box::new(v))), span.clone()));
+                }
+                return Ok(Command::Ops(ops));
+            },
+            Some(v) if self.functions.contains(v) => {
+                if words.len() > 1 {
+                    self.pos = start;
+                    return self.error("arguments to functions are not supported");
+                }
+                return Ok(Command::Ops(vec![(Operation::Call(ValueSource::GlobalVariable(v.to_string())), span)]));
+            },
+            _ => {}
+        }
+
+        let env = assignments.into_iter()
+            .map(|(k, v)| EnvInfo {
+                key: StringSource::Plain(k),
+                value: self.word_source(&v)
+            })
+            .collect();
+        Ok(Command::Exec(ExecInfo {
+            command: words.iter().map(|w| self.arg_source(w)).collect(),
+            env: env,
+            stdin: stdin,
+            stdout: stdout,
+            span: Some(span)
+        }))
+    }
+
+    fn variable_source(&self, name: &str) -> StringSource {
+        if self.locals.contains(name) {
+            StringSource::LocalVariable(name.to_string())
+        } else {
+            StringSource::GlobalVariable(name.to_string())
+        }
+    }
+
+    fn word_source(&self, w: &Word) -> StringSource {
+        self.join_parts(w, false)
+    }
+
+    // Quoted parts of a pattern match literally: literals are escaped
+    // here, expansions when they run.
+    fn pattern_source(&self, w: &Word) -> StringSource {
+        self.join_parts(w, true)
+    }
+
+    fn join_parts(&self, w: &Word, escape_quoted: bool) -> StringSource {
+        let mut parts: Vec<StringSource> = Vec::new();
+        for &(ref part, quoted) in w.parts.iter() {
+            let escape = escape_quoted && quoted;
+            let source = match *part {
+                WordPart::Literal(ref s) => {
+                    let s = if escape {
+                        glob::escape(s)
+                    } else {
+                        s.clone()
+                    };
+                    // Quoting no longer matters once the word is split up.
+                    if let Some(&mut StringSource::Plain(ref mut prev)) = parts.last_mut() {
+                        *prev += s.as_str();
+                        continue;
+                    }
+                    parts.push(StringSource::Plain(s));
+                    continue;
+                },
+                WordPart::Variable(ref name) => self.variable_source(name.as_str()),
+                WordPart::LastExitStatus => StringSource::Value(ValueSource::LastExitStatus),
+                WordPart::Expand(ref name, ref expansion) => StringSource::Expand {
+                    var: Box::new(self.variable_source(name.as_str())),
+                    op: self.expand_op(expansion)
+                }
+            };
+            parts.push(if escape {
+                StringSource::Escape(Box::new(source))
+            } else {
+                source
+            });
+        }
+
+        match parts.len() {
+            0 => StringSource::Plain(String::new()),
+            1 => parts.pop().unwrap(),
+            _ => StringSource::Join(parts)
+        }
+    }
+
+    // Command arguments with unquoted wildcards or braces are expanded
+    // when the command runs.
+    fn arg_source(&self, w: &Word) -> StringSource {
+        let magic = w.parts.iter().any(|&(ref part, quoted)| match *part {
+            WordPart::Literal(ref s) if !quoted => s.contains(&['*', '?', '[', '{'][..]),
+            _ => false
+        });
+        if magic {
+            StringSource::Glob(Box::new(self.pattern_source(w)))
+        } else {
+            self.word_source(w)
+        }
+    }
+
+    fn expand_op(&self, e: &Expansion) -> ExpandOp {
+        let word = |w: &Word| Box::new(self.word_source(w));
+        let pattern = |w: &Word| Box::new(self.pattern_source(w));
+        match *e {
+            Expansion::Default(ref w, if_empty) => ExpandOp::Default {
+                word: word(w),
+                if_empty: if_empty
+            },
+            Expansion::Alternative(ref w, if_empty) => ExpandOp::Alternative {
+                word: word(w),
+                if_empty: if_empty
+            },
+            Expansion::RemovePrefix(ref w, longest) => ExpandOp::RemovePrefix {
+                pattern: pattern(w),
+                longest: longest
+            },
+            Expansion::RemoveSuffix(ref w, longest) => ExpandOp::RemoveSuffix {
+                pattern: pattern(w),
+                longest: longest
+            },
+            Expansion::Replace(ref p, ref w, all) => ExpandOp::Replace {
+                pattern: pattern(p),
+                replacement: word(w),
+                all: all
+            },
+            Expansion::Length => ExpandOp::Length,
+            Expansion::Substring(ref offset, ref length) => ExpandOp::Substring {
+                offset: Box::new(self.number_source(offset)),
+                length: length.as_ref().map(|v| Box::new(self.number_source(v)))
+            }
+        }
+    }
+
+    // Substring offsets and lengths: integers, variable names or words
+    // that expand to an integer.
+    fn number_source(&self, w: &Word) -> ValueSource {
+        if let Some(text) = w.plain() {
+            let text = text.trim();
+            if let Ok(v) = text.parse::<i64>() {
+                return ValueSource::Plain(var::Value::Integer(v));
+            }
+            if is_name(text) {
+                return match self.variable_source(text) {
+                    StringSource::LocalVariable(name) => ValueSource::LocalVariable(name),
+                    _ => ValueSource::GlobalVariable(text.to_string())
+                };
+            }
+        }
+        ValueSource::String(Box::new(self.word_source(w)))
+    }
+
+    fn scope_of(&self, name: &str) -> Scope {
+        if self.locals.contains(name) {
+            Scope::Local
+        } else {
+            Scope::Global
+        }
+    }
+
+    fn assign(&self, name: String, value: StringSource) -> Operation {
+        let value = ValueSource::String(Box::new(value));
+        match self.scope_of(name.as_str()) {
+            Scope::Local => Operation::AssignLocal(name, value),
+            Scope::Global => Operation::AssignGlobal(name, value)
+        }
+    }
+}
+
+/// Compiles a script in a POSIX-sh-like language into a block.
+///
+/// Supported are simple commands with `name=value` prefixes, `<`, `>` and
+/// `>>` redirections, pipelines, `!`, `&&`, `||`, `&` on simple commands,
+/// `if`/`elif`/`else`, `while`, `until`, `for ... in`, `break`, `{ ... }`
+/// groups, `( ... )` subshells and functions without arguments. Words may
+/// use single and double quotes, backslash escapes, `$name`, `${name}`,
+/// `$?` and the parameter expansions `${name:-word}`, `${name-word}`,
+/// `${name:+word}`, `${name+word}`, `${name#pattern}`, `${name##pattern}`,
+/// `${name%pattern}`, `${name%%pattern}`, `${name/pattern/word}`,
+/// `${name//pattern/word}`, `${#name}` and `${name:offset:length}`.
+/// Command arguments and `for` words with unquoted `*`, `?`, `[...]`, `**`
+/// or braces are expanded against the working directory when the command
+/// or loop runs, as described in `glob::expand`. There is no field
+/// splitting or command substitution, and other `for` word lists are fixed
+/// at parse time.
+///
+/// Function names are resolved statically: a command calls a function if
+/// the script defines one with that name anywhere. Variables declared with
+/// `local` refer to the function's frame for the rest of its body, or to
+/// the root frame for the rest of the script if declared at top level.
+///
+/// Ops and commands get a span with the line and column they start at.
+pub fn parse(src: &str) -> Result<Block, LoadError> {
+    parse_in(src, &HashSet::new(), &HashSet::new())
+}
+
+/// Like `parse`, but commands named in `functions` also call the global
+/// function of that name, and top-level references to names in `locals`
+/// refer to locals of the root frame. Used to run input that refers to
+/// functions and locals defined by earlier input, as in an interactive
+/// session.
+pub fn parse_in(src: &str, functions: &HashSet<String>, locals: &HashSet<String>) -> Result<Block, LoadError> {
+    let tokens = lexer(src).tokenize()?;
+
+    let mut functions = functions.clone();
+    for i in 0..tokens.len().saturating_sub(2) {
+        if let Token::Word(ref w) = tokens[i].token {
+            if tokens[i + 1].token == Token::LParen && tokens[i + 2].token == Token::RParen {
+                if let Some(name) = w.plain() {
+                    functions.insert(name.to_string());
+                }
+            }
+        }
+    }
+
+    let mut parser = Parser {
+        tokens: tokens,
+        pos: 0,
+        functions: functions,
+        locals: locals.clone(),
+        loop_depth: 0,
+        next_pipe: 0
+    };
+
+    let ops = parser.parse_list(&[])?;
+    if *parser.peek() != Token::Eof {
+        return parser.unexpected();
+    }
+    Ok(block(ops))
+}
+
+/// Compiles text as if it were the inside of a double-quoted word, so
+/// `$name`, `${...}`, `$?` and backslash escapes are expanded. Variables
+/// refer to globals.
+pub fn parse_string(src: &str) -> Result<StringSource, LoadError> {
+    let mut lexer = lexer(src);
+    let mut word = Word {
+        parts: Vec::new()
+    };
+    if lexer.read_double_quoted(&mut word)? {
+        // Stopped at an unescaped quote.
+        return Err(LoadError::syntax("unexpected '\"'", lexer.line, lexer.column - 1));
+    }
+
+    let parser = Parser {
+        tokens: Vec::new(),
+        pos: 0,
+        functions: HashSet::new(),
+        locals: HashSet::new(),
+        loop_depth: 0,
+        next_pipe: 0
+    };
+    Ok(parser.word_source(&word))
+}
+
+/// Returns true if `e`, returned by `parse` for `src`, could go away with
+/// more input appended, e.g. for an unterminated quote or a missing `fi`.
+pub fn is_incomplete(src: &str, e: &LoadError) -> bool {
+    let msg = e.message.as_str();
+    if msg == UNTERMINATED_SINGLE_QUOTE || msg == UNTERMINATED_DOUBLE_QUOTE || msg == UNTERMINATED_EXPANSION {
+        return true;
+    }
+
+    // Only the end-of-input token sits after the last character.
+    let line = 1 + src.matches('\n').count();
+    let column = 1 + src.rsplit('\n').next().unwrap_or("").chars().count();
+    e.line == line && e.column == column
+}
+
+fn lexer(src: &str) -> Lexer {
+    Lexer {
+        chars: src.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1
+    }
+}
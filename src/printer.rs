@@ -0,0 +1,549 @@
+use std::fmt;
+use serde_json;
+use engine::{Block, Operation, Scope, ExecInfo, StdioConfig, StringSource, ExpandOp, ValueSource, ArithOp, ParallelInfo};
+use var;
+
+const INDENT: &'static str = "    ";
+
+// Renders blocks as shell-like text. Output that only uses constructs the
+// parser understands can be parsed back into an equivalent block.
+struct Printer {
+    out: String,
+    indent: usize
+}
+
+impl Printer {
+    fn new() -> Printer {
+        Printer {
+            out: String::new(),
+            indent: 0
+        }
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out += INDENT;
+        }
+        self.out += text;
+        self.out.push('\n');
+    }
+
+    fn nested(&mut self, blk: &Block) {
+        self.indent += 1;
+        self.ops(blk.ops.as_slice());
+        self.indent -= 1;
+    }
+
+    fn ops(&mut self, ops: &[Operation]) {
+        let mut i = 0;
+        while i < ops.len() {
+            // `a && b` and `a || b` lower to a command followed by an
+            // `IfElse` with one empty branch.
+            if let Some(head) = command_text(&ops[i]) {
+                let mut text = head;
+                let mut n = 1;
+                while let Some(v) = ops.get(i + n).and_then(and_or_text) {
+                    text += v.as_str();
+                    n += 1;
+                }
+                if n > 1 {
+                    self.line(text.as_str());
+                    i += n;
+                    continue;
+                }
+            }
+
+            self.op(&ops[i]);
+            i += 1;
+        }
+    }
+
+    fn op(&mut self, op: &Operation) {
+        match *op {
+            Operation::IfElse(ref if_blk, ref else_blk) => {
+                // The first block runs if the last status is non-zero.
+                if if_blk.ops.is_empty() {
+                    self.line("if [ $? -eq 0 ]; then");
+                    self.nested(else_blk);
+                } else if else_blk.ops.is_empty() {
+                    self.line("if [ $? -ne 0 ]; then");
+                    self.nested(if_blk);
+                } else {
+                    self.line("if [ $? -eq 0 ]; then");
+                    self.nested(else_blk);
+                    self.line("else");
+                    self.nested(if_blk);
+                }
+                self.line("fi");
+            },
+            Operation::Loop(ref blk) => {
+                let (keyword, cond, body) = match split_while(blk) {
+                    Some(v) => v,
+                    None => ("while", &[] as &[Operation], &blk.ops[..])
+                };
+                if cond.is_empty() {
+                    self.line(format!("{} true; do", keyword).as_str());
+                } else if let (1, Some(text)) = (cond.len(), single_line(&cond[0])) {
+                    self.line(format!("{} {}; do", keyword, text).as_str());
+                } else {
+                    self.line(keyword);
+                    self.indent += 1;
+                    self.ops(cond);
+                    self.indent -= 1;
+                    self.line("do");
+                }
+                self.indent += 1;
+                self.ops(body);
+                self.indent -= 1;
+                self.line("done");
+            },
+            Operation::AssignGlobal(ref name, ValueSource::Plain(var::Value::Function(ref blk))) => {
+                self.line(format!("{}() {{", name).as_str());
+                self.nested(blk);
+                self.line("}");
+            },
+            Operation::Parallel(ref info) => self.parallel(info),
+            Operation::ForEach(ref info) => {
+                let words: Vec<String> = info.words.iter().map(|v| v.to_string()).collect();
+                self.line(format!("for {} in {}; do", info.name, words.join(" ")).as_str());
+                self.nested(&info.body);
+                self.line("done");
+            },
+            _ => match single_line(op) {
+                Some(text) => self.line(text.as_str()),
+                // An op with no text of its own is kept as a comment, which
+                // the parser skips.
+                None => {
+                    let json = serde_json::to_string(op).unwrap_or_default();
+                    self.line(format!("# {}", json).as_str());
+                }
+            }
+        }
+    }
+
+    fn parallel(&mut self, info: &ParallelInfo) {
+        let default_options = info.max_concurrency == 0 && info.merge_globals.is_empty() && !info.fail_fast;
+
+        // A single branch with default options is what a subshell lowers to.
+        if default_options && info.blocks.len() == 1 {
+            self.line("(");
+            self.nested(&info.blocks[0]);
+            self.line(")");
+            return;
+        }
+
+        if !default_options {
+            let mut options: Vec<String> = Vec::new();
+            if info.max_concurrency != 0 {
+                options.push(format!("max_concurrency={}", info.max_concurrency));
+            }
+            if info.fail_fast {
+                options.push("fail_fast".to_string());
+            }
+            if !info.merge_globals.is_empty() {
+                options.push(format!("merge_globals={}", info.merge_globals.join(" ")));
+            }
+            self.line(format!("# parallel ({})", options.join(", ")).as_str());
+        }
+        for blk in info.blocks.iter() {
+            self.line("{");
+            self.nested(blk);
+            self.line("} &");
+        }
+        self.line("wait");
+    }
+}
+
+// Text of ops that fit on a single line. `None` for compound ops.
+fn single_line(op: &Operation) -> Option<String> {
+    Some(match *op {
+        Operation::Exec(ref info) => info.to_string(),
+        Operation::ParallelExec(ref infos) => pipeline_text(infos.as_slice()),
+        Operation::BackgroundExec(ref info) => format!("{} &", info),
+        Operation::Break => "break".to_string(),
+        Operation::AssignGlobal(_, ValueSource::Plain(var::Value::Function(_))) => return None,
+        Operation::AssignGlobal(ref name, ref v) => format!("{}={}", name, v),
+        Operation::AssignLocal(ref name, ref v) => format!("local {}={}", name, v),
+        Operation::Declare(scope, ref name) => format!("declare {} {}", scope_text(scope), name),
+        Operation::Unset(scope, ref name) => format!("unset {} {}", scope_text(scope), name),
+        Operation::SetAttributes(scope, ref name, ref attributes) => {
+            let mut text = format!("attributes {} {}", scope_text(scope), name);
+            if attributes.readonly {
+                text += " readonly";
+            }
+            if let Some(ty) = attributes.value_type {
+                text += format!(" {}", ty).as_str();
+            }
+            text
+        },
+        Operation::EngineBacktrace => "engine_backtrace".to_string(),
+        Operation::Print(ref s) => format!("print {}", s),
+        Operation::CheckEq(ref a, ref b) => format!("check_eq {} {}", a, b),
+        Operation::Call(ValueSource::GlobalVariable(ref name)) => name.clone(),
+        Operation::Call(ref v) => format!("call {}", v),
+        Operation::IfElse(..) | Operation::Loop(_) | Operation::Parallel(_) | Operation::ForEach(_) => return None
+    })
+}
+
+fn scope_text(scope: Scope) -> &'static str {
+    match scope {
+        Scope::Local => "local",
+        Scope::Global => "global"
+    }
+}
+
+fn command_text(op: &Operation) -> Option<String> {
+    match *op {
+        Operation::Exec(_) | Operation::ParallelExec(_) | Operation::Call(_) => single_line(op),
+        _ => None
+    }
+}
+
+fn and_or_text(op: &Operation) -> Option<String> {
+    let (if_blk, else_blk) = match *op {
+        Operation::IfElse(ref a, ref b) => (a, b),
+        _ => return None
+    };
+    match (if_blk.ops.len(), else_blk.ops.len()) {
+        (0, 1) => command_text(&else_blk.ops[0]).map(|v| format!(" && {}", v)),
+        (1, 0) => command_text(&if_blk.ops[0]).map(|v| format!(" || {}", v)),
+        _ => None
+    }
+}
+
+// Recognizes `while`/`until` loops as lowered by the parser: the condition
+// followed by an `IfElse` with a lone `Break` on one side.
+fn split_while(blk: &Block) -> Option<(&'static str, &[Operation], &[Operation])> {
+    let (last, cond) = match blk.ops.split_last() {
+        Some(v) => v,
+        None => return None
+    };
+    if cond.is_empty() {
+        return None;
+    }
+    let is_break = |b: &Block| b.ops.len() == 1 && match b.ops[0] {
+        Operation::Break => true,
+        _ => false
+    };
+    match *last {
+        Operation::IfElse(ref a, ref b) if is_break(a) => Some(("while", cond, &b.ops[..])),
+        Operation::IfElse(ref a, ref b) if is_break(b) => Some(("until", cond, &a.ops[..])),
+        _ => None
+    }
+}
+
+// Renders `a | b | c` if the pipes form a simple chain, and every command
+// with its pipe redirections otherwise.
+fn pipeline_text(infos: &[ExecInfo]) -> String {
+    let chained = infos.iter().enumerate().all(|(i, info)| {
+        let stdin_ok = match info.stdin {
+            StdioConfig::Pipe(ref name) => i > 0 && match infos[i - 1].stdout {
+                StdioConfig::Pipe(ref prev) => prev == name,
+                _ => false
+            },
+            _ => i == 0
+        };
+        let stdout_ok = match info.stdout {
+            StdioConfig::Pipe(_) => i + 1 < infos.len(),
+            _ => i + 1 == infos.len()
+        };
+        stdin_ok && stdout_ok
+    });
+
+    if !chained {
+        return infos.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" & ");
+    }
+    infos.iter()
+        .map(|info| exec_text(info, true))
+        .collect::<Vec<String>>()
+        .join(" | ")
+}
+
+fn exec_text(info: &ExecInfo, skip_pipes: bool) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    for env in info.env.iter() {
+        let key = match env.key {
+            StringSource::Plain(ref v) => v.clone(),
+            ref other => format!("{}", other)
+        };
+        parts.push(format!("{}={}", key, env.value));
+    }
+    for arg in info.command.iter() {
+        parts.push(arg.to_string());
+    }
+
+    let stdio = [("<", &info.stdin), (">", &info.stdout)];
+    for &(dir, cfg) in stdio.iter() {
+        match *cfg {
+            StdioConfig::Inherit => {},
+            StdioConfig::Pipe(ref name) => if !skip_pipes {
+                parts.push(format!("{}|{}", dir, name));
+            },
+            StdioConfig::File(ref path) => parts.push(format!("{} {}", dir, path)),
+            StdioConfig::AppendFile(ref path) => parts.push(format!("{}{} {}", dir, dir, path))
+        }
+    }
+    parts.join(" ")
+}
+
+fn is_bare_word(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "_./:,+@%^-".contains(c))
+        && !["if", "then", "else", "elif", "fi", "while", "until", "do", "done", "for", "in", "break", "local"].contains(&s)
+}
+
+fn single_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+// Appends `s` as it would appear inside double quotes.
+fn interpolate(s: &StringSource, out: &mut String) {
+    match *s {
+        StringSource::Plain(ref v) => {
+            for c in v.chars() {
+                if c == '"' || c == '$' || c == '`' || c == '\\' {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+        },
+        StringSource::GlobalVariable(ref name) | StringSource::LocalVariable(ref name) => {
+            *out += format!("${{{}}}", name).as_str();
+        },
+        StringSource::Value(ref v) => interpolate_value(v, out),
+        StringSource::Join(ref parts) => for p in parts.iter() {
+            interpolate(p, out);
+        },
+        StringSource::Expand { ref var, ref op } => {
+            let name = match **var {
+                StringSource::GlobalVariable(ref name) | StringSource::LocalVariable(ref name) => name.clone(),
+                ref other => {
+                    let mut name = String::new();
+                    interpolate(other, &mut name);
+                    name
+                }
+            };
+            *out += expand_text(name.as_str(), op).as_str();
+        },
+        StringSource::Glob(ref pattern) | StringSource::Escape(ref pattern) => interpolate(pattern, out)
+    }
+}
+
+// Appends an escaped expansion in a pattern as the quoted text it comes
+// from.
+fn quoted_expansion(s: &StringSource, out: &mut String) {
+    out.push('"');
+    interpolate(s, out);
+    out.push('"');
+}
+
+fn expand_text(name: &str, op: &ExpandOp) -> String {
+    let colon = |v: bool| if v { ":" } else { "" };
+    match *op {
+        ExpandOp::Default { ref word, if_empty } => {
+            format!("${{{}{}-{}}}", name, colon(if_empty), expansion_word(word, false, "}"))
+        },
+        ExpandOp::Alternative { ref word, if_empty } => {
+            format!("${{{}{}+{}}}", name, colon(if_empty), expansion_word(word, false, "}"))
+        },
+        ExpandOp::RemovePrefix { ref pattern, longest } => {
+            let op = if longest { "##" } else { "#" };
+            format!("${{{}{}{}}}", name, op, expansion_word(pattern, true, "}"))
+        },
+        ExpandOp::RemoveSuffix { ref pattern, longest } => {
+            let op = if longest { "%%" } else { "%" };
+            format!("${{{}{}{}}}", name, op, expansion_word(pattern, true, "}"))
+        },
+        ExpandOp::Replace { ref pattern, ref replacement, all } => {
+            let op = if all { "//" } else { "/" };
+            format!(
+                "${{{}{}{}/{}}}",
+                name,
+                op,
+                expansion_word(pattern, true, "/}"),
+                expansion_word(replacement, false, "}")
+            )
+        },
+        ExpandOp::Length => format!("${{#{}}}", name),
+        ExpandOp::Substring { ref offset, ref length } => {
+            let mut text = String::new();
+            interpolate_value(offset, &mut text);
+            // `${name:-...}` would be a default.
+            if text.starts_with('-') {
+                text.insert(0, ' ');
+            }
+            if let Some(ref length) = *length {
+                text.push(':');
+                interpolate_value(length, &mut text);
+            }
+            format!("${{{}:{}}}", name, text)
+        }
+    }
+}
+
+// A word inside `${...}`, which ends at any character in `stop`. Patterns
+// keep their backslash escapes, which the parser turns back into the same
+// pattern.
+fn expansion_word(s: &StringSource, pattern: bool, stop: &str) -> String {
+    let parts: Vec<&StringSource> = match *s {
+        StringSource::Join(ref parts) => parts.iter().collect(),
+        ref other => vec![other]
+    };
+
+    let mut out = String::new();
+    for part in parts {
+        match *part {
+            StringSource::Plain(ref v) => {
+                let mut chars = v.chars();
+                while let Some(c) = chars.next() {
+                    if pattern && c == '\\' {
+                        out.push(c);
+                        out.extend(chars.next());
+                        continue;
+                    }
+                    if "\"$`\\'".contains(c) || stop.contains(c) {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+            },
+            StringSource::Escape(ref s) if pattern => quoted_expansion(s, &mut out),
+            ref other => interpolate(other, &mut out)
+        }
+    }
+    out
+}
+
+fn interpolate_value(v: &ValueSource, out: &mut String) {
+    match *v {
+        ValueSource::Plain(ref v) => interpolate(&StringSource::Plain(v.to_string()), out),
+        ValueSource::GlobalVariable(ref name) | ValueSource::LocalVariable(ref name) => {
+            *out += format!("${{{}}}", name).as_str();
+        },
+        ValueSource::String(ref s) => interpolate(s, out),
+        ValueSource::LastExitStatus => *out += "$?",
+        ValueSource::Arith(..) => *out += format!("$(({}))", arith_text(v)).as_str(),
+        ValueSource::IsDefined(ref name) => *out += format!("$(defined {})", name).as_str()
+    }
+}
+
+// Operands inside `$(( ))`, where variables need no `$`.
+fn arith_text(v: &ValueSource) -> String {
+    match *v {
+        ValueSource::Plain(var::Value::Integer(v)) => v.to_string(),
+        ValueSource::Plain(var::Value::Float(v)) => format!("{:?}", v),
+        ValueSource::GlobalVariable(ref name) | ValueSource::LocalVariable(ref name) => name.clone(),
+        ValueSource::LastExitStatus => "$?".to_string(),
+        ValueSource::Arith(op, ref a, ref b) => {
+            let op = match op {
+                ArithOp::Add => "+",
+                ArithOp::Sub => "-",
+                ArithOp::Mul => "*",
+                ArithOp::Div => "/",
+                ArithOp::Rem => "%"
+            };
+            let wrap = |v: &ValueSource| match *v {
+                ValueSource::Arith(..) => format!("({})", arith_text(v)),
+                _ => arith_text(v)
+            };
+            format!("{} {} {}", wrap(a), op, wrap(b))
+        },
+        _ => v.to_string()
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut p = Printer::new();
+        p.ops(self.ops.as_slice());
+        f.write_str(p.out.as_str())
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut p = Printer::new();
+        p.op(self);
+        f.write_str(p.out.trim_end_matches('\n'))
+    }
+}
+
+impl fmt::Display for ExecInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(exec_text(self, false).as_str())
+    }
+}
+
+/// Renders as a single shell word.
+impl fmt::Display for StringSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let StringSource::Plain(ref v) = *self {
+            return if is_bare_word(v.as_str()) {
+                f.write_str(v.as_str())
+            } else {
+                f.write_str(single_quote(v.as_str()).as_str())
+            };
+        }
+
+        if let StringSource::Glob(ref pattern) = *self {
+            return f.write_str(glob_word(pattern).as_str());
+        }
+
+        let mut out = String::new();
+        interpolate(self, &mut out);
+        write!(f, "\"{}\"", out)
+    }
+}
+
+// An unquoted word, so its wildcards and braces keep their meaning. The
+// pattern's own escapes are kept as they are.
+fn glob_word(s: &StringSource) -> String {
+    let parts: Vec<&StringSource> = match *s {
+        StringSource::Join(ref parts) => parts.iter().collect(),
+        ref other => vec![other]
+    };
+
+    let mut out = String::new();
+    for part in parts {
+        match *part {
+            StringSource::Plain(ref v) => {
+                let mut chars = v.chars();
+                while let Some(c) = chars.next() {
+                    if c == '\\' {
+                        out.push(c);
+                        out.extend(chars.next());
+                        continue;
+                    }
+                    if !c.is_ascii_alphanumeric() && !"_./:,+@%^-=*?[]{}!~".contains(c) {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+            },
+            StringSource::Escape(ref s) => quoted_expansion(s, &mut out),
+            ref other => interpolate(other, &mut out)
+        }
+    }
+    out
+}
+
+/// Renders as a single shell word. Numbers and `null` are unquoted.
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValueSource::Plain(var::Value::Null) => f.write_str("null"),
+            ValueSource::Plain(var::Value::Integer(v)) => write!(f, "{}", v),
+            ValueSource::Plain(var::Value::Float(v)) => write!(f, "{:?}", v),
+            ValueSource::Plain(var::Value::String(ref v)) => write!(f, "{}", StringSource::Plain(v.clone())),
+            ValueSource::Plain(var::Value::Function(ref blk)) => {
+                let body: Vec<String> = blk.ops.iter().map(|v| v.to_string()).collect();
+                write!(f, "() {{ {}; }}", body.join("; "))
+            },
+            ValueSource::String(ref s) => write!(f, "{}", s),
+            _ => {
+                let mut out = String::new();
+                interpolate_value(self, &mut out);
+                write!(f, "\"{}\"", out)
+            }
+        }
+    }
+}
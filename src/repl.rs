@@ -0,0 +1,889 @@
+use std;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use engine;
+use parser;
+use signals;
+use var;
+
+const KEYWORDS: &'static [&'static str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "do", "done", "for", "in", "break", "local"
+];
+
+// Words after which the next word is a command name.
+const COMMAND_PREFIXES: &'static [&'static str] = &[
+    "if", "then", "elif", "else", "while", "until", "do", "!", "{"
+];
+
+/// Lines entered in an interactive session, optionally kept in a file so
+/// they carry over to later sessions.
+///
+/// The file holds one entry per line. Backslashes and newlines in entries
+/// are escaped as `\\` and `\n`.
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+    pub max_len: usize
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            entries: Vec::new(),
+            path: None,
+            max_len: 1000
+        }
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Loads the history in `path` and appends new entries to it. A
+    /// missing file is treated as empty.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<History> {
+        let mut ret = History::new();
+        ret.path = Some(path.as_ref().to_path_buf());
+
+        let f = match std::fs::File::open(path.as_ref()) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+            Err(e) => return Err(e)
+        };
+        for line in std::io::BufReader::new(f).lines() {
+            ret.entries.push(unescape_entry(line?.as_str()));
+        }
+
+        // Keep the file from growing without bound.
+        if ret.entries.len() > ret.max_len {
+            let excess = ret.entries.len() - ret.max_len;
+            ret.entries.drain(..excess);
+            ret.rewrite()?;
+        }
+        Ok(ret)
+    }
+
+    pub fn entries(&self) -> &[String] {
+        self.entries.as_slice()
+    }
+
+    /// Records an entry. Blank entries and repeats of the previous entry
+    /// are skipped. If the file can't be written, the entry is kept in
+    /// memory and the file is no longer used.
+    pub fn add(&mut self, entry: &str) -> std::io::Result<()> {
+        let entry = entry.trim_end_matches('\n');
+        if entry.trim().is_empty() || self.entries.last().map(|v| v == entry).unwrap_or(false) {
+            return Ok(());
+        }
+
+        self.entries.push(entry.to_string());
+        if self.entries.len() > self.max_len {
+            self.entries.remove(0);
+        }
+
+        let ret = match self.path {
+            Some(ref path) => std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", escape_entry(entry)))
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+            None => Ok(())
+        };
+        if ret.is_err() {
+            self.path = None;
+        }
+        ret
+    }
+
+    fn rewrite(&self) -> std::io::Result<()> {
+        let path = match self.path {
+            Some(ref v) => v,
+            None => return Ok(())
+        };
+        let mut data = String::new();
+        for entry in self.entries.iter() {
+            data += escape_entry(entry.as_str()).as_str();
+            data.push('\n');
+        }
+        std::fs::write(path, data)
+    }
+}
+
+fn escape_entry(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_entry(line: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some(c) => ret.push(c),
+            None => ret.push('\\')
+        }
+    }
+    ret
+}
+
+/// Candidates for completing the word that ends at the cursor.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Completion {
+    pub start: usize, // byte offset of the word in the line
+    pub candidates: Vec<String> // each replaces the whole word; sorted
+}
+
+/// Completes the word before byte offset `pos` in `line`.
+///
+/// Words containing `$` complete to variable names, words in command
+/// position to builtins, functions, keywords and executables on `PATH`,
+/// and other words to paths relative to the engine's working directory.
+/// Directories get a trailing `/`.
+pub fn complete(eng: &engine::Engine, line: &str, pos: usize) -> Completion {
+    let before = &line[..pos];
+    let start = before.rfind(is_word_separator).map(|i| i + 1).unwrap_or(0);
+    let word = &before[start..];
+
+    let candidates = if let Some(dollar) = word.rfind('$') {
+        complete_variable(eng, &word[..dollar], &word[dollar + 1..])
+    } else if !word.contains('/') && is_command_position(&before[..start]) {
+        complete_command(eng, word)
+    } else {
+        complete_path(eng, word)
+    };
+
+    Completion {
+        start: start,
+        candidates: candidates
+    }
+}
+
+fn is_word_separator(c: char) -> bool {
+    " \t\n;&|()<>".contains(c)
+}
+
+fn is_command_position(before: &str) -> bool {
+    let before = before.trim_end();
+    match before.chars().last() {
+        None => return true,
+        Some(c) if ";&|(\n".contains(c) => return true,
+        _ => {}
+    }
+    let prev = match before.rfind(is_word_separator) {
+        Some(i) => &before[i + 1..],
+        None => before
+    };
+    COMMAND_PREFIXES.contains(&prev)
+}
+
+fn complete_variable(eng: &engine::Engine, head: &str, name: &str) -> Vec<String> {
+    let (name, braced) = if name.starts_with('{') {
+        (&name[1..], true)
+    } else {
+        (name, false)
+    };
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Vec::new();
+    }
+
+    let mut names: Vec<&String> = eng.vars.keys().collect();
+    if let Some(frame) = eng.call_stack.last() {
+        names.extend(frame.vars.keys());
+    }
+
+    let mut ret: Vec<String> = names.into_iter()
+        .filter(|v| v.starts_with(name))
+        .map(|v| if braced {
+            format!("{}${{{}}}", head, v)
+        } else {
+            format!("{}${}", head, v)
+        })
+        .collect();
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+fn complete_command(eng: &engine::Engine, prefix: &str) -> Vec<String> {
+    let mut ret: Vec<String> = KEYWORDS.iter().map(|v| v.to_string()).collect();
+    ret.extend(eng.builtins.keys().cloned());
+    ret.extend(function_names(eng));
+
+    let path = match eng.env.get("PATH") {
+        Some(v) => Some(v.clone()),
+        None => std::env::var("PATH").ok()
+    };
+    if let Some(path) = path {
+        for dir in path.split(':').filter(|v| !v.is_empty()) {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(v) => v,
+                Err(_) => continue
+            };
+            for entry in entries.filter_map(|v| v.ok()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with(prefix) && is_executable(&entry.path()) {
+                    ret.push(name);
+                }
+            }
+        }
+    }
+
+    ret.retain(|v| v.starts_with(prefix));
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::metadata(path) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn complete_path(eng: &engine::Engine, word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..i + 1], &word[i + 1..]),
+        None => ("", word)
+    };
+
+    let mut full = match eng.cwd {
+        Some(ref cwd) => PathBuf::from(cwd),
+        None => PathBuf::from(".")
+    };
+    if !dir.is_empty() {
+        full.push(dir);
+    }
+
+    let entries = match std::fs::read_dir(&full) {
+        Ok(v) => v,
+        Err(_) => return Vec::new()
+    };
+    let mut ret: Vec<String> = Vec::new();
+    for entry in entries.filter_map(|v| v.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        let mut candidate = format!("{}{}", dir, escape_word(name.as_str()));
+        if entry.path().is_dir() {
+            candidate.push('/');
+        }
+        ret.push(candidate);
+    }
+    ret.sort();
+    ret
+}
+
+// Backslash-escapes characters the parser would otherwise treat specially.
+fn escape_word(s: &str) -> String {
+    let mut ret = String::new();
+    for c in s.chars() {
+        if " \t\n'\"\\$`#;&|()<>".contains(c) {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+fn function_names(eng: &engine::Engine) -> HashSet<String> {
+    eng.vars.iter()
+        .filter(|&(_, v)| match v.impl_ref().value {
+            var::Value::Function(_) => true,
+            _ => false
+        })
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+// Locals declared at top level by earlier input.
+fn root_locals(eng: &engine::Engine) -> HashSet<String> {
+    eng.call_stack[0].vars.keys().cloned().collect()
+}
+
+/// An interactive session: reads scripts a line at a time and runs each
+/// on the same engine, so variables and functions carry over.
+pub struct Repl {
+    pub eng: engine::EngineHandle,
+    pub prompt: engine::StringSource,
+    pub continuation_prompt: engine::StringSource, // while input is incomplete
+    pub history: History,
+    pending: String
+}
+
+impl Repl {
+    pub fn new(eng: engine::EngineHandle) -> Repl {
+        Repl {
+            eng: eng,
+            prompt: engine::StringSource::Plain("$ ".to_string()),
+            continuation_prompt: engine::StringSource::Plain("> ".to_string()),
+            history: History::new(),
+            pending: String::new()
+        }
+    }
+
+    /// The prompt to show before the next line.
+    pub fn prompt(&self) -> String {
+        let src = if self.pending.is_empty() {
+            &self.prompt
+        } else {
+            &self.continuation_prompt
+        };
+        src.fetch(&self.eng.borrow()).unwrap_or(String::new())
+    }
+
+    /// Adds a line of input, without its newline. Once the input read so
+    /// far forms a complete script, records it in the history and runs it.
+    ///
+    /// Returns the signal from running the script, or `None` if more input
+    /// is needed. Syntax errors are reported to the engine's error stream
+    /// and set the exit status to 2.
+    pub fn feed_line(&mut self, line: &str) -> Option<i32> {
+        self.pending += line;
+        self.pending.push('\n');
+
+        // An odd number of trailing backslashes continues the line.
+        if line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1 {
+            return None;
+        }
+
+        let src = std::mem::replace(&mut self.pending, String::new());
+        let (functions, locals) = {
+            let eng = self.eng.borrow();
+            (function_names(&eng), root_locals(&eng))
+        };
+        let ret = parser::parse_in(src.as_str(), &functions, &locals);
+        if let Err(ref e) = ret {
+            if parser::is_incomplete(src.as_str(), e) {
+                self.pending = src;
+                return None;
+            }
+        }
+
+        if let Err(e) = self.history.add(src.as_str()) {
+            self.eng.borrow().report_error(&e);
+        }
+        Some(match ret {
+            Ok(mut blk) => self.eng.eval_block(&mut blk),
+            Err(e) => {
+                let mut eng = self.eng.borrow_mut();
+                eng.report_error(&e);
+                eng.last_exit_status = 2;
+                signals::EXCEPTION
+            }
+        })
+    }
+
+    /// Drops input that is waiting for more lines.
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Reads and runs lines from stdin until end of input. Line editing,
+    /// history recall and completion are available when stdin is a
+    /// terminal; otherwise lines are read as-is and no prompt is shown.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        if stdin_is_terminal() {
+            self.run_terminal()?;
+        } else {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                self.feed_line(line?.as_str());
+                self.report_diagnostics();
+            }
+        }
+
+        if !self.pending.is_empty() {
+            // Reports the error that kept the input incomplete.
+            let src = std::mem::replace(&mut self.pending, String::new());
+            let (functions, locals) = {
+                let eng = self.eng.borrow();
+                (function_names(&eng), root_locals(&eng))
+            };
+            if let Err(e) = parser::parse_in(src.as_str(), &functions, &locals) {
+                let mut eng = self.eng.borrow_mut();
+                eng.report_error(&e);
+                eng.last_exit_status = 2;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn run_terminal(&mut self) -> std::io::Result<()> {
+        let mut editor = term::LineEditor::new();
+        loop {
+            let prompt = self.prompt();
+            let input = {
+                let eng = &self.eng;
+                editor.read_line(prompt.as_str(), self.history.entries(), &|line, pos| {
+                    complete(&eng.borrow(), line, pos)
+                })?
+            };
+            match input {
+                term::Input::Line(line) => {
+                    // Ctrl-C now interrupts the command, not the session.
+                    let _interrupts = term::InterruptGuard::install(self.eng.borrow().interrupted.clone());
+                    self.feed_line(line.as_str());
+                    self.report_diagnostics();
+                },
+                term::Input::Interrupted => self.cancel(),
+                term::Input::Eof => return Ok(())
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn run_terminal(&mut self) -> std::io::Result<()> {
+        unreachable!()
+    }
+
+    fn report_diagnostics(&self) {
+        let mut eng = self.eng.borrow_mut();
+        for msg in eng.take_diagnostics() {
+            eng.output.write_error(format!("oneshell: {}\n", msg).as_str());
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn stdin_is_terminal() -> bool {
+    unsafe { libc::isatty(0) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_terminal() -> bool {
+    false
+}
+
+#[cfg(unix)]
+mod term {
+    use std;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+    use libc;
+    use super::Completion;
+
+    pub enum Input {
+        Line(String),
+        Interrupted, // Ctrl-C
+        Eof // Ctrl-D on an empty line
+    }
+
+    enum Key {
+        Char(char),
+        Ctrl(u8), // the letter, e.g. b'a' for Ctrl-A
+        Enter,
+        Backspace,
+        Delete,
+        Tab,
+        Left,
+        Right,
+        Up,
+        Down,
+        Home,
+        End,
+        Unknown
+    }
+
+    /// Keeps SIGINT from terminating the process for as long as it is
+    /// alive, and sets `flag` instead, so that the engine stops at the
+    /// next op or loop iteration. Children still get the default action:
+    /// unlike an ignored signal, a handler is reset when they exec.
+    pub struct InterruptGuard {
+        orig: libc::sigaction,
+        flag: Arc<AtomicBool>
+    }
+
+    // The handler can't be handed any state, so it finds the guard's flag
+    // here.
+    static INTERRUPT_FLAG: AtomicPtr<AtomicBool> = AtomicPtr::new(std::ptr::null_mut());
+
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        let flag = INTERRUPT_FLAG.load(Ordering::SeqCst);
+        if !flag.is_null() {
+            unsafe { &*flag }.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl InterruptGuard {
+        pub fn install(flag: Arc<AtomicBool>) -> InterruptGuard {
+            flag.store(false, Ordering::SeqCst);
+            INTERRUPT_FLAG.store(&*flag as *const AtomicBool as *mut AtomicBool, Ordering::SeqCst);
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut orig: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(libc::SIGINT, &action, &mut orig);
+                InterruptGuard {
+                    orig: orig,
+                    flag: flag
+                }
+            }
+        }
+    }
+
+    impl Drop for InterruptGuard {
+        fn drop(&mut self) {
+            unsafe {
+                libc::sigaction(libc::SIGINT, &self.orig, std::ptr::null_mut());
+            }
+            INTERRUPT_FLAG.store(std::ptr::null_mut(), Ordering::SeqCst);
+
+            // The interrupt only applies to the command it arrived during.
+            self.flag.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // Puts the terminal in raw mode for as long as it is alive.
+    struct RawMode {
+        orig: libc::termios
+    }
+
+    impl RawMode {
+        fn enable() -> std::io::Result<RawMode> {
+            unsafe {
+                let mut orig: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(0, &mut orig) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let mut raw = orig;
+                raw.c_iflag &= !(libc::ICRNL | libc::IXON | libc::BRKINT | libc::INPCK | libc::ISTRIP);
+                raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(0, libc::TCSADRAIN, &raw) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(RawMode {
+                    orig: orig
+                })
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(0, libc::TCSADRAIN, &self.orig);
+            }
+        }
+    }
+
+    // Reads straight from the descriptor so that nothing typed ahead is
+    // buffered away from child processes.
+    fn read_byte() -> std::io::Result<Option<u8>> {
+        let mut b: u8 = 0;
+        loop {
+            let n = unsafe { libc::read(0, &mut b as *mut u8 as *mut libc::c_void, 1) };
+            if n == 1 {
+                return Ok(Some(b));
+            } else if n == 0 {
+                return Ok(None);
+            }
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    fn read_key() -> std::io::Result<Option<Key>> {
+        let b = match read_byte()? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        Ok(Some(match b {
+            b'\r' | b'\n' => Key::Enter,
+            b'\t' => Key::Tab,
+            8 | 127 => Key::Backspace,
+            1..=26 => Key::Ctrl(b'a' + b - 1),
+            27 => read_escape()?,
+            0..=31 => Key::Unknown,
+            _ => {
+                // The rest of a UTF-8 sequence.
+                let len = if b >= 0xf0 { 4 } else if b >= 0xe0 { 3 } else if b >= 0xc0 { 2 } else { 1 };
+                let mut bytes = vec![b];
+                for _ in 1..len {
+                    match read_byte()? {
+                        Some(v) => bytes.push(v),
+                        None => break
+                    }
+                }
+                match std::str::from_utf8(bytes.as_slice()).ok().and_then(|v| v.chars().next()) {
+                    Some(c) => Key::Char(c),
+                    None => Key::Unknown
+                }
+            }
+        }))
+    }
+
+    // Called after ESC. Understands the CSI and SS3 sequences sent for
+    // arrow, Home, End and Delete keys.
+    fn read_escape() -> std::io::Result<Key> {
+        match read_byte()? {
+            Some(b'[') | Some(b'O') => {},
+            _ => return Ok(Key::Unknown)
+        }
+        let mut params = String::new();
+        loop {
+            let b = match read_byte()? {
+                Some(v) => v,
+                None => return Ok(Key::Unknown)
+            };
+            if b.is_ascii_digit() || b == b';' {
+                params.push(b as char);
+                continue;
+            }
+            return Ok(match (b, params.as_str()) {
+                (b'A', _) => Key::Up,
+                (b'B', _) => Key::Down,
+                (b'C', _) => Key::Right,
+                (b'D', _) => Key::Left,
+                (b'H', _) | (b'~', "1") | (b'~', "7") => Key::Home,
+                (b'F', _) | (b'~', "4") | (b'~', "8") => Key::End,
+                (b'~', "3") => Key::Delete,
+                _ => Key::Unknown
+            });
+        }
+    }
+
+    /// A minimal line editor for ANSI terminals.
+    pub struct LineEditor {
+        buf: Vec<char>,
+        cursor: usize
+    }
+
+    impl LineEditor {
+        pub fn new() -> LineEditor {
+            LineEditor {
+                buf: Vec::new(),
+                cursor: 0
+            }
+        }
+
+        pub fn read_line(
+            &mut self,
+            prompt: &str,
+            history: &[String],
+            complete: &Fn(&str, usize) -> Completion
+        ) -> std::io::Result<Input> {
+            self.buf.clear();
+            self.cursor = 0;
+
+            let _raw = RawMode::enable()?;
+            let mut out = String::from(prompt);
+
+            // `history.len()` is the line being edited, which is saved in
+            // `current` while browsing.
+            let mut history_index = history.len();
+            let mut current: Vec<char> = Vec::new();
+            let mut last_was_tab = false;
+
+            loop {
+                self.refresh(prompt, &mut out);
+                write_out(&mut out)?;
+
+                let key = match read_key()? {
+                    Some(v) => v,
+                    None => return Ok(Input::Eof)
+                };
+                let is_tab = match key {
+                    Key::Tab => true,
+                    _ => false
+                };
+
+                match key {
+                    Key::Enter => {
+                        out += "\r\n";
+                        write_out(&mut out)?;
+                        return Ok(Input::Line(self.buf.iter().collect()));
+                    },
+                    Key::Ctrl(b'c') => {
+                        out += "^C\r\n";
+                        write_out(&mut out)?;
+                        return Ok(Input::Interrupted);
+                    },
+                    Key::Ctrl(b'd') => {
+                        if self.buf.is_empty() {
+                            out += "\r\n";
+                            write_out(&mut out)?;
+                            return Ok(Input::Eof);
+                        }
+                        self.delete();
+                    },
+                    Key::Char(c) => {
+                        self.buf.insert(self.cursor, c);
+                        self.cursor += 1;
+                    },
+                    Key::Backspace => {
+                        if self.cursor > 0 {
+                            self.cursor -= 1;
+                            self.buf.remove(self.cursor);
+                        }
+                    },
+                    Key::Delete => self.delete(),
+                    Key::Left | Key::Ctrl(b'b') => {
+                        if self.cursor > 0 {
+                            self.cursor -= 1;
+                        }
+                    },
+                    Key::Right | Key::Ctrl(b'f') => {
+                        if self.cursor < self.buf.len() {
+                            self.cursor += 1;
+                        }
+                    },
+                    Key::Home | Key::Ctrl(b'a') => self.cursor = 0,
+                    Key::End | Key::Ctrl(b'e') => self.cursor = self.buf.len(),
+                    Key::Ctrl(b'k') => self.buf.truncate(self.cursor),
+                    Key::Ctrl(b'u') => {
+                        self.buf.drain(..self.cursor);
+                        self.cursor = 0;
+                    },
+                    Key::Ctrl(b'w') => {
+                        let mut start = self.cursor;
+                        while start > 0 && self.buf[start - 1].is_whitespace() {
+                            start -= 1;
+                        }
+                        while start > 0 && !self.buf[start - 1].is_whitespace() {
+                            start -= 1;
+                        }
+                        self.buf.drain(start..self.cursor);
+                        self.cursor = start;
+                    },
+                    Key::Ctrl(b'l') => {
+                        out += "\x1b[H\x1b[2J";
+                        out += prompt;
+                    },
+                    Key::Up | Key::Ctrl(b'p') => {
+                        if history_index > 0 {
+                            if history_index == history.len() {
+                                current = self.buf.clone();
+                            }
+                            history_index -= 1;
+                            self.set_line(history[history_index].chars().collect());
+                        }
+                    },
+                    Key::Down | Key::Ctrl(b'n') => {
+                        if history_index < history.len() {
+                            history_index += 1;
+                            if history_index == history.len() {
+                                self.set_line(current.clone());
+                            } else {
+                                self.set_line(history[history_index].chars().collect());
+                            }
+                        }
+                    },
+                    Key::Tab => self.complete(prompt, complete, last_was_tab, &mut out),
+                    _ => out += "\x07"
+                }
+
+                last_was_tab = is_tab;
+            }
+        }
+
+        fn delete(&mut self) {
+            if self.cursor < self.buf.len() {
+                self.buf.remove(self.cursor);
+            }
+        }
+
+        fn set_line(&mut self, line: Vec<char>) {
+            self.buf = line;
+            self.cursor = self.buf.len();
+        }
+
+        // Redraws the last line of the prompt and the buffer, and moves the
+        // terminal cursor into place.
+        fn refresh(&self, prompt: &str, out: &mut String) {
+            let prompt_tail = match prompt.rfind('\n') {
+                Some(i) => &prompt[i + 1..],
+                None => prompt
+            };
+            *out += "\r";
+            *out += prompt_tail;
+            out.extend(self.buf.iter());
+            *out += "\x1b[K";
+            if self.cursor < self.buf.len() {
+                *out += format!("\x1b[{}D", self.buf.len() - self.cursor).as_str();
+            }
+        }
+
+        // Inserts the only candidate, or the longest prefix shared by all
+        // of them. A second Tab that can't extend the word lists them.
+        fn complete(&mut self, prompt: &str, complete: &Fn(&str, usize) -> Completion, list: bool, out: &mut String) {
+            let line: String = self.buf.iter().collect();
+            let pos: usize = self.buf[..self.cursor].iter().map(|c| c.len_utf8()).sum();
+            let c = complete(line.as_str(), pos);
+            if c.candidates.is_empty() {
+                *out += "\x07";
+                return;
+            }
+
+            let word = &line[c.start..pos];
+            let replacement = if c.candidates.len() == 1 {
+                let mut v = c.candidates[0].clone();
+                if !v.ends_with('/') {
+                    v.push(' ');
+                }
+                v
+            } else {
+                let mut prefix = c.candidates[0].as_str();
+                for other in c.candidates[1..].iter() {
+                    let len = prefix.char_indices()
+                        .zip(other.chars())
+                        .take_while(|&((_, a), b)| a == b)
+                        .last()
+                        .map(|((i, a), _)| i + a.len_utf8())
+                        .unwrap_or(0);
+                    prefix = &prefix[..len];
+                }
+                if prefix.len() <= word.len() {
+                    if list {
+                        *out += "\r\n";
+                        *out += c.candidates.join("  ").as_str();
+                        *out += "\r\n";
+                        *out += prompt;
+                    } else {
+                        *out += "\x07";
+                    }
+                    return;
+                }
+                prefix.to_string()
+            };
+
+            let start = line[..c.start].chars().count();
+            self.buf.splice(start..self.cursor, replacement.chars());
+            self.cursor = start + replacement.chars().count();
+        }
+    }
+
+    fn write_out(out: &mut String) -> std::io::Result<()> {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+        out.clear();
+        Ok(())
+    }
+}
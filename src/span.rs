@@ -0,0 +1,252 @@
+use std::fmt;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor, IntoDeserializer};
+use serde_json;
+use engine::Operation;
+
+/// Where an op or command came from. Every part is optional: scripts get
+/// a line and column (plus the file, when run from one), while hosts
+/// generating ASTs may use `id` to refer back to their own nodes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Span {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub line: usize, // 1-based; 0 if unknown
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub column: usize, // 1-based; 0 if unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>
+}
+
+fn is_zero(v: &usize) -> bool {
+    *v == 0
+}
+
+impl Span {
+    pub fn at(line: usize, column: usize) -> Span {
+        Span {
+            line: line,
+            column: column,
+            ..Span::default()
+        }
+    }
+}
+
+/// `file:line:column`, leaving out unknown parts, followed by the id in
+/// parentheses if there is one.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(ref file) = self.file {
+            parts.push(file.clone());
+        }
+        if self.line != 0 {
+            parts.push(self.line.to_string());
+            if self.column != 0 {
+                parts.push(self.column.to_string());
+            }
+        }
+        let location = parts.join(":");
+
+        match self.id {
+            Some(ref id) if location.is_empty() => write!(f, "{}", id),
+            Some(ref id) => write!(f, "{} ({})", location, id),
+            None => write!(f, "{}", location)
+        }
+    }
+}
+
+/// The ops of a block with their spans, by index.
+///
+/// An op with a span is written as its usual externally tagged form with
+/// an extra `"span"` key, e.g. `{"Break": null, "span": {"line": 3}}`.
+/// Ops without one are written exactly as before.
+pub struct SpannedOps(pub Vec<Operation>, pub Vec<Option<Span>>);
+
+pub struct SpannedOpsRef<'a>(pub &'a [Operation], pub &'a [Option<Span>]);
+
+impl<'a> Serialize for SpannedOpsRef<'a> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeSeq};
+
+        let mut seq = s.serialize_seq(Some(self.0.len()))?;
+        for (i, op) in self.0.iter().enumerate() {
+            match self.1.get(i) {
+                Some(&Some(ref span)) => {
+                    let mut value = serde_json::to_value(op).map_err(S::Error::custom)?;
+                    if let serde_json::Value::String(name) = value {
+                        let mut m = serde_json::Map::new();
+                        m.insert(name, serde_json::Value::Null);
+                        value = serde_json::Value::Object(m);
+                    }
+                    if let serde_json::Value::Object(ref mut m) = value {
+                        m.insert("span".to_string(), serde_json::to_value(span).map_err(S::Error::custom)?);
+                    }
+                    seq.serialize_element(&value)?;
+                },
+                _ => seq.serialize_element(op)?
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for SpannedOps {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<SpannedOps, D::Error> {
+        d.deserialize_seq(SpannedOpsVisitor)
+    }
+}
+
+struct SpannedOpsVisitor;
+
+impl<'de> Visitor<'de> for SpannedOpsVisitor {
+    type Value = SpannedOps;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of operations")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SpannedOps, A::Error> {
+        let mut ops = Vec::new();
+        let mut spans = Vec::new();
+        while let Some(SpannedOp(op, span)) = seq.next_element()? {
+            ops.push(op);
+            spans.push(span);
+        }
+        // Keep `Block::spans` empty for blocks without any.
+        if spans.iter().all(|v| v.is_none()) {
+            spans.clear();
+        }
+        Ok(SpannedOps(ops, spans))
+    }
+}
+
+struct SpannedOp(Operation, Option<Span>);
+
+impl<'de> Deserialize<'de> for SpannedOp {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<SpannedOp, D::Error> {
+        d.deserialize_any(SpannedOpVisitor)
+    }
+}
+
+struct SpannedOpVisitor;
+
+impl<'de> Visitor<'de> for SpannedOpVisitor {
+    type Value = SpannedOp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an operation")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<SpannedOp, E> {
+        let d: de::value::StrDeserializer<E> = v.into_deserializer();
+        let op = Operation::deserialize(d)?;
+        Ok(SpannedOp(op, None))
+    }
+
+    // The variant payload is read straight from the map, so errors in it
+    // keep their position in the source.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SpannedOp, A::Error> {
+        let mut op: Option<Operation> = None;
+        let mut span: Option<Span> = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "span" {
+                if span.is_some() {
+                    return Err(de::Error::duplicate_field("span"));
+                }
+                span = Some(map.next_value()?);
+            } else if op.is_none() {
+                op = Some(Operation::deserialize(VariantDeserializer {
+                    variant: key,
+                    map: &mut map
+                })?);
+            } else {
+                return Err(de::Error::custom("expected a single operation per object"));
+            }
+        }
+
+        match op {
+            Some(op) => Ok(SpannedOp(op, span)),
+            None => Err(de::Error::custom("expected an operation"))
+        }
+    }
+}
+
+// Presents one `"Variant": payload` map entry as an enum.
+struct VariantDeserializer<'a, A: 'a> {
+    variant: String,
+    map: &'a mut A
+}
+
+impl<'de, 'a, A: MapAccess<'de>> Deserializer<'de> for VariantDeserializer<'a, A> {
+    type Error = A::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, A::Error> {
+        visitor.visit_enum(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a, A: MapAccess<'de>> de::EnumAccess<'de> for VariantDeserializer<'a, A> {
+    type Error = A::Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), A::Error> {
+        let variant = {
+            let d: de::value::StrDeserializer<A::Error> = self.variant.as_str().into_deserializer();
+            seed.deserialize(d)?
+        };
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, A: MapAccess<'de>> de::VariantAccess<'de> for VariantDeserializer<'a, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.map.next_value()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, A::Error> {
+        self.map.next_value_seed(seed)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        self.map.next_value_seed(TupleSeed(len, visitor))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, A::Error> {
+        self.map.next_value_seed(StructSeed(fields, visitor))
+    }
+}
+
+struct TupleSeed<V>(usize, V);
+
+impl<'de, V: Visitor<'de>> DeserializeSeed<'de> for TupleSeed<V> {
+    type Value = V::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<V::Value, D::Error> {
+        d.deserialize_tuple(self.0, self.1)
+    }
+}
+
+struct StructSeed<V>(&'static [&'static str], V);
+
+impl<'de, V: Visitor<'de>> DeserializeSeed<'de> for StructSeed<V> {
+    type Value = V::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<V::Value, D::Error> {
+        d.deserialize_struct("", self.0, self.1)
+    }
+}
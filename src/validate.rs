@@ -0,0 +1,237 @@
+use std::fmt;
+use engine::{Block, Operation, ExecInfo, StdioConfig, StringSource, ExpandOp, ValueSource};
+use span::Span;
+use var;
+
+/// A problem found by `Block::validate`.
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub path: String, // to the offending node, e.g. `$.ops[2].Exec.command`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span> // of the command or op containing the node
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(ref span) => write!(f, "{}: {} (at {})", span, self.message, self.path),
+            None => write!(f, "{} (at {})", self.message, self.path)
+        }
+    }
+}
+
+impl Block {
+    /// Checks for mistakes the engine would otherwise only hit at runtime:
+    /// empty commands, `Break` outside of a loop, and pipes in a command
+    /// that aren't both produced and consumed exactly once.
+    /// `Engine::load_block` rejects blocks with any of these.
+    ///
+    /// Function bodies and parallel branches must contain their own loop
+    /// to break.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut v = Validator {
+            diagnostics: Vec::new()
+        };
+        v.block(self, "$", false);
+        v.diagnostics
+    }
+}
+
+struct Validator {
+    diagnostics: Vec<Diagnostic>
+}
+
+impl Validator {
+    fn report(&mut self, message: String, path: String, span: Option<&Span>) {
+        self.diagnostics.push(Diagnostic {
+            message: message,
+            path: path,
+            span: span.cloned()
+        });
+    }
+
+    fn block(&mut self, blk: &Block, path: &str, in_loop: bool) {
+        for (i, op) in blk.ops.iter().enumerate() {
+            self.op(op, format!("{}.ops[{}]", path, i), blk.span(i), in_loop);
+        }
+    }
+
+    fn op(&mut self, op: &Operation, path: String, span: Option<&Span>, in_loop: bool) {
+        match *op {
+            Operation::Exec(ref info) => {
+                let path = format!("{}.Exec", path);
+                self.exec(info, &path, span);
+                self.pipes(&[(info, path)], span);
+            },
+            Operation::BackgroundExec(ref info) => {
+                let path = format!("{}.BackgroundExec", path);
+                self.exec(info, &path, span);
+                self.pipes(&[(info, path)], span);
+            },
+            Operation::ParallelExec(ref infos) => {
+                let items: Vec<(&ExecInfo, String)> = infos.iter().enumerate()
+                    .map(|(i, info)| (info, format!("{}.ParallelExec[{}]", path, i)))
+                    .collect();
+                for &(info, ref path) in items.iter() {
+                    self.exec(info, path, span);
+                }
+                self.pipes(items.as_slice(), span);
+            },
+            Operation::IfElse(ref if_blk, ref else_blk) => {
+                self.block(if_blk, format!("{}.IfElse[0]", path).as_str(), in_loop);
+                self.block(else_blk, format!("{}.IfElse[1]", path).as_str(), in_loop);
+            },
+            Operation::Loop(ref blk) => self.block(blk, format!("{}.Loop", path).as_str(), true),
+            Operation::ForEach(ref info) => {
+                for (i, word) in info.words.iter().enumerate() {
+                    self.string(word, format!("{}.ForEach.words[{}]", path, i), span);
+                }
+                self.block(&info.body, format!("{}.ForEach.body", path).as_str(), true);
+            },
+            Operation::Break => {
+                if !in_loop {
+                    self.report("Break outside of a loop".to_string(), format!("{}.Break", path), span);
+                }
+            },
+            Operation::AssignGlobal(_, ref value) => self.value(value, format!("{}.AssignGlobal[1]", path), span),
+            Operation::AssignLocal(_, ref value) => self.value(value, format!("{}.AssignLocal[1]", path), span),
+            Operation::Declare(..)
+                | Operation::Unset(..)
+                | Operation::SetAttributes(..)
+                | Operation::EngineBacktrace => {},
+            Operation::Print(ref s) => self.string(s, format!("{}.Print", path), span),
+            Operation::CheckEq(ref left, ref right) => {
+                self.value(left, format!("{}.CheckEq[0]", path), span);
+                self.value(right, format!("{}.CheckEq[1]", path), span);
+            },
+            Operation::Call(ref target) => self.value(target, format!("{}.Call", path), span),
+            Operation::Parallel(ref info) => {
+                for (i, blk) in info.blocks.iter().enumerate() {
+                    self.block(blk, format!("{}.Parallel.blocks[{}]", path, i).as_str(), false);
+                }
+            }
+        }
+    }
+
+    fn exec(&mut self, info: &ExecInfo, path: &str, span: Option<&Span>) {
+        let span = info.span.as_ref().or(span);
+
+        if info.command.is_empty() {
+            self.report("Command is empty".to_string(), format!("{}.command", path), span);
+        }
+        for (i, arg) in info.command.iter().enumerate() {
+            self.string(arg, format!("{}.command[{}]", path, i), span);
+        }
+        for (i, env) in info.env.iter().enumerate() {
+            self.string(&env.key, format!("{}.env[{}].key", path, i), span);
+            self.string(&env.value, format!("{}.env[{}].value", path, i), span);
+        }
+        for &(stdio, name) in [(&info.stdin, "stdin"), (&info.stdout, "stdout")].iter() {
+            match *stdio {
+                StdioConfig::File(ref target) => {
+                    self.string(target, format!("{}.{}.File", path, name), span);
+                },
+                StdioConfig::AppendFile(ref target) => {
+                    self.string(target, format!("{}.{}.AppendFile", path, name), span);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // Pipes connect the commands of a single op.
+    fn pipes(&mut self, items: &[(&ExecInfo, String)], span: Option<&Span>) {
+        let mut produced: Vec<&str> = Vec::new();
+        for &(info, ref path) in items.iter() {
+            if let StdioConfig::Pipe(ref name) = info.stdout {
+                if produced.contains(&name.as_str()) {
+                    self.report(
+                        format!("Pipe `{}` is produced more than once", name),
+                        format!("{}.stdout.Pipe", path),
+                        info.span.as_ref().or(span)
+                    );
+                }
+                produced.push(name.as_str());
+            }
+        }
+
+        let mut consumed: Vec<&str> = Vec::new();
+        for &(info, ref path) in items.iter() {
+            if let StdioConfig::Pipe(ref name) = info.stdin {
+                let message = if consumed.contains(&name.as_str()) {
+                    format!("Pipe `{}` is consumed more than once", name)
+                } else if !produced.contains(&name.as_str()) {
+                    format!("Pipe `{}` is consumed but never produced", name)
+                } else {
+                    consumed.push(name.as_str());
+                    continue;
+                };
+                self.report(message, format!("{}.stdin.Pipe", path), info.span.as_ref().or(span));
+            }
+        }
+
+        for &(info, ref path) in items.iter() {
+            if let StdioConfig::Pipe(ref name) = info.stdout {
+                if !consumed.contains(&name.as_str()) {
+                    self.report(
+                        format!("Pipe `{}` is produced but never consumed", name),
+                        format!("{}.stdout.Pipe", path),
+                        info.span.as_ref().or(span)
+                    );
+                }
+            }
+        }
+    }
+
+    // Values are only searched for function literals, whose bodies are
+    // checked like any other block.
+    fn value(&mut self, v: &ValueSource, path: String, span: Option<&Span>) {
+        match *v {
+            ValueSource::Plain(var::Value::Function(ref blk)) => {
+                self.block(blk, format!("{}.Plain.Function", path).as_str(), false);
+            },
+            ValueSource::String(ref s) => self.string(s, format!("{}.String", path), span),
+            ValueSource::Arith(_, ref left, ref right) => {
+                self.value(left, format!("{}.Arith[1]", path), span);
+                self.value(right, format!("{}.Arith[2]", path), span);
+            },
+            _ => {}
+        }
+    }
+
+    fn string(&mut self, s: &StringSource, path: String, span: Option<&Span>) {
+        match *s {
+            StringSource::Value(ref v) => self.value(v, format!("{}.Value", path), span),
+            StringSource::Join(ref parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    self.string(part, format!("{}.Join[{}]", path, i), span);
+                }
+            },
+            StringSource::Expand { ref var, ref op } => {
+                self.string(var, format!("{}.Expand.var", path), span);
+                let path = format!("{}.Expand.op", path);
+                match *op {
+                    ExpandOp::Default { ref word, .. } => self.string(word, format!("{}.Default.word", path), span),
+                    ExpandOp::Alternative { ref word, .. } => self.string(word, format!("{}.Alternative.word", path), span),
+                    ExpandOp::RemovePrefix { ref pattern, .. } => self.string(pattern, format!("{}.RemovePrefix.pattern", path), span),
+                    ExpandOp::RemoveSuffix { ref pattern, .. } => self.string(pattern, format!("{}.RemoveSuffix.pattern", path), span),
+                    ExpandOp::Replace { ref pattern, ref replacement, .. } => {
+                        self.string(pattern, format!("{}.Replace.pattern", path), span);
+                        self.string(replacement, format!("{}.Replace.replacement", path), span);
+                    },
+                    ExpandOp::Length => {},
+                    ExpandOp::Substring { ref offset, ref length } => {
+                        self.value(offset, format!("{}.Substring.offset", path), span);
+                        if let Some(ref length) = *length {
+                            self.value(length, format!("{}.Substring.length", path), span);
+                        }
+                    }
+                }
+            },
+            StringSource::Glob(ref pattern) => self.string(pattern, format!("{}.Glob", path), span),
+            StringSource::Escape(ref s) => self.string(s, format!("{}.Escape", path), span),
+            _ => {}
+        }
+    }
+}
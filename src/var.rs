@@ -1,12 +1,13 @@
 use std;
 use std::os::raw::c_void;
 use std::error::Error;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::{RefCell, Ref};
 use engine;
 use signals;
+use jit;
 
-#[derive(Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Value {
     Null,
     Integer(i64),
@@ -15,16 +16,67 @@ pub enum Value {
     Function(engine::Block)
 }
 
+/// The type of a non-null `Value`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Type {
+    Integer,
+    Float,
+    String,
+    Function
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", match *self {
+            Type::Integer => "integer",
+            Type::Float => "float",
+            Type::String => "string",
+            Type::Function => "function"
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct Variable {
     inner: Rc<RefCell<VariableImpl>>
 }
 
+#[derive(Clone)]
+pub struct WeakVariable {
+    inner: Weak<RefCell<VariableImpl>>
+}
+
+impl WeakVariable {
+    pub fn upgrade(&self) -> Option<Variable> {
+        self.inner.upgrade().map(|v| Variable { inner: v })
+    }
+}
+
 pub struct VariableImpl {
     pub value: Value
 }
 
 impl Value {
+    /// `None` for `Null`.
+    pub fn type_of(&self) -> Option<Type> {
+        match *self {
+            Value::Null => None,
+            Value::Integer(_) => Some(Type::Integer),
+            Value::Float(_) => Some(Type::Float),
+            Value::String(_) => Some(Type::String),
+            Value::Function(_) => Some(Type::Function)
+        }
+    }
+
+    /// Integers, and strings that parse as one.
+    pub fn to_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(v) => Some(v),
+            Value::String(ref v) => v.trim().parse().ok(),
+            _ => None
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match *self {
             Value::Null => "(null)".to_string(),
@@ -34,6 +86,33 @@ impl Value {
             Value::Function(_) => "<Function>".to_string()
         }
     }
+
+    /// Integers wrap on overflow and are promoted to floats when mixed
+    /// with them. Integer division or remainder by zero yields `None`, as
+    /// does `i64::MIN / -1` (and `% -1`), which overflows.
+    pub fn arith(&self, op: engine::ArithOp, other: &Value) -> Option<Value> {
+        use engine::ArithOp;
+
+        match (self, other) {
+            (&Value::Integer(a), &Value::Integer(b)) => match op {
+                ArithOp::Add => Some(a.wrapping_add(b)),
+                ArithOp::Sub => Some(a.wrapping_sub(b)),
+                ArithOp::Mul => Some(a.wrapping_mul(b)),
+                ArithOp::Div => a.checked_div(b),
+                ArithOp::Rem => a.checked_rem(b)
+            }.map(|v| Value::Integer(v)),
+            (&Value::Float(a), &Value::Float(b)) => Some(Value::Float(match op {
+                ArithOp::Add => a + b,
+                ArithOp::Sub => a - b,
+                ArithOp::Mul => a * b,
+                ArithOp::Div => a / b,
+                ArithOp::Rem => a % b
+            })),
+            (&Value::Integer(a), &Value::Float(_)) => Value::Float(a as f64).arith(op, other),
+            (&Value::Float(_), &Value::Integer(b)) => self.arith(op, &Value::Float(b as f64)),
+            _ => None
+        }
+    }
 }
 
 impl Variable {
@@ -53,14 +132,39 @@ impl Variable {
         self.inner.borrow().value.to_string()
     }
 
+    pub fn downgrade(&self) -> WeakVariable {
+        WeakVariable {
+            inner: Rc::downgrade(&self.inner)
+        }
+    }
+
+    /// Whether both handles refer to the same storage. Assignments replace
+    /// the variable, so this also tells whether a name was reassigned.
+    pub fn ptr_eq(&self, other: &Variable) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// The compiled entry and relocation table of a function's body, if
+    /// it has been JIT-compiled.
+    pub fn function_jit(&self) -> Option<(jit::JitEntry, *const u64)> {
+        match self.inner.borrow().value {
+            Value::Function(ref blk) => blk.jit_info.as_ref().map(|v| (v.entry, v.relocs_ptr())),
+            _ => None
+        }
+    }
+
     pub fn deep_clone(&self) -> Variable {
         Variable::from_value(self.inner.borrow().value.clone())
     }
 
+    /// Runs the function in a new frame. `name` identifies the frame in
+    /// backtraces.
     // FIXME: Extremely unsafe code
-    pub fn call(&self, eng: &engine::EngineHandleImpl) -> Result<(), Box<Error>> {
+    pub fn call(&self, eng: &engine::EngineHandleImpl, name: &str) -> Result<(), Box<Error>> {
         if let Value::Function(ref blk) = self.inner.borrow().value {
-            eng.borrow_mut().call_stack.push(Box::new(engine::FunctionState::new()));
+            // A panic skips the frame pops in nested calls, so the stacks
+            // are cut back to their depth here instead.
+            let depths = eng.borrow_mut().enter_call(name, blk);
 
             let _eng = eng as *const engine::EngineHandleImpl as *const c_void;
             let blk = blk as *const engine::Block as *const c_void;
@@ -73,14 +177,15 @@ impl Variable {
             }) {
                 Ok(ret) => if ret == signals::OK {
                     Ok(())
+                } else if ret == signals::EXCEPTION {
+                    Err(Box::new(engine::PropagatedException) as Box<Error>)
                 } else {
                     Err("Bad control status".into())
                 },
                 Err(_) => Err("Error in function".into())
             };
 
-            eng.borrow_mut().call_stack.pop();
-
+            eng.borrow_mut().leave_call(depths);
             ret
         } else {
             Err("Value cannot be called as a function".into())